@@ -7,4 +7,5 @@ MEMORY
   /*CS3 (xrw)  : ORIGIN = 0x63000000, LENGTH = 0*/
   /*CS1 (xrw)  : ORIGIN = 0x61000000, LENGTH = 0*/
 }
+_flash = ORIGIN(FLASH);
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
\ No newline at end of file
@@ -0,0 +1,84 @@
+use core::fmt::{self, Write};
+
+use intra_comms::definitions::Team;
+
+use crate::{rf::SettingError, robot_state::RobotState};
+
+fn team_name(team: Team) -> &'static str {
+    match team {
+        Team::Blue => "blue",
+        Team::Yellow => "yellow",
+    }
+}
+
+pub fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Lists the robots which answered since boot. `now` is the time since boot in us.
+pub fn write_robots(out: &mut impl Write, state: &RobotState, now: u64) -> fmt::Result {
+    let mut any_robot = false;
+    for (id, last_seen) in state.last_seen.iter().enumerate() {
+        let Some((time, team)) = last_seen else {
+            continue;
+        };
+        any_robot = true;
+        let link = &state.link[id];
+        write!(
+            out,
+            "robot {} {}: {}, last seen {} ms ago, {}% loss",
+            id,
+            team_name(*team),
+            if state.is_online(id, now) == Some(true) {
+                "online"
+            } else {
+                "offline"
+            },
+            now.saturating_sub(*time) / 1000,
+            (link.packet_loss() * 100.0) as u32
+        )?;
+        if let Some(rtt) = link.mean_rtt() {
+            write!(out, ", rtt {} us", rtt)?;
+        }
+        if let Some(rssi) = link.rssi_history().first() {
+            write!(out, ", {} dBm", rssi)?;
+        }
+        out.write_str("\n\r")?;
+    }
+    if !any_robot {
+        out.write_str("No robot answered yet\n\r")?;
+    }
+    Ok(())
+}
+
+/// Reports the answer of the robot with `id` to the test packet
+pub fn write_test_result(out: &mut impl Write, state: &RobotState, id: u8) -> fmt::Result {
+    match &state.receive_buffer[usize::from(id)] {
+        Some((_, rssi, rtt)) => write!(
+            out,
+            "robot {} answered after {} us with {} dBm\n\r",
+            id, rtt, rssi
+        ),
+        None => write!(out, "robot {} didn't answer\n\r", id),
+    }
+}
+
+pub fn write_configuration_result(out: &mut impl Write, id: u8, received: bool) -> fmt::Result {
+    if received {
+        write!(out, "robot {} acknowledged the configuration\n\r", id)
+    } else {
+        write!(out, "robot {} didn't acknowledge the configuration\n\r", id)
+    }
+}
+
+pub fn write_setting_result(out: &mut impl Write, result: Result<(), SettingError>) -> fmt::Result {
+    out.write_str(match result {
+        Ok(()) => "Done\n\r",
+        Err(SettingError::Busy) => "The rf is busy. Try again\n\r",
+        Err(SettingError::Transceiver) => "Failed to configure the transceiver\n\r",
+    })
+}
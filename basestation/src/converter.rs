@@ -1,12 +1,22 @@
-use intra_comms::definitions::{
-    BallState, BasestationToRobot, CameraVelocity, DribblerSpeedSelection, DribblerState,
-    GameState, KickSelection, KickSpeedSelection, KickerChargeHint, LocalVelocity,
-    MovementSelection, Position, RobotToBasestation, Team,
+use atsam4_hal::heapless::Vec;
+use defmt::warn;
+use intra_comms::{
+    definitions::{
+        BallState, BasestationToRobot, CameraVelocity, DribblerSpeedSelection, DribblerState,
+        GameState, KickSelection, KickSpeedSelection, KickerChargeHint, LocalVelocity,
+        MovementSelection, Position, RobotConfiguration, RobotToBasestation, Team,
+    },
+    MAX_CHARGE_VOLTAGE,
 };
-use protobuf::proto::luhsoccer::{
-    self, from_basestation_packet::VelocityFeedback, TristateDribblerMode,
+use protobuf::luhsoccer::proto::{
+    basestation::{self, from_basestation_packet::VelocityFeedback, TristateDribblerMode},
+    robot_telemetry::ConnectionQualitiy,
+    ssl_vision::SslDetectionRobot,
+    RobotConfigurationCommand, RobotTelemetry,
 };
 
+use crate::link_quality::LinkStats;
+
 fn convert_speed(vel: f32) -> i16 {
     (vel * 1000.0) as i16
 }
@@ -15,39 +25,46 @@ fn convert_rads(rads: f32) -> i16 {
     (rads * 1024.0) as i16
 }
 
-fn convert_rad(rads: f32) -> u16 {
-    (rads * 1024.0) as u16
+pub fn parse_team(team_color: basestation::TeamColor) -> Team {
+    match team_color {
+        basestation::TeamColor::Blue => Team::Blue,
+        basestation::TeamColor::Yellow => Team::Yellow,
+    }
+}
+
+pub fn team_to_color(team: Team) -> basestation::TeamColor {
+    match team {
+        Team::Blue => basestation::TeamColor::Blue,
+        Team::Yellow => basestation::TeamColor::Yellow,
+    }
 }
 
 pub fn parse_server_to_base_station(
-    packet: luhsoccer::ToBasestationPacket,
+    packet: basestation::ToBasestationPacket,
 ) -> Option<BasestationToRobot> {
     let id = packet.id as u8;
-    let team = match packet.team_color() {
-        luhsoccer::TeamColor::Blue => Team::Blue,
-        luhsoccer::TeamColor::Yellow => Team::Yellow,
-    };
+    let team = parse_team(packet.team_color());
 
     let movement = match packet.movement? {
-        luhsoccer::to_basestation_packet::Movement::LocalVelocity(local_vel) => {
+        basestation::to_basestation_packet::Movement::LocalVelocity(local_vel) => {
             MovementSelection::RobotVelocity(LocalVelocity {
                 forward: convert_speed(local_vel.forward),
                 left: convert_speed(local_vel.left),
                 counterclockwise: convert_rads(local_vel.counter_clockwise),
             })
         }
-        luhsoccer::to_basestation_packet::Movement::GlobalVelocity(global_vel) => {
+        basestation::to_basestation_packet::Movement::GlobalVelocity(global_vel) => {
             MovementSelection::CameraVelocity(CameraVelocity {
                 x: convert_speed(global_vel.x),
                 y: convert_speed(global_vel.y),
                 counterclockwise: convert_rads(global_vel.counter_clockwise),
             })
         }
-        luhsoccer::to_basestation_packet::Movement::GlobalPosition(global_pos) => {
+        basestation::to_basestation_packet::Movement::GlobalPosition(global_pos) => {
             MovementSelection::Position(Position {
                 x: convert_speed(global_pos.x),
                 y: convert_speed(global_pos.y),
-                theta: convert_rad(global_pos.theta),
+                theta: Position::theta_from_rad(global_pos.theta),
             })
         }
     };
@@ -56,25 +73,25 @@ pub fn parse_server_to_base_station(
     let kicker_info = packet.kicker_info?;
 
     let kicker_charge_hint = match kicker_info.charge_hint() {
-        luhsoccer::ChargeHint::Charge => KickerChargeHint::Charge,
-        luhsoccer::ChargeHint::Discharge => KickerChargeHint::Discharge,
-        luhsoccer::ChargeHint::DontCare => KickerChargeHint::DontCare,
+        basestation::ChargeHint::Charge => KickerChargeHint::Charge,
+        basestation::ChargeHint::Discharge => KickerChargeHint::Discharge,
+        basestation::ChargeHint::DontCare => KickerChargeHint::DontCare,
     };
 
     // Kicking speed is a required field
     let kick_speed = match kicker_info.kicking_speed.clone()? {
         // todo this clone needed?
-        luhsoccer::kicker_info::KickingSpeed::Relative(vel) => {
+        basestation::kicker_info::KickingSpeed::Relative(vel) => {
             KickSpeedSelection::Relative(convert_speed(vel).try_into().ok()?)
         }
-        luhsoccer::kicker_info::KickingSpeed::Absolute(vel) => {
+        basestation::kicker_info::KickingSpeed::Absolute(vel) => {
             KickSpeedSelection::Absolute(convert_speed(vel).try_into().ok()?)
         }
     };
 
     let kick_type = match kicker_info.mode() {
-        luhsoccer::KickerMode::Kick => KickSelection::Kick,
-        luhsoccer::KickerMode::Chip => KickSelection::Chip,
+        basestation::KickerMode::Kick => KickSelection::Kick,
+        basestation::KickerMode::Chip => KickSelection::Chip,
     };
 
     // Dribbler info is a required field
@@ -82,11 +99,13 @@ pub fn parse_server_to_base_station(
 
     // Dribbler speed is a required field
     let dribbler_speed = match dribbler_info.dribber_mode? {
-        luhsoccer::dribbler_info::DribberMode::Percent(per) => {
+        basestation::dribbler_info::DribberMode::Percent(per) => {
             DribblerSpeedSelection::Percent(per as u8)
         }
-        luhsoccer::dribbler_info::DribberMode::Rpm(rpm) => DribblerSpeedSelection::Rpm(rpm as u16),
-        luhsoccer::dribbler_info::DribberMode::TristateMode(mode) => {
+        basestation::dribbler_info::DribberMode::Rpm(rpm) => {
+            DribblerSpeedSelection::Rpm(rpm as u16)
+        }
+        basestation::dribbler_info::DribberMode::TristateMode(mode) => {
             let state = match TristateDribblerMode::from_i32(mode)? {
                 TristateDribblerMode::Off => DribblerState::Off,
                 TristateDribblerMode::Half => DribblerState::Half,
@@ -104,31 +123,134 @@ pub fn parse_server_to_base_station(
         kick_speed,
         kick_type,
         dribbler_speed,
+        // both are attached right before sending
         robot_position: None,
         game_state: GameState::Normal,
         time_sync: None,
+        configuration: None,
+        keep_state: false,
     })
 }
 
+/// Settings of one configuration command in the order they are sent to the robot
+pub type RobotSettings = Vec<RobotConfiguration, 11>;
+
+/// Splits a configuration command of the server into the settings the robots know. Returns `None`
+/// if a value is out of range or the command sets something the robots can't configure.
+pub fn parse_robot_configuration(command: &RobotConfigurationCommand) -> Option<RobotSettings> {
+    let mut settings = RobotSettings::new();
+    let values = [
+        command.vel_max_x,
+        command.vel_max_y,
+        command.vel_max_t,
+        command.acc_max_x,
+        command.acc_max_y,
+        command.acc_max_t,
+        command.brk_max_x,
+        command.brk_max_y,
+        command.brk_max_t,
+        command.k_pos,
+        command.k_theta,
+    ];
+    if values
+        .into_iter()
+        .flatten()
+        .any(|value| !value.is_finite() || value <= 0.0)
+    {
+        return None;
+    }
+    let limits = [
+        lower(command.vel_max_x, command.vel_max_y).map(RobotConfiguration::MaxVelocity),
+        command
+            .vel_max_t
+            .map(RobotConfiguration::MaxAngularVelocity),
+        lower(command.acc_max_x, command.acc_max_y).map(RobotConfiguration::MaxAcceleration),
+        command
+            .acc_max_t
+            .map(RobotConfiguration::MaxAngularAcceleration),
+        lower(command.brk_max_x, command.brk_max_y).map(RobotConfiguration::MaxBraking),
+        command.brk_max_t.map(RobotConfiguration::MaxAngularBraking),
+        command.k_pos.map(RobotConfiguration::PositionGain),
+        command.k_theta.map(RobotConfiguration::AngularGain),
+    ];
+    for setting in limits.into_iter().flatten() {
+        settings.push(setting).ok()?;
+    }
+    if let Some(speed) = command.max_dribbler_speed {
+        let speed = u8::try_from(speed).ok().filter(|speed| *speed <= 100)?;
+        settings
+            .push(RobotConfiguration::MaxDribblerSpeed(speed))
+            .ok()?;
+    }
+    if let Some(voltage) = command.max_cap_voltage {
+        let voltage = u8::try_from(voltage)
+            .ok()
+            .filter(|voltage| *voltage <= MAX_CHARGE_VOLTAGE)?;
+        settings
+            .push(RobotConfiguration::MaxCapVoltage(voltage))
+            .ok()?;
+    }
+    // the robot only answers to its new id afterwards
+    if let Some(id) = command.new_id {
+        let id = u8::try_from(id).ok().filter(|id| *id < 16)?;
+        settings.push(RobotConfiguration::Id(id)).ok()?;
+    }
+
+    let unsupported = [command.k_vel, command.k_omega, command.avoid_ball_distance];
+    if unsupported.iter().any(Option::is_some)
+        || command.avoid_defense_area.is_some()
+        || command.avoid_ball.is_some()
+        || command.avoid_borders.is_some()
+    {
+        warn!(
+            "robot {} has no velocity gains or obstacle avoidance to configure",
+            command.id
+        );
+        return None;
+    }
+    Some(settings)
+}
+
+/// The robots have one limit for all directions, so the lower one is used
+fn lower(x: Option<f32>, y: Option<f32>) -> Option<f32> {
+    match (x, y) {
+        (Some(x), Some(y)) => Some(x.min(y)),
+        (x, y) => x.or(y),
+    }
+}
+
+/// Converts a robot detected by ssl vision. Returns `None` if the orientation is unknown.
+pub fn parse_vision_robot(robot: &SslDetectionRobot) -> Option<Position> {
+    Some(Position {
+        // vision already uses mm
+        x: robot.x as i16,
+        y: robot.y as i16,
+        theta: Position::theta_from_rad(robot.orientation?),
+    })
+}
+
+/// `now` is the time of the basestation in us
 pub fn parse_base_station_to_server(
     packet: RobotToBasestation,
     rssi_basestation: i32,
     measured_rtt: u32,
-) -> luhsoccer::FromBasestationPacket {
+    now: u64,
+    link: &LinkStats,
+) -> basestation::FromBasestationPacket {
     let id = packet.id as u32;
-    let team_color = match packet.team {
-        Team::Blue => luhsoccer::TeamColor::Blue,
-        Team::Yellow => luhsoccer::TeamColor::Yellow,
-    } as i32;
+    let team_color = team_to_color(packet.team) as i32;
     let battery_voltage = packet.battery_voltage as f32 / 8.0;
     let kicker_voltage = packet.kicker_voltage as f32;
     let has_ball = match packet.has_ball {
         BallState::InDribbler => true,
         BallState::NotInDribbler => false,
     };
-    let error_code = packet.error as u32;
+    if !packet.error.is_empty() {
+        warn!("robot {} reports errors: {}", packet.id, packet.error);
+    }
+    let error_code = u32::from(packet.error.bits());
     let rssi_robot = -(packet.rssi as i32);
-    let firmware_version = Some(luhsoccer::FirmwareVersion {
+    let firmware_version = Some(basestation::FirmwareVersion {
         major: packet.firmware_version.major as u32,
         minor: packet.firmware_version.minor as u32,
         patch: packet.firmware_version.patch as u32,
@@ -138,14 +260,14 @@ pub fn parse_base_station_to_server(
     let velocity_feedback = match packet.velocity {
         Some(vel) => match vel {
             intra_comms::definitions::VelocitySelection::RobotVelocity(vel) => Some(
-                VelocityFeedback::LocalVelocity(luhsoccer::LocalVelocityFeedback {
+                VelocityFeedback::LocalVelocity(basestation::LocalVelocityFeedback {
                     forward: vel.forward as f32 / 1000.0,
                     left: vel.left as f32 / 1000.0,
                     counter_clockwise: vel.counterclockwise as f32 / 1024.0,
                 }),
             ),
             intra_comms::definitions::VelocitySelection::CameraVelocity(vel) => Some(
-                VelocityFeedback::GlobalVelocity(luhsoccer::GlobalVelocityFeedback {
+                VelocityFeedback::GlobalVelocity(basestation::GlobalVelocityFeedback {
                     x: vel.x as f32 / 1000.0,
                     y: vel.y as f32 / 1000.0,
                     counter_clockwise: vel.counterclockwise as f32 / 1024.0,
@@ -155,7 +277,7 @@ pub fn parse_base_station_to_server(
         None => None,
     };
 
-    luhsoccer::FromBasestationPacket {
+    basestation::FromBasestationPacket {
         id,
         team_color,
         battery_voltage,
@@ -167,9 +289,31 @@ pub fn parse_base_station_to_server(
         rssi_robot,
         rssi_basestation,
         global_position: None,
-        feedback_time: 0,
+        feedback_time: packet
+            .sample_time
+            .map(|time| u32::try_from(now.saturating_sub(time.as_micros())).unwrap_or(u32::MAX)),
         firmware_version,
         measured_rtt,
         velocity_feedback,
+        dribbler_rpm: packet.dribbler_rpm.map(f32::from),
+        ball_confidence: f32::from(packet.ball_confidence) / 100.0,
+        light_barrier_working: packet.light_barrier_working,
+        packet_loss: link.packet_loss(),
+        crc_errors: link.crc_errors(),
+        timeouts: link.timeouts(),
+        invalid_packets: link.invalid_packets(),
+        mean_rtt: link.mean_rtt().unwrap_or(measured_rtt),
+        max_rtt: link.max_rtt().unwrap_or(measured_rtt),
+        rssi_history: link.rssi_history().into_iter().collect(),
+    }
+}
+
+/// The connection quality is set when the telemetry is reported
+pub fn parse_telemetry(packet: &RobotToBasestation) -> RobotTelemetry {
+    RobotTelemetry {
+        battery_voltage: packet.battery_voltage as f32 / 8.0,
+        cap_voltage: packet.kicker_voltage as f32,
+        connection_qualitiy: ConnectionQualitiy::NoConnection as i32,
+        light_barrier_working: packet.light_barrier_working,
     }
 }
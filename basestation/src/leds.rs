@@ -0,0 +1,57 @@
+use protobuf::luhsoccer::proto::robot_telemetry::ConnectionQualitiy;
+use smart_leds::RGB;
+
+use crate::{robot_state::RobotState, status::Status};
+
+pub const NUM_LEDS: usize = 16;
+
+const OFF: RGB<u8> = RGB { r: 0, g: 0, b: 0 };
+const GREEN: RGB<u8> = RGB { r: 0, g: 32, b: 0 };
+const YELLOW: RGB<u8> = RGB { r: 32, g: 24, b: 0 };
+const RED: RGB<u8> = RGB { r: 32, g: 0, b: 0 };
+const BLUE: RGB<u8> = RGB { r: 0, g: 0, b: 32 };
+const MAGENTA: RGB<u8> = RGB { r: 32, g: 0, b: 32 };
+const CYAN: RGB<u8> = RGB { r: 0, g: 32, b: 32 };
+
+/// Colors of the status leds. `now` is the time since boot in us.
+///
+/// Each led shows the link to the robot with the same id: off if the server doesn't command it,
+/// green for a good, yellow for a degraded and red for a lost connection. Problems of the
+/// basestation blink all leds while `blink` is set, in the order of precedence: blue without an
+/// IP address, magenta without packets from the server and cyan without ssl vision.
+pub fn status_leds(
+    status: &Status,
+    state: &RobotState,
+    now: u64,
+    blink: bool,
+) -> [RGB<u8>; NUM_LEDS] {
+    let now_ms = now / 1000;
+    let warning = if status.ip.is_none() {
+        Some(BLUE)
+    } else if status.server_missing(now_ms) {
+        Some(MAGENTA)
+    } else if status.vision_missing(now_ms) {
+        Some(CYAN)
+    } else {
+        None
+    };
+    if let Some(color) = warning.filter(|_| blink) {
+        return [color; NUM_LEDS];
+    }
+
+    let mut leds = [OFF; NUM_LEDS];
+    for (id, led) in leds.iter_mut().enumerate() {
+        if !state.is_commanded(id, now) {
+            continue;
+        }
+        *led = match state.is_online(id, now) {
+            Some(true) => match state.link[id].quality() {
+                ConnectionQualitiy::Good => GREEN,
+                ConnectionQualitiy::Bad => YELLOW,
+                ConnectionQualitiy::NoConnection => RED,
+            },
+            Some(false) | None => RED,
+        };
+    }
+    leds
+}
@@ -0,0 +1,126 @@
+use atsam4_hal::heapless::Vec;
+use protobuf::luhsoccer::proto::robot_telemetry::ConnectionQualitiy;
+
+/// Number of slots of each robot the statistics are kept for
+const WINDOW: usize = 32;
+/// Number of RSSI values reported to the server
+pub const RSSI_HISTORY: usize = 8;
+/// The connection is bad if more packets are lost
+const GOOD_MAX_LOSS: f32 = 0.1;
+/// dBm, the connection is bad if the mean RSSI is lower
+const GOOD_MIN_RSSI: i32 = -85;
+
+/// Result of one slot of a robot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotOutcome {
+    /// `rtt` in us, `rssi` in dBm measured by the basestation
+    Answered {
+        rtt: u32,
+        rssi: i32,
+    },
+    Timeout,
+    CrcError,
+    /// The feedback couldn't be read or decoded
+    InvalidPacket,
+}
+
+impl SlotOutcome {
+    pub fn rtt(&self) -> Option<u32> {
+        match self {
+            Self::Answered { rtt, .. } => Some(*rtt),
+            _ => None,
+        }
+    }
+}
+
+/// Rolling statistics of the last slots of a robot
+#[derive(Default)]
+pub struct LinkStats {
+    outcomes: [Option<SlotOutcome>; WINDOW],
+    /// Index of the next outcome
+    next: usize,
+}
+
+impl LinkStats {
+    pub fn record(&mut self, outcome: SlotOutcome) {
+        self.outcomes[self.next] = Some(outcome);
+        self.next = (self.next + 1) % WINDOW;
+    }
+
+    /// Outcomes of the recorded slots, newest first
+    fn newest_first(&self) -> impl Iterator<Item = &SlotOutcome> {
+        let (older, newer) = self.outcomes.split_at(self.next);
+        newer.iter().rev().chain(older.iter().rev()).flatten()
+    }
+
+    fn answers(&self) -> impl Iterator<Item = (u32, i32)> + '_ {
+        self.newest_first().filter_map(|outcome| match outcome {
+            SlotOutcome::Answered { rtt, rssi } => Some((*rtt, *rssi)),
+            _ => None,
+        })
+    }
+
+    fn count(&self, kind: SlotOutcome) -> u32 {
+        self.newest_first()
+            .filter(|outcome| **outcome == kind)
+            .count() as u32
+    }
+
+    /// Part of the recorded slots without valid feedback in 0..=1
+    pub fn packet_loss(&self) -> f32 {
+        let slots = self.newest_first().count();
+        if slots == 0 {
+            return 0.0;
+        }
+        let lost = slots - self.answers().count();
+        lost as f32 / slots as f32
+    }
+
+    pub fn crc_errors(&self) -> u32 {
+        self.count(SlotOutcome::CrcError)
+    }
+
+    pub fn timeouts(&self) -> u32 {
+        self.count(SlotOutcome::Timeout)
+    }
+
+    pub fn invalid_packets(&self) -> u32 {
+        self.count(SlotOutcome::InvalidPacket)
+    }
+
+    /// us
+    pub fn mean_rtt(&self) -> Option<u32> {
+        let (sum, count) = self
+            .answers()
+            .fold((0_u64, 0_u64), |(sum, count), (rtt, _)| {
+                (sum + u64::from(rtt), count + 1)
+            });
+        (count > 0).then(|| (sum / count) as u32)
+    }
+
+    /// us
+    pub fn max_rtt(&self) -> Option<u32> {
+        self.answers().map(|(rtt, _)| rtt).max()
+    }
+
+    /// dBm of the last answers, newest first
+    pub fn rssi_history(&self) -> Vec<i32, RSSI_HISTORY> {
+        self.answers()
+            .map(|(_, rssi)| rssi)
+            .take(RSSI_HISTORY)
+            .collect()
+    }
+
+    pub fn quality(&self) -> ConnectionQualitiy {
+        let (sum, count) = self
+            .answers()
+            .fold((0, 0), |(sum, count), (_, rssi)| (sum + rssi, count + 1));
+        if count == 0 {
+            ConnectionQualitiy::NoConnection
+        } else if self.packet_loss() <= GOOD_MAX_LOSS && sum / count >= GOOD_MIN_RSSI {
+            ConnectionQualitiy::Good
+        } else {
+            ConnectionQualitiy::Bad
+        }
+    }
+}
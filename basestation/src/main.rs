@@ -2,12 +2,19 @@
 #![no_std]
 #![no_main]
 
+mod commands;
 mod converter;
+mod leds;
+mod link_quality;
 mod network;
+mod referee;
 mod rf;
 mod robot_state;
+mod settings;
 mod status;
+mod tdma;
 mod usb_serial;
+mod vision;
 
 use atsam4_hal as _;
 use defmt_rtt as _;
@@ -23,22 +30,34 @@ defmt::timestamp!("{=u64:us}", {
 #[rtic::app(device = atsam4_hal::pac, dispatchers = [AES, USART0, USART1, EFC])]
 mod app {
 
+    use crate::commands;
+    use crate::leds;
     use crate::network;
+    use crate::referee::RefereeState;
     use crate::rf;
     use crate::robot_state;
+    use crate::settings::SettingsStore;
     use crate::status::Status;
     use crate::usb_serial;
+    use crate::vision::VisionPositions;
 
     use atsam4_hal as hal;
     use atsam4_hal::ethernet::{EthernetAddress, RxDescriptorTable, TxDescriptorTable};
+    use console::{Command, LineBuffer};
+    use core::fmt::Write;
 
-    use defmt::info;
+    use defmt::{info, warn};
     use dwt_systick_monotonic::{fugit, DwtSystick};
 
     use embedded_hal::timer::CountDown;
     use fugit::{ExtU64, RateExtU32};
     use hal::ethernet::Controller;
+    use intra_comms::definitions::RobotConfiguration;
 
+    use basestation_config::{
+        identity::{self, SERIAL_NUMBER_LEN},
+        AddressConfig, Config, RobotIds,
+    };
     use hal::spi::SpiU8;
     use hal::{
         clock::{ClockController, MainClock, SlowClock},
@@ -50,7 +69,8 @@ mod app {
     };
     use network::Network;
     use robot_state::RobotState;
-    use smart_leds::{SmartLedsWrite, RGB};
+    use rtic::Mutex;
+    use smart_leds::SmartLedsWrite;
     use usb_serial::UsbSerial;
 
     use ws2812_timer_delay as ws2812;
@@ -65,6 +85,7 @@ mod app {
     static mut RX_DESC: hal::ethernet::RxDescriptorTable<32> = RxDescriptorTable::new();
     static mut TX_DESC: hal::ethernet::TxDescriptorTable<1> = TxDescriptorTable::new();
     static mut NETWORK_STORAGE: Option<network::Storage> = None;
+    static mut SERIAL_NUMBER: [u8; SERIAL_NUMBER_LEN] = [0; SERIAL_NUMBER_LEN];
 
     #[shared]
     struct Shared {
@@ -72,6 +93,10 @@ mod app {
         network: Network<'static, Controller>,
         serial: UsbSerial<'static>,
         status: Status,
+        vision: VisionPositions,
+        referee: RefereeState,
+        radio: rf::Radio,
+        settings: SettingsStore,
     }
 
     #[local]
@@ -85,8 +110,6 @@ mod app {
             >,
             hal::gpio::Pd31<hal::gpio::Output<hal::gpio::PushPull>>,
         >,
-        rf: rf::Transceiver,
-        rf_amp: Option<rf::Amp>,
     }
 
     #[init]
@@ -149,7 +172,14 @@ mod app {
         // Safety: This is safe since they are no interrupts enabled yet
         let storage = unsafe { NETWORK_STORAGE.as_mut().unwrap() };
 
-        let mac_address = [0x02, 0x6C, 0x75, 0x68, 0x62, 0x73];
+        let settings = SettingsStore::load(ctx.device.EFC);
+        let config = *settings.config();
+
+        let mac_address = config.mac_address(settings.unique_id());
+        info!("MAC address: {:02x}", mac_address);
+        let serial_number = settings
+            .unique_id()
+            .map_or([b'0'; SERIAL_NUMBER_LEN], identity::serial_number);
         let gmac = hal::ethernet::ControllerBuilder::new()
             .set_ethernet_address(EthernetAddress::new(mac_address))
             .build(
@@ -179,7 +209,10 @@ mod app {
 
         let mut delay = hal::delay::Delay::new(ctx.core.SYST);
 
-        let network = network::Network::from_device(gmac, mac_address, storage);
+        let mut status = Status::default();
+        let mut network =
+            network::Network::from_device(gmac, mac_address, storage, config.server_port);
+        network.set_config(config.address, &mut status);
 
         let mut rf_spi = hal::spi::SpiMaster::<SpiU8>::new(
             ctx.device.SPI,
@@ -236,7 +269,9 @@ mod app {
 
         let mut sx1280 = sx1280.into_flrc();
 
-        sx1280.set_frequency(2_400u32.MHz()).unwrap();
+        sx1280
+            .set_frequency(u32::from(config.rf_frequency).MHz())
+            .unwrap();
         sx1280.set_buffer_base_address(0, 128).unwrap();
         sx1280.set_packet_type(sx1280::definitions::GfskFlrcPacketType::PacketLengthVariable);
         sx1280.set_sync_word_match(sx1280::definitions::GfskFlrcSyncWordMatch::SyncWord1);
@@ -252,7 +287,7 @@ mod app {
             )
             .unwrap();
         sx1280
-            .set_tx_param(-2, sx1280::definitions::RampTime::Ramp02us)
+            .set_tx_param(config.tx_power, sx1280::definitions::RampTime::Ramp02us)
             .unwrap();
 
         let writer = sx1280::definitions::IrqWriter::new()
@@ -264,6 +299,12 @@ mod app {
             .enable_interrupts(writer, writer, writer, writer)
             .unwrap();
 
+        let radio = rf::Radio::new(
+            sx1280,
+            sky,
+            pins.pd19.into_floating_input(&ctx.device.MATRIX),
+        );
+
         let tc0 = TimerCounter::new(ctx.device.TC0);
         let tc0_chs = tc0.split::<15_000_000, 15_000_000, 15_000_000>(
             clocks.peripheral_clocks.tc_0.into_enabled_clock(),
@@ -287,16 +328,18 @@ mod app {
         );
 
         let usb_ref = unsafe { USB.as_ref().unwrap() };
-        let mut serial = crate::usb_serial::UsbSerial::new(usb_ref);
+        // Safety: This is safe since they are no interrupts enabled yet
+        let serial_number = unsafe {
+            SERIAL_NUMBER = serial_number;
+            core::str::from_utf8(&SERIAL_NUMBER).unwrap()
+        };
+        let mut serial = crate::usb_serial::UsbSerial::new(usb_ref, serial_number);
         serial.device.force_reset().unwrap();
         serial.on_interrupt();
 
-        print_status::spawn_after(5u64.secs()).unwrap();
-
-        let status = Status::default();
-
         //test_rf::spawn_after(200u64.millis()).unwrap();
-        let state = RobotState::default();
+        let mut state = RobotState::default();
+        state.owned_ids = config.owned_ids;
 
         (
             Shared {
@@ -304,24 +347,31 @@ mod app {
                 network,
                 serial,
                 status,
+                vision: VisionPositions::default(),
+                referee: RefereeState::default(),
+                radio,
+                settings,
             },
-            Local {
-                ws,
-                rf: sx1280,
-                rf_amp: Some(sky),
-            },
+            Local { ws },
             init::Monotonics(mono),
         )
     }
 
-    #[idle(shared = [state, network, status])]
+    #[idle(shared = [state, network, status, vision, referee])]
     fn idle(mut ctx: idle::Context) -> ! {
         loop {
             ctx.shared.status.lock(|status| {
                 ctx.shared.network.lock(|network| {
                     network.poll(
-                        |_packet| {
-                            // TODO implement
+                        |vision_packet| {
+                            ctx.shared.vision.lock(|vision| {
+                                vision.update(vision_packet);
+                            });
+                        },
+                        |referee_packet| {
+                            ctx.shared.referee.lock(|referee| {
+                                referee.update(&referee_packet);
+                            });
                         },
                         |wrapper_packet, endpoint| {
                             ctx.shared.state.lock(|state| {
@@ -336,59 +386,318 @@ mod app {
         }
     }
 
-    #[task(local = [ws], priority = 1)]
+    #[task(local = [ws, blink: bool = false], shared = [state, status], priority = 1)]
     fn write_status(ctx: write_status::Context) {
-        const NUM_LEDS: usize = 16;
+        let now = monotonics::now().duration_since_epoch().to_micros();
+        *ctx.local.blink = !*ctx.local.blink;
+        let blink = *ctx.local.blink;
+        let data = (ctx.shared.status, ctx.shared.state)
+            .lock(|status, state| leds::status_leds(status, state, now, blink));
 
-        let mut data: [RGB<u8>; NUM_LEDS] = [RGB::default(); NUM_LEDS];
+        ctx.local.ws.write(data.into_iter()).unwrap();
 
-        for color in data.iter_mut().skip(0).step_by(3) {
-            color.r = 0;
+        write_status::spawn_after(500u64.millis()).unwrap();
+    }
+
+    #[task(shared = [state, vision, referee, radio])]
+    fn transmit(mut ctx: transmit::Context, endpoint: atsam4_hal::smoltcp::wire::IpEndpoint) {
+        let done = ctx.shared.state.lock(|state| {
+            state.server = Some(endpoint);
+            ctx.shared.vision.lock(|vision| {
+                vision.attach_to(&mut state.send_buffer);
+            });
+            let broadcast = ctx.shared.referee.lock(|referee| {
+                referee.attach_to(&mut state.send_buffer);
+                state.create_broadcast(referee.game_state())
+            });
+            state.attach_configurations();
+            // the robots listen to the broadcast between their own packets
+            ctx.shared
+                .radio
+                .lock(|radio| radio.start_cycle(state, broadcast))
+        });
+        if done {
+            let _ = send_feedback::spawn();
         }
+    }
 
-        for color in data.iter_mut().skip(1).step_by(3) {
-            color.g = 0;
+    #[task(binds = PIOD, priority = 2, shared = [state, radio])]
+    fn rf_interrupt(ctx: rf_interrupt::Context) {
+        let done =
+            (ctx.shared.state, ctx.shared.radio).lock(|state, radio| radio.on_interrupt(state));
+        if done {
+            let _ = send_feedback::spawn();
         }
+    }
 
-        for color in data.iter_mut().skip(2).step_by(3) {
-            color.b = 0;
+    /// Continues an rf cycle if DIO1 of the transceiver didn't raise an interrupt
+    #[task(shared = [state, radio])]
+    fn rf_poll(ctx: rf_poll::Context) {
+        let done = (ctx.shared.state, ctx.shared.radio).lock(|state, radio| radio.poll(state));
+        if done {
+            let _ = send_feedback::spawn();
         }
+    }
 
-        ctx.local.ws.write(data.into_iter()).unwrap();
+    /// Ends an rf cycle which is stuck because an interrupt of the transceiver was lost
+    #[task(shared = [state, radio])]
+    fn rf_watchdog(ctx: rf_watchdog::Context, cycle: u32) {
+        let done = (ctx.shared.state, ctx.shared.radio)
+            .lock(|state, radio| radio.on_watchdog(state, cycle));
+        if done {
+            let _ = send_feedback::spawn();
+        }
+    }
 
-        write_status::spawn_after(500u64.millis()).unwrap();
+    /// Starts a cycle for the pending configurations if the server doesn't send packets
+    #[task(shared = [state, referee, radio])]
+    fn configuration_cycle(ctx: configuration_cycle::Context) {
+        let done = (ctx.shared.state, ctx.shared.referee, ctx.shared.radio).lock(
+            |state, referee, radio| {
+                state.attach_configurations();
+                let broadcast = state.create_broadcast(referee.game_state());
+                radio.start_cycle(state, broadcast)
+            },
+        );
+        if done {
+            let _ = send_feedback::spawn();
+        }
     }
 
-    #[task(local = [rf, rf_amp], shared = [state, network])]
-    fn transmit(mut ctx: transmit::Context, endpoint: atsam4_hal::smoltcp::wire::IpEndpoint) {
+    /// Sends the feedback after an rf cycle. Spawning it fails while it is still pending, which is
+    /// fine as the pending run sends the latest feedback.
+    #[task(shared = [state, network, serial])]
+    fn send_feedback(mut ctx: send_feedback::Context) {
+        let now = monotonics::now().duration_since_epoch().to_micros();
         ctx.shared.state.lock(|state| {
-            rf::transmit_and_receive_feedback(state, ctx.local.rf, ctx.local.rf_amp);
-
+            if let Some(id) = state.test.take() {
+                ctx.shared.serial.lock(|serial| {
+                    let _ = commands::write_test_result(serial, state, id);
+                    serial.write_all(b"> ");
+                });
+            }
+            if let Some((id, received)) = state.console_configuration.take() {
+                ctx.shared.serial.lock(|serial| {
+                    let _ = commands::write_configuration_result(serial, id, received);
+                    serial.write_all(b"> ");
+                });
+            }
+            if state.needs_configuration_cycle(now) {
+                let _ = configuration_cycle::spawn_after(10u64.millis());
+            }
+            let Some(endpoint) = state.server else {
+                return;
+            };
             if let Some(feedback) = state.create_network_packet() {
                 ctx.shared.network.lock(|network| {
                     network.send_feedback(&feedback, endpoint);
                 });
             }
-        })
+        });
+    }
+
+    #[task(shared = [state, network, serial, status, vision, referee, radio, settings])]
+    fn run_command(mut ctx: run_command::Context, command: Command) {
+        info!("Console command: {}", command);
+        match command {
+            Command::Help => {
+                ctx.shared
+                    .serial
+                    .lock(|serial| serial.write_all(console::HELP.as_bytes()));
+            }
+            Command::Status => {
+                let rf = ctx.shared.radio.lock(|radio| radio.stats());
+                let vision = ctx.shared.vision.lock(|vision| vision.is_forwarding());
+                let referee = ctx.shared.referee.lock(|referee| referee.is_forwarding());
+                let config = ctx.shared.settings.lock(|settings| *settings.config());
+                (ctx.shared.status, ctx.shared.serial).lock(|status, serial| {
+                    status.rf = rf;
+                    let _ = status.write_to(serial);
+                    let _ = write!(
+                        serial,
+                        "RF: {} MHz, {} dBm, server port {}\n\r",
+                        config.rf_frequency, config.tx_power, config.server_port
+                    );
+                    let _ = write!(serial, "Robot ids: {}\n\r", config.owned_ids);
+                    let _ = write!(
+                        serial,
+                        "Forwarding: vision {}, referee {}\n\r",
+                        commands::on_off(vision),
+                        commands::on_off(referee)
+                    );
+                });
+            }
+            Command::Robots => {
+                let now = monotonics::now().duration_since_epoch().to_micros();
+                (ctx.shared.state, ctx.shared.serial).lock(|state, serial| {
+                    let _ = commands::write_robots(serial, state, now);
+                });
+            }
+            Command::Frequency(frequency) => {
+                let result = ctx
+                    .shared
+                    .radio
+                    .lock(|radio| radio.set_frequency(frequency));
+                ctx.shared.serial.lock(|serial| {
+                    let _ = commands::write_setting_result(serial, result);
+                });
+                if result.is_ok() {
+                    save_config(ctx.shared.settings, ctx.shared.serial, |config| {
+                        config.rf_frequency = frequency;
+                    });
+                }
+            }
+            Command::TxPower(power) => {
+                let result = ctx.shared.radio.lock(|radio| radio.set_tx_power(power));
+                ctx.shared.serial.lock(|serial| {
+                    let _ = commands::write_setting_result(serial, result);
+                });
+                if result.is_ok() {
+                    save_config(ctx.shared.settings, ctx.shared.serial, |config| {
+                        config.tx_power = power;
+                    });
+                }
+            }
+            Command::StaticIp { .. } | Command::Dhcp => {
+                let address = match command {
+                    Command::StaticIp {
+                        address,
+                        prefix,
+                        gateway,
+                    } => AddressConfig::Static {
+                        address,
+                        prefix,
+                        gateway,
+                    },
+                    _ => AddressConfig::Dhcp,
+                };
+                (ctx.shared.network, ctx.shared.status).lock(|network, status| {
+                    network.set_config(address, status);
+                });
+                save_config(ctx.shared.settings, ctx.shared.serial, |config| {
+                    config.address = address;
+                });
+            }
+            Command::Test { id, team } => {
+                if is_owned(&mut ctx.shared.state, &mut ctx.shared.serial, id) {
+                    let done = (ctx.shared.state, ctx.shared.referee, ctx.shared.radio).lock(
+                        |state, referee, radio| {
+                            state.queue_test_packet(id, team);
+                            let broadcast = state.create_broadcast(referee.game_state());
+                            radio.start_cycle(state, broadcast)
+                        },
+                    );
+                    if done {
+                        let _ = send_feedback::spawn();
+                    }
+                    // the answer is reported at the end of the cycle
+                    return;
+                }
+            }
+            Command::Ids(ids) => {
+                let owned_ids = RobotIds::from_bits(ids);
+                ctx.shared.state.lock(|state| state.owned_ids = owned_ids);
+                info!("Controlling the robots {}", owned_ids);
+                save_config(ctx.shared.settings, ctx.shared.serial, |config| {
+                    config.owned_ids = owned_ids;
+                });
+            }
+            Command::RobotFrequency {
+                id,
+                team,
+                frequency,
+            } => {
+                if is_owned(&mut ctx.shared.state, &mut ctx.shared.serial, id) {
+                    ctx.shared.state.lock(|state| {
+                        let settings = [RobotConfiguration::RfFrequency(frequency)];
+                        state.queue_configuration(id, team, settings.into_iter().collect(), None);
+                    });
+                    // a cycle may already be scheduled
+                    let _ = configuration_cycle::spawn();
+                    ctx.shared.serial.lock(|serial| {
+                        serial.write_all(
+                            b"Sending the frequency until the robot acknowledges it\n\r",
+                        );
+                    });
+                }
+            }
+            Command::Vision(forward) => {
+                ctx.shared
+                    .vision
+                    .lock(|vision| vision.set_forwarding(forward));
+            }
+            Command::Referee(forward) => {
+                ctx.shared
+                    .referee
+                    .lock(|referee| referee.set_forwarding(forward));
+            }
+        }
+        ctx.shared.serial.lock(|serial| serial.write_all(b"> "));
     }
 
-    #[task(shared = [serial, status])]
-    fn print_status(mut ctx: print_status::Context) {
-        ctx.shared.status.lock(|status| {
-            ctx.shared.serial.lock(|serial| {
-                status.write_to_serial(serial);
+    /// Tells the console user if the robot with `id` is controlled by another basestation
+    fn is_owned(
+        mut state: impl Mutex<T = RobotState>,
+        mut serial: impl Mutex<T = UsbSerial<'static>>,
+        id: u8,
+    ) -> bool {
+        let owned = state.lock(|state| state.owned_ids.contains(id));
+        if !owned {
+            serial.lock(|serial| {
+                let _ = write!(
+                    serial,
+                    "robot {} is controlled by another basestation. See ids\n\r",
+                    id
+                );
             });
-        });
-        print_status::spawn_after(1u64.secs()).unwrap();
+        }
+        owned
     }
 
-    #[task(binds = UDP, shared = [serial])]
+    /// Saves the configuration changed by a console command
+    fn save_config(
+        mut settings: impl Mutex<T = SettingsStore>,
+        mut serial: impl Mutex<T = UsbSerial<'static>>,
+        update: impl FnOnce(&mut Config),
+    ) {
+        if let Err(error) = settings.lock(|settings| settings.update(update)) {
+            warn!("Failed to save the configuration: {}", error);
+            serial.lock(|serial| serial.write_all(b"Failed to save the configuration\n\r"));
+        }
+    }
+
+    #[task(binds = UDP, local = [line: LineBuffer<64> = LineBuffer::new()], shared = [serial])]
     fn usb_interrupt(mut ctx: usb_interrupt::Context) {
+        let line = ctx.local.line;
         ctx.shared.serial.lock(|serial| {
-            if serial.on_interrupt() {
-                let mut buffer = [0_u8; 64];
-                if let Ok(length) = serial.serial.read(&mut buffer[..]) {
-                    info!("Got data: {:?}", buffer[..length]);
+            if !serial.on_interrupt() {
+                return;
+            }
+            let mut buffer = [0_u8; 64];
+            let Ok(length) = serial.serial.read(&mut buffer[..]) else {
+                return;
+            };
+            for byte in &buffer[..length] {
+                // echo the input like a terminal
+                match byte {
+                    b'\r' | b'\n' => serial.write_all(b"\n\r"),
+                    0x08 | 0x7f => serial.write_all(b"\x08 \x08"),
+                    _ => serial.write_all(core::slice::from_ref(byte)),
+                }
+                match line.push(*byte) {
+                    None => {}
+                    Some(Ok(text)) => match console::parse(text) {
+                        Ok(Some(command)) => {
+                            if run_command::spawn(command).is_err() {
+                                serial.write_all(b"Busy\n\r> ");
+                            }
+                        }
+                        Ok(None) => serial.write_all(b"> "),
+                        Err(error) => {
+                            let _ = write!(serial, "{}\n\r> ", error.message());
+                        }
+                    },
+                    Some(Err(())) => serial.write_all(b"Invalid line\n\r> "),
                 }
             }
         });
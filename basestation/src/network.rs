@@ -1,42 +1,70 @@
 use atsam4_hal::smoltcp::iface::Config;
-use atsam4_hal::{
-    heapless::Vec,
-    smoltcp::{
-        iface::{Interface, SocketHandle, SocketSet, SocketStorage},
-        phy::Device,
-        socket::{dhcpv4, udp},
-        time::Instant,
-        wire::{DhcpOption, EthernetAddress, HardwareAddress, IpCidr, IpEndpoint, Ipv4Address},
+use atsam4_hal::smoltcp::{
+    iface::{Interface, SocketHandle, SocketSet, SocketStorage},
+    phy::Device,
+    socket::{dhcpv4, udp},
+    time::Instant,
+    wire::{
+        DhcpOption, EthernetAddress, HardwareAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr,
     },
 };
-use defmt::{error, info, warn};
+use basestation_config::{
+    identity::{self, HOSTNAME_LEN},
+    AddressConfig,
+};
+use defmt::{error, info, warn, Format};
 use prost::Message;
-use protobuf::proto::{
-    luhsoccer::{FromBasestationWrapper, ToBasestationWrapper},
-    ssl_vision::SslWrapperPacket,
+use protobuf::{
+    luhsoccer::proto::{
+        basestation::{FromBasestationWrapper, ToBasestationWrapper},
+        ssl_vision::SslWrapperPacket,
+    },
+    Referee,
 };
 
 use crate::status::Status;
 use crate::{app::monotonics::Monotonic, HEAP};
 
+/// ms without a DHCP lease until a link-local address is used
+const DHCP_TIMEOUT: u64 = 10_000;
 const MAX_SOCKETS: usize = 10;
 const MAX_DHCP_OPTIONS: usize = 1;
+const DHCP_OPTION_HOSTNAME: u8 = 12;
 const MAX_VISION_TX_METADATA: usize = 1;
 const MAX_VISION_TX_DATA: usize = 16;
 const MAX_VISION_RX_METADATA: usize = 10;
 const MAX_VISION_RX_DATA: usize = 1024;
+const MAX_REFEREE_TX_METADATA: usize = 1;
+const MAX_REFEREE_TX_DATA: usize = 16;
+const MAX_REFEREE_RX_METADATA: usize = 4;
+const MAX_REFEREE_RX_DATA: usize = 1024;
 const MAX_SERVER_TX_METADATA: usize = 10;
 const MAX_SERVER_TX_DATA: usize = 1024;
 const MAX_SERVER_RX_METADATA: usize = 10;
 const MAX_SERVER_RX_DATA: usize = 1024;
 
+/// Where the current IPv4 address comes from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Format)]
+pub enum AddressSource {
+    #[default]
+    None,
+    Dhcp,
+    LinkLocal,
+    Static,
+}
+
 pub struct Storage<'a> {
     sockets: [SocketStorage<'a>; MAX_SOCKETS],
     dhcp_options: [DhcpOption<'a>; MAX_DHCP_OPTIONS],
+    hostname: [u8; HOSTNAME_LEN],
     vision_tx_metadata: [udp::PacketMetadata; MAX_VISION_TX_METADATA],
     vision_tx_data: [u8; MAX_VISION_TX_DATA],
     vision_rx_metadata: [udp::PacketMetadata; MAX_VISION_RX_METADATA],
     vision_rx_data: [u8; MAX_VISION_RX_DATA],
+    referee_tx_metadata: [udp::PacketMetadata; MAX_REFEREE_TX_METADATA],
+    referee_tx_data: [u8; MAX_REFEREE_TX_DATA],
+    referee_rx_metadata: [udp::PacketMetadata; MAX_REFEREE_RX_METADATA],
+    referee_rx_data: [u8; MAX_REFEREE_RX_DATA],
     server_tx_metadata: [udp::PacketMetadata; MAX_SERVER_TX_METADATA],
     server_tx_data: [u8; MAX_SERVER_TX_DATA],
     server_rx_metadata: [udp::PacketMetadata; MAX_SERVER_RX_METADATA],
@@ -53,14 +81,20 @@ impl Storage<'_> {
     pub const fn new() -> Self {
         Self {
             sockets: [SocketStorage::EMPTY; MAX_SOCKETS],
+            // the host name is set by `Network::from_device`
             dhcp_options: [DhcpOption {
-                kind: 12,
-                data: b"luhbots-bs",
+                kind: DHCP_OPTION_HOSTNAME,
+                data: &[],
             }],
+            hostname: [0; HOSTNAME_LEN],
             vision_tx_metadata: [udp::PacketMetadata::EMPTY; MAX_VISION_TX_METADATA],
             vision_tx_data: [0u8; MAX_VISION_TX_DATA],
             vision_rx_metadata: [udp::PacketMetadata::EMPTY; MAX_VISION_RX_METADATA],
             vision_rx_data: [0u8; MAX_VISION_RX_DATA],
+            referee_tx_metadata: [udp::PacketMetadata::EMPTY; MAX_REFEREE_TX_METADATA],
+            referee_tx_data: [0u8; MAX_REFEREE_TX_DATA],
+            referee_rx_metadata: [udp::PacketMetadata::EMPTY; MAX_REFEREE_RX_METADATA],
+            referee_rx_data: [0u8; MAX_REFEREE_RX_DATA],
             server_tx_metadata: [udp::PacketMetadata::EMPTY; MAX_SERVER_TX_METADATA],
             server_tx_data: [0u8; MAX_SERVER_TX_DATA],
             server_rx_metadata: [udp::PacketMetadata::EMPTY; MAX_SERVER_RX_METADATA],
@@ -78,12 +112,21 @@ where
     sockets: SocketSet<'s>,
     dhcp_handle: SocketHandle,
     vision_handle: SocketHandle,
+    referee_handle: SocketHandle,
     server_handle: SocketHandle,
+    server_port: u16,
+    config: AddressConfig,
+    source: AddressSource,
+    /// Used if there is no DHCP server
+    link_local: Ipv4Cidr,
+    /// ms since boot when the last DHCP lease was lost
+    dhcp_started: u64,
 }
 
 const SSL_VISION_MULTICAST: Ipv4Address = Ipv4Address::new(224, 5, 23, 2);
 const SSL_VISION_MULTICAST_PORT: u16 = 10006;
-const SERVER_PORT: u16 = 0xb45e;
+const SSL_REFEREE_MULTICAST: Ipv4Address = Ipv4Address::new(224, 5, 23, 1);
+const SSL_REFEREE_MULTICAST_PORT: u16 = 10003;
 
 impl<'s, D> Network<'s, D>
 where
@@ -93,6 +136,7 @@ where
         mut device: D,
         hardware_address: [u8; 6],
         storage: &'s mut Storage<'s>,
+        server_port: u16,
     ) -> Self {
         let mut config = Config::new();
         config.hardware_addr = Some(HardwareAddress::Ethernet(EthernetAddress::from_bytes(
@@ -103,6 +147,11 @@ where
         let mut sockets = SocketSet::new(&mut storage.sockets[..]);
 
         let mut dhcp = dhcpv4::Socket::new();
+        storage.hostname = identity::hostname(hardware_address);
+        storage.dhcp_options = [DhcpOption {
+            kind: DHCP_OPTION_HOSTNAME,
+            data: &storage.hostname,
+        }];
         dhcp.set_outgoing_options(&storage.dhcp_options);
         let dhcp_handle = sockets.add(dhcp);
 
@@ -117,6 +166,17 @@ where
         let vision = udp::Socket::new(vision_rx, vision_tx);
         let vision_handle = sockets.add(vision);
 
+        let referee_rx = udp::PacketBuffer::new(
+            &mut storage.referee_rx_metadata[..],
+            &mut storage.referee_rx_data[..],
+        );
+        let referee_tx = udp::PacketBuffer::new(
+            &mut storage.referee_tx_metadata[..],
+            &mut storage.referee_tx_data[..],
+        );
+        let referee = udp::Socket::new(referee_rx, referee_tx);
+        let referee_handle = sockets.add(referee);
+
         let server_rx = udp::PacketBuffer::new(
             &mut storage.server_rx_metadata[..],
             &mut storage.server_rx_data[..],
@@ -134,40 +194,150 @@ where
             sockets,
             dhcp_handle,
             vision_handle,
+            referee_handle,
             server_handle,
+            server_port,
+            config: AddressConfig::Dhcp,
+            source: AddressSource::None,
+            link_local: link_local_address(hardware_address),
+            dhcp_started: now_ms(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: AddressConfig, status: &mut Status) {
+        self.config = config;
+        match config {
+            AddressConfig::Dhcp => {
+                info!("Using dhcp");
+                self.set_address(None, None, AddressSource::None, status);
+                self.sockets
+                    .get_mut::<dhcpv4::Socket>(self.dhcp_handle)
+                    .reset();
+                self.dhcp_started = now_ms();
+            }
+            AddressConfig::Static {
+                address,
+                prefix,
+                gateway,
+            } => {
+                let cidr = Ipv4Cidr::new(Ipv4Address(address), prefix);
+                self.set_address(
+                    Some(cidr),
+                    gateway.map(Ipv4Address),
+                    AddressSource::Static,
+                    status,
+                );
+            }
+        }
+    }
+
+    /// Configures the interface with `address` and the default route over `router`
+    fn set_address(
+        &mut self,
+        address: Option<Ipv4Cidr>,
+        router: Option<Ipv4Address>,
+        source: AddressSource,
+        status: &mut Status,
+    ) {
+        match address {
+            Some(cidr) => info!(
+                "Got IPv4: {}/{} from {}",
+                &cidr.address().0,
+                cidr.prefix_len(),
+                source
+            ),
+            None => info!("Removed IPv4 address"),
+        }
+        self.source = source;
+        status.ip = address.map(|cidr| cidr.address().0);
+        status.address_source = source;
+
+        let routes = self.interface.routes_mut();
+        routes.remove_default_ipv4_route();
+        if let Some(router) = router {
+            info!("Default gateway: {}", &router.0);
+            if routes.add_default_ipv4_route(router).is_err() {
+                warn!("Failed to add the default route");
+            }
+        }
+        self.interface.update_ip_addrs(|addrs| {
+            addrs.clear();
+            if let Some(cidr) = address {
+                // the interface has room for one address
+                let _ = addrs.push(IpCidr::Ipv4(cidr));
+            }
+        });
+
+        // the server socket is bound to the old address
+        self.sockets
+            .get_mut::<udp::Socket>(self.server_handle)
+            .close();
+        if address.is_some() {
+            self.join_multicast_groups();
         }
     }
 
+    fn join_multicast_groups(&mut self) {
+        #[allow(clippy::cast_possible_wrap)]
+        let now = Instant::from_millis(now_ms() as i64);
+        for group in [SSL_VISION_MULTICAST, SSL_REFEREE_MULTICAST] {
+            if self
+                .interface
+                .join_multicast_group(&mut self.device, group, now)
+                .is_err()
+            {
+                warn!("Failed to join multicast group {}", &group.0);
+            }
+        }
+        info!("Joined multicast groups");
+    }
+
     pub fn poll(
         &mut self,
         on_vision: impl FnOnce(SslWrapperPacket),
+        on_referee: impl FnOnce(Referee),
         on_server: impl FnOnce(ToBasestationWrapper, IpEndpoint),
         status: &mut Status,
     ) {
+        let now_ms = now_ms();
         #[allow(clippy::cast_possible_wrap)]
-        let now = Instant::from_millis(Monotonic::now().duration_since_epoch().to_millis() as i64);
+        let now = Instant::from_millis(now_ms as i64);
+        if self.config == AddressConfig::Dhcp
+            && self.source == AddressSource::None
+            && now_ms.saturating_sub(self.dhcp_started) > DHCP_TIMEOUT
+        {
+            warn!("No dhcp server. Using a link-local address");
+            self.set_address(
+                Some(self.link_local),
+                None,
+                AddressSource::LinkLocal,
+                status,
+            );
+        }
         if self
             .interface
             .poll(now, &mut self.device, &mut self.sockets)
         {
-            match self
+            let event = self
                 .sockets
                 .get_mut::<dhcpv4::Socket>(self.dhcp_handle)
-                .poll()
-            {
+                .poll();
+            match event {
+                // a static address stays until dhcp is used again
+                _ if self.config != AddressConfig::Dhcp => {}
                 Some(dhcpv4::Event::Configured(config)) => {
-                    update_with_dhcp(&mut self.interface, &config, status);
-                    self.interface
-                        .join_multicast_group(&mut self.device, SSL_VISION_MULTICAST, now)
-                        .unwrap();
-                    info!("Joined multicast group");
+                    let (address, router) = (config.address, config.router);
+                    if router.is_none() {
+                        warn!("Dhcp lease without a router");
+                    }
+                    self.set_address(Some(address), router, AddressSource::Dhcp, status);
                 }
                 Some(dhcpv4::Event::Deconfigured) => {
                     info!("Dhcp deconfigured!");
-                    self.interface.update_ip_addrs(Vec::clear);
-                    let _ = self.interface.routes_mut().remove_default_ipv4_route();
+                    self.set_address(None, None, AddressSource::None, status);
+                    self.dhcp_started = now_ms;
                 }
-                _ => {}
+                None => {}
             }
 
             let vision = self.sockets.get_mut::<udp::Socket>(self.vision_handle);
@@ -183,7 +353,33 @@ where
                     |(data, _sender)| {
                         SslWrapperPacket::decode(data).map_or_else(
                             |_| error!("decoding protobuf packet from vision"),
-                            on_vision,
+                            |packet| {
+                                status.last_vision = Some(now_ms);
+                                on_vision(packet);
+                            },
+                        );
+                        if HEAP.used() != 0 {
+                            // Memory should always free after this
+                            warn!("Memory leak! Something bad will happen");
+                        }
+                    },
+                );
+            }
+
+            let referee = self.sockets.get_mut::<udp::Socket>(self.referee_handle);
+            if !referee.is_open() {
+                info!("Open ssl referee socket");
+                referee.bind(SSL_REFEREE_MULTICAST_PORT).ok();
+            }
+            if referee.can_recv() {
+                referee.recv().map_or_else(
+                    |_| {
+                        info!("Error while reading referee data");
+                    },
+                    |(data, _sender)| {
+                        Referee::decode(data).map_or_else(
+                            |_| error!("decoding protobuf packet from the game controller"),
+                            on_referee,
                         );
                         if HEAP.used() != 0 {
                             // Memory should always free after this
@@ -198,7 +394,7 @@ where
                 for cidr in self.interface.ip_addrs() {
                     info!("Opening server socket");
                     server
-                        .bind(IpEndpoint::new(cidr.address(), SERVER_PORT))
+                        .bind(IpEndpoint::new(cidr.address(), self.server_port))
                         .ok();
                 }
             }
@@ -209,6 +405,7 @@ where
                     },
                     |(data, sender)| {
                         if let Ok(packet) = ToBasestationWrapper::decode(data) {
+                            status.last_server = Some(now_ms);
                             on_server(packet, sender);
                         } else {
                             warn!("Error while decoding protobuf packet from server");
@@ -238,20 +435,13 @@ where
     }
 }
 
-fn update_with_dhcp(interface: &mut Interface, config: &dhcpv4::Config, status: &mut Status) {
-    info!(
-        "Got IPv4: {} and default gateway: {}",
-        &config.address.address().0,
-        &config.router.unwrap().0
-    );
-    status.ip = Some(config.address.address().0);
-    interface.routes_mut().remove_default_ipv4_route();
-    interface
-        .routes_mut()
-        .add_default_ipv4_route(config.router.unwrap())
-        .unwrap();
-    interface.update_ip_addrs(|addrs| {
-        addrs.clear();
-        addrs.push(IpCidr::Ipv4(config.address)).unwrap();
-    });
+fn now_ms() -> u64 {
+    Monotonic::now().duration_since_epoch().to_millis()
+}
+
+/// Address in 169.254.0.0/16 derived from the MAC address like in RFC 3927. The first and the
+/// last 256 addresses are reserved.
+fn link_local_address(hardware_address: [u8; 6]) -> Ipv4Cidr {
+    let [.., high, low] = hardware_address;
+    Ipv4Cidr::new(Ipv4Address::new(169, 254, high.clamp(1, 254), low), 16)
 }
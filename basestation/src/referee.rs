@@ -0,0 +1,68 @@
+use defmt::{info, warn};
+use intra_comms::definitions::{BasestationToRobot, GameState};
+use protobuf::{referee::Command, Referee};
+
+use crate::app::monotonics::Monotonic;
+
+/// The game controller sends multiple packets per second. Without them the robots are controlled
+/// by the server alone again.
+const TIMEOUT_MS: u64 = 1000;
+
+/// Latest command of the game controller
+#[derive(Default)]
+pub struct RefereeState {
+    game_state: Option<GameState>,
+    /// ms since boot
+    last_update: u64,
+    /// The game controller is ignored
+    paused: bool,
+}
+
+impl RefereeState {
+    pub fn is_forwarding(&self) -> bool {
+        !self.paused
+    }
+
+    /// Starts or stops forwarding the game state to the robots
+    pub fn set_forwarding(&mut self, forward: bool) {
+        info!("Forwarding the game controller: {}", forward);
+        self.paused = !forward;
+        self.game_state = None;
+    }
+
+    pub fn update(&mut self, packet: &Referee) {
+        if self.paused {
+            return;
+        }
+        let game_state = match packet.command() {
+            Command::Halt => GameState::Halt,
+            Command::Stop => GameState::Stop,
+            _ => GameState::Normal,
+        };
+        if self.game_state != Some(game_state) {
+            info!("game controller changed the game state to {}", game_state);
+        }
+        self.game_state = Some(game_state);
+        self.last_update = now();
+    }
+
+    pub fn game_state(&mut self) -> GameState {
+        if self.game_state.is_some() && now() - self.last_update > TIMEOUT_MS {
+            warn!("lost connection to the game controller");
+            self.game_state = None;
+        }
+        self.game_state.unwrap_or(GameState::Normal)
+    }
+
+    /// Sets the game state of all packets to be sent
+    pub fn attach_to(&mut self, send_buffer: &mut [Option<BasestationToRobot>]) {
+        let game_state = self.game_state();
+        for packet in send_buffer.iter_mut().flatten() {
+            packet.game_state = game_state;
+        }
+    }
+}
+
+fn now() -> u64 {
+    Monotonic::now().duration_since_epoch().to_millis()
+}
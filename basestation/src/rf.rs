@@ -1,13 +1,33 @@
 use atsam4_hal::{
-    gpio::{Floating, Input, Output, Pa11, Pa22, Pa27, Pa5, Pa8, Pd18, Pd28, PushPull},
+    gpio::{Floating, Input, Output, Pa11, Pa22, Pa27, Pa5, Pa8, Pd18, Pd19, Pd28, PushPull},
     spi::{SpiMaster, SpiU8},
 };
-use defmt::warn;
-use intra_comms::definitions::{BasestationToRobot, RobotToBasestation};
-use sky66112::Sky66112;
-use sx1280::{SimpleSpiDevice, Sx1280};
+use defmt::{error, info, warn, Format};
+use fugit::{ExtU64, RateExtU32};
+use intra_comms::definitions::{
+    BasestationToRobot, Broadcast, RobotToBasestation, Team, TimesyncTimestamp,
+};
+use sky66112::{ReceiveLnaMode, Sky66112, SleepMode, TransmitHighPowerMode};
+use sx1280::{
+    definitions::{IrqBit, PeriodBase, RampTime},
+    SimpleSpiDevice, Sx1280,
+};
 
-use crate::{app::monotonics::Monotonic, robot_state::RobotState};
+use crate::{
+    app::{monotonics::Monotonic, rf_poll, rf_watchdog},
+    link_quality::SlotOutcome,
+    robot_state::RobotState,
+    tdma::{Slot, Tdma, TdmaStats},
+};
+
+/// us until a robot receives a packet if the round trip time wasn't measured yet
+const DEFAULT_LATENCY: u32 = 300;
+/// ms until sending a packet is aborted
+const TX_TIMEOUT: u16 = 2;
+/// us added to the longest possible cycle until the watchdog aborts it
+const WATCHDOG_MARGIN: u32 = 1000;
+/// us between two reads of the interrupts of the transceiver while a cycle is running
+const POLL_INTERVAL: u64 = 200;
 
 pub type Transceiver = Sx1280<
     SimpleSpiDevice<SpiMaster<SpiU8>, Pa11<Output<PushPull>>>,
@@ -18,8 +38,8 @@ pub type Transceiver = Sx1280<
     sx1280::Blocking,
 >;
 
-pub type Amp = Sky66112<
-    sky66112::SleepMode,
+pub type Amp<M = SleepMode> = Sky66112<
+    M,
     sky66112::TiedHigh,
     Pa5<Output<PushPull>>,
     Pa22<Output<PushPull>>,
@@ -28,81 +48,381 @@ pub type Amp = Sky66112<
     Pa8<Output<PushPull>>,
 >;
 
-pub fn transmit_and_receive_feedback(
-    state: &mut RobotState,
-    transceiver: &mut Transceiver,
-    amp: &mut Option<Amp>,
-) {
-    for buffer_entry in state.send_buffer.iter_mut() {
-        if let Some(packet) = buffer_entry {
-            let start = Monotonic::now();
-            if let Ok(serialized_packet) = postcard::to_vec::<BasestationToRobot, 127>(packet) {
-                transceiver
-                    .set_sync_word1(intra_comms::ROBOT_BLUE_SYNC_WORDS[(packet.id) as usize])
-                    .unwrap();
-
-                let sky_send = amp.take().unwrap().into_transmit_high_power_mode();
-
-                transceiver
-                    .send_packet::<40>(
-                        &serialized_packet[..],
-                        sx1280::definitions::PeriodBase::MilliSeconds1,
-                        10,
-                    )
-                    .unwrap();
-
-                loop {
-                    let irq_reader = transceiver.irq_status().unwrap();
-                    if irq_reader.is_set(sx1280::definitions::IrqBit::TxDone) {
-                        break;
-                    } else if irq_reader.is_set(sx1280::definitions::IrqBit::RxTxTimeout) {
-                        warn!("Packet sending timed out");
-                        break;
-                    }
-                }
+/// DIO1 of the SX1280. It is raised by TxDone, RxDone and RxTxTimeout. The wiring isn't confirmed
+/// by a schematic yet, so the interrupts are polled as well.
+pub type Dio1 = Pd19<Input<Floating>>;
+
+enum AmpMode {
+    Sleep(Amp),
+    Transmit(Amp<TransmitHighPowerMode>),
+    Receive(Amp<ReceiveLnaMode>),
+}
+
+impl AmpMode {
+    fn into_sleep(self) -> Self {
+        match self {
+            Self::Sleep(amp) => Self::Sleep(amp),
+            Self::Transmit(amp) => Self::Sleep(amp.into_sleep_mode2()),
+            Self::Receive(amp) => Self::Sleep(amp.into_sleep_mode2()),
+        }
+    }
+
+    fn into_transmit(self) -> Self {
+        match self {
+            Self::Sleep(amp) => Self::Transmit(amp.into_transmit_high_power_mode()),
+            Self::Transmit(amp) => Self::Transmit(amp),
+            Self::Receive(amp) => Self::Transmit(amp.into_transmit_high_power_mode()),
+        }
+    }
+
+    fn into_receive(self) -> Self {
+        match self {
+            Self::Sleep(amp) => Self::Receive(amp.into_receive_lna_mode()),
+            Self::Transmit(amp) => Self::Receive(amp.into_receive_lna_mode()),
+            Self::Receive(amp) => Self::Receive(amp),
+        }
+    }
+}
+
+/// Why a setting of the transceiver wasn't changed
+#[derive(Debug, Clone, Copy, Format)]
+pub enum SettingError {
+    /// A cycle is running
+    Busy,
+    Transceiver,
+}
+
+#[derive(Clone, Copy)]
+enum Phase {
+    Idle,
+    Broadcast,
+    /// Sending the packet of the robot of `Team` in the slot
+    Command(Slot, Team),
+    /// Waiting for the feedback of the robot of `Team` in the slot
+    Feedback(Slot, Team),
+}
+
+/// Sends the packets to the robots in the slots planned by [`Tdma`].
+///
+/// Every step of a cycle is started by the DIO1 interrupt of the transceiver, so the basestation
+/// keeps handling the network while the robots are answering. The interrupts of the transceiver are
+/// also polled during a cycle, so the cycle goes on without DIO1, only slower.
+pub struct Radio {
+    transceiver: Transceiver,
+    amp: Option<AmpMode>,
+    dio1: Dio1,
+    tdma: Tdma,
+    phase: Phase,
+    /// us since boot
+    cycle_start: u64,
+    /// us since boot
+    slot_start: u64,
+    /// Aborts the running cycle if an interrupt is lost
+    watchdog: Option<rf_watchdog::SpawnHandle>,
+}
+
+impl Radio {
+    pub fn new(transceiver: Transceiver, amp: Amp, mut dio1: Dio1) -> Self {
+        dio1.enable_rising_edge_interrupt();
+        Self {
+            transceiver,
+            amp: Some(AmpMode::Sleep(amp)),
+            dio1,
+            tdma: Tdma::default(),
+            phase: Phase::Idle,
+            cycle_start: 0,
+            slot_start: 0,
+            watchdog: None,
+        }
+    }
+
+    pub fn stats(&self) -> TdmaStats {
+        self.tdma.stats()
+    }
+
+    /// Sets the frequency in MHz
+    pub fn set_frequency(&mut self, frequency: u16) -> Result<(), SettingError> {
+        if self.tdma.is_running() {
+            return Err(SettingError::Busy);
+        }
+        self.transceiver
+            .set_frequency(u32::from(frequency).MHz())
+            .map_err(|_| SettingError::Transceiver)?;
+        info!("Set the rf frequency to {} MHz", frequency);
+        Ok(())
+    }
+
+    /// Sets the output power of the transceiver in dBm
+    pub fn set_tx_power(&mut self, power: i8) -> Result<(), SettingError> {
+        if self.tdma.is_running() {
+            return Err(SettingError::Busy);
+        }
+        self.transceiver
+            .set_tx_param(power, RampTime::Ramp02us)
+            .map_err(|_| SettingError::Transceiver)?;
+        info!("Set the rf tx power to {} dBm", power);
+        Ok(())
+    }
+
+    /// Starts a new cycle with `broadcast` followed by the packets in the send buffer. Returns
+    /// true if the cycle is already done.
+    ///
+    /// If the last cycle is still running, its remaining slots send the latest packets and the
+    /// others are sent in the next cycle.
+    pub fn start_cycle(&mut self, state: &mut RobotState, mut broadcast: Broadcast) -> bool {
+        if self.tdma.is_running() {
+            warn!("Last rf cycle is still running");
+            self.tdma.overrun();
+            return false;
+        }
+        self.tdma
+            .start_cycle(&mut state.send_buffer, &state.last_rtt);
+        self.cycle_start = now();
+        let max_duration = self.tdma.max_duration(u32::from(TX_TIMEOUT) * 1000) + WATCHDOG_MARGIN;
+        match rf_watchdog::spawn_after(u64::from(max_duration).micros(), self.tdma.cycle()) {
+            Ok(handle) => self.watchdog = Some(handle),
+            Err(_) => warn!("Failed to start the rf watchdog"),
+        }
+        // fails if the poll is still pending from the last cycle, which then goes on polling
+        let _ = rf_poll::spawn_after(POLL_INTERVAL.micros());
 
-                let sky_receive = sky_send.into_receive_lna_mode();
-
-                transceiver
-                    .start_receive_packet(127, sx1280::definitions::PeriodBase::MilliSeconds1, 4)
-                    .unwrap();
-
-                loop {
-                    let irq_status = transceiver.irq_status().unwrap();
-                    if irq_status.is_set(sx1280::definitions::IrqBit::RxDone) {
-                        let (rssi, errors, _sync) = transceiver.packet_status().unwrap();
-                        if errors.crc_error || errors.length_error || errors.abort_error {
-                            warn!("CRC error");
-                            break;
-                        }
-                        let packet = transceiver.read_packet::<40>().unwrap();
-
-                        if let Ok(deserialized_packet) =
-                            postcard::from_bytes::<RobotToBasestation>(&packet[..])
-                        {
-                            if deserialized_packet.id < state.receive_buffer.len() as u8 {
-                                let end = Monotonic::now();
-                                let rtt = (end - start).to_micros();
-                                state.receive_buffer[deserialized_packet.id as usize] =
-                                    Some((deserialized_packet, rssi, rtt as u32));
-                            } else {
-                                warn!("Got invalid robot id");
-                            }
-                        } else {
-                            warn!("Failed to deserialize packet");
-                        }
-
-                        break;
-                    } else if irq_status.is_set(sx1280::definitions::IrqBit::RxTxTimeout) {
-                        warn!("Timeout while waiting for packet");
-                        break;
-                    }
+        let latency = state.mean_latency().unwrap_or(DEFAULT_LATENCY);
+        broadcast.time_sync = Some(TimesyncTimestamp::from_micros(
+            self.cycle_start + u64::from(latency),
+        ));
+        let Ok(serialized_packet) = postcard::to_vec::<Broadcast, 127>(&broadcast) else {
+            warn!("Failed to serialize broadcast");
+            return self.next_slot(state);
+        };
+        if self.transmit(intra_comms::BROADCAST_SYNC_WORD, &serialized_packet) {
+            self.phase = Phase::Broadcast;
+            false
+        } else {
+            self.next_slot(state)
+        }
+    }
+
+    /// Handles the DIO1 interrupt. Returns true if the cycle is done and the feedback can be sent
+    /// to the server.
+    pub fn on_interrupt(&mut self, state: &mut RobotState) -> bool {
+        if !self.dio1.take_interrupt() {
+            return false;
+        }
+        self.handle_irq(state)
+    }
+
+    /// Reads the interrupts of the transceiver in case DIO1 isn't raised and polls again while the
+    /// cycle is running. Returns true if the cycle is done and the feedback can be sent to the
+    /// server.
+    pub fn poll(&mut self, state: &mut RobotState) -> bool {
+        if !self.tdma.is_running() {
+            return false;
+        }
+        let done = self.handle_irq(state);
+        if self.tdma.is_running() && rf_poll::spawn_after(POLL_INTERVAL.micros()).is_err() {
+            warn!("Failed to poll the rf interrupts");
+        }
+        done
+    }
+
+    /// Continues the cycle if the current step is done
+    fn handle_irq(&mut self, state: &mut RobotState) -> bool {
+        let Ok(irq) = self.transceiver.irq_status() else {
+            error!("Failed to read the rf interrupts");
+            return false;
+        };
+        let timeout = irq.is_set(IrqBit::RxTxTimeout);
+        let done = match self.phase {
+            Phase::Feedback(..) => irq.is_set(IrqBit::RxDone),
+            _ => irq.is_set(IrqBit::TxDone),
+        };
+        // the step isn't done yet or was already handled by the interrupt or the poll
+        if !done && !timeout {
+            return false;
+        }
+        match self.phase {
+            // the last step of the cycle was already handled
+            Phase::Idle => false,
+            Phase::Broadcast => {
+                if timeout {
+                    warn!("Sending the broadcast timed out");
+                }
+                self.next_slot(state)
+            }
+            Phase::Command(slot, team) => {
+                if timeout {
+                    warn!("Sending the packet for robot {} timed out", slot.id);
+                    self.miss_slot(state, slot);
+                    return self.next_slot(state);
                 }
+                self.amp = self.amp.take().map(AmpMode::into_receive);
+                // the robot has to answer within its slot
+                let elapsed = now() - self.slot_start;
+                let remaining = u64::from(slot.length).saturating_sub(elapsed);
+                let timeout = u16::try_from(remaining * 64 / 1000)
+                    .unwrap_or(u16::MAX)
+                    .max(1);
+                if self
+                    .transceiver
+                    .start_receive_packet(127, PeriodBase::MicroSeconds15N625, timeout)
+                    .is_err()
+                {
+                    error!("Failed to start receiving feedback");
+                    self.miss_slot(state, slot);
+                    return self.next_slot(state);
+                }
+                self.phase = Phase::Feedback(slot, team);
+                false
+            }
+            Phase::Feedback(slot, team) => {
+                let outcome = if irq.is_set(IrqBit::RxDone) {
+                    self.receive_feedback(state, slot, team)
+                } else {
+                    warn!("Timeout while waiting for feedback of robot {}", slot.id);
+                    SlotOutcome::Timeout
+                };
+                state.link[usize::from(slot.id)].record(outcome);
+                self.tdma.end_slot(slot, outcome.rtt());
+                self.next_slot(state)
+            }
+        }
+    }
 
-                *amp = Some(sky_receive.into_sleep_mode2());
+    /// Aborts `cycle` if it is still running because an interrupt was lost. Returns true if the
+    /// feedback can be sent to the server.
+    pub fn on_watchdog(&mut self, state: &mut RobotState, cycle: u32) -> bool {
+        if !self.tdma.is_running() || self.tdma.cycle() != cycle {
+            return false;
+        }
+        warn!("Rf cycle {} didn't finish in time. Aborting it", cycle);
+        if self.transceiver.set_standby_rc().is_err()
+            || self.transceiver.clear_interrupts().is_err()
+        {
+            error!("Failed to stop the transceiver");
+        }
+        match self.phase {
+            Phase::Idle | Phase::Broadcast => (),
+            Phase::Command(slot, _) | Phase::Feedback(slot, _) => self.miss_slot(state, slot),
+        }
+        self.watchdog = None;
+        self.amp = self.amp.take().map(AmpMode::into_sleep);
+        self.tdma.abort_cycle((now() - self.cycle_start) as u32);
+        self.phase = Phase::Idle;
+        true
+    }
+
+    /// Reads the feedback of the robot of `team` in `slot`. Packets of other robots are rejected, so
+    /// a late answer isn't credited to the slot.
+    fn receive_feedback(&mut self, state: &mut RobotState, slot: Slot, team: Team) -> SlotOutcome {
+        let rtt = (now() - self.slot_start) as u32;
+        let Ok((rssi, errors, _sync)) = self.transceiver.packet_status() else {
+            error!("Failed to read the packet status");
+            return SlotOutcome::InvalidPacket;
+        };
+        if errors.crc_error || errors.length_error || errors.abort_error {
+            warn!("CRC error");
+            return SlotOutcome::CrcError;
+        }
+        let Ok(packet) = self.transceiver.read_packet::<64>() else {
+            error!("Failed to read the packet");
+            return SlotOutcome::InvalidPacket;
+        };
+        let Ok(deserialized_packet) = postcard::from_bytes::<RobotToBasestation>(&packet[..])
+        else {
+            warn!("Failed to deserialize packet");
+            return SlotOutcome::InvalidPacket;
+        };
+        if deserialized_packet.id != slot.id || deserialized_packet.team != team {
+            warn!(
+                "Got feedback of robot {} of team {} in the slot of robot {}",
+                deserialized_packet.id, deserialized_packet.team, slot.id
+            );
+            return SlotOutcome::InvalidPacket;
+        }
+        let id = usize::from(slot.id);
+        state.last_rtt[id] = Some(rtt);
+        state.last_seen[id] = Some((now(), deserialized_packet.team));
+        state.acknowledge_configuration(
+            id,
+            deserialized_packet.team,
+            deserialized_packet.configuration_ack,
+        );
+        state.receive_buffer[id] = Some((deserialized_packet, rssi, rtt));
+        SlotOutcome::Answered { rtt, rssi }
+    }
+
+    /// Counts `slot` as missed by its robot
+    fn miss_slot(&mut self, state: &mut RobotState, slot: Slot) {
+        state.link[usize::from(slot.id)].record(SlotOutcome::Timeout);
+        self.tdma.end_slot(slot, None);
+    }
+
+    /// Starts the next slot with a packet. Ends the cycle and returns true if there is none.
+    fn next_slot(&mut self, state: &mut RobotState) -> bool {
+        while let Some(slot) = self.tdma.next_slot() {
+            let Some(packet) = state.send_buffer[usize::from(slot.id)].take() else {
+                continue;
+            };
+            let team = packet.team;
+            if self.send_command(state, slot, packet) {
+                self.phase = Phase::Command(slot, team);
+                return false;
             }
+            self.miss_slot(state, slot);
+        }
+        if let Some(watchdog) = self.watchdog.take() {
+            // fails if the watchdog is already due. It then ignores the finished cycle
+            let _ = watchdog.cancel();
+        }
+        self.amp = self.amp.take().map(AmpMode::into_sleep);
+        self.tdma.end_cycle((now() - self.cycle_start) as u32);
+        self.phase = Phase::Idle;
+        true
+    }
 
-            *buffer_entry = None;
+    fn send_command(
+        &mut self,
+        state: &RobotState,
+        slot: Slot,
+        mut packet: BasestationToRobot,
+    ) -> bool {
+        let Some(sync_word) = intra_comms::robot_sync_word(packet.team, packet.id) else {
+            warn!("No sync word for robot {}", packet.id);
+            return false;
+        };
+        self.slot_start = now();
+        // the time when the robot receives the packet
+        let latency = state.last_rtt[usize::from(slot.id)].map_or(DEFAULT_LATENCY, |rtt| rtt / 2);
+        packet.time_sync = Some(TimesyncTimestamp::from_micros(
+            self.slot_start + u64::from(latency),
+        ));
+        let Ok(serialized_packet) = postcard::to_vec::<BasestationToRobot, 127>(&packet) else {
+            warn!("Failed to serialize packet for robot {}", packet.id);
+            return false;
+        };
+        self.transmit(sync_word, &serialized_packet)
+    }
+
+    /// Starts sending `data`. TxDone or RxTxTimeout raise DIO1 when it is done.
+    fn transmit(&mut self, sync_word: u32, data: &[u8]) -> bool {
+        if self.transceiver.set_sync_word1(sync_word).is_err() {
+            error!("Failed to set the sync word");
+            return false;
+        }
+        self.amp = self.amp.take().map(AmpMode::into_transmit);
+        if self
+            .transceiver
+            .send_packet::<64>(data, PeriodBase::MilliSeconds1, TX_TIMEOUT)
+            .is_err()
+        {
+            error!("Failed to send packet");
+            self.amp = self.amp.take().map(AmpMode::into_sleep);
+            return false;
         }
+        true
     }
 }
+
+fn now() -> u64 {
+    Monotonic::now().duration_since_epoch().to_micros()
+}
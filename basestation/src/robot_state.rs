@@ -1,58 +1,418 @@
-use defmt::warn;
-use intra_comms::definitions::{BasestationToRobot, RobotToBasestation};
-use protobuf::proto::luhsoccer::{FromBasestationWrapper, ToBasestationWrapper};
+use atsam4_hal::{heapless::Vec, smoltcp::wire::IpEndpoint};
+use basestation_config::RobotIds;
+use defmt::{info, warn};
+use intra_comms::definitions::{
+    BasestationToRobot, Broadcast, ConfigurationMessage, DribblerSpeedSelection, DribblerState,
+    ErrorFlags, GameState, KickSelection, KickSpeedSelection, KickerChargeHint, LocalVelocity,
+    MovementSelection, RobotToBasestation, Team,
+};
+use protobuf::luhsoccer::proto::{
+    basestation::{
+        FirmwareVersion, FromBasestationWrapper, RobotConnection, TeamColor, ToBasestationWrapper,
+    },
+    RobotConfigurationFeedback, RobotTelemetry,
+};
 
-use crate::converter;
+use crate::{
+    app::monotonics::Monotonic,
+    converter::{self, RobotSettings},
+    link_quality::LinkStats,
+};
+
+/// Set in the error code of the feedback if a packet from the server was invalid. The lower bits
+/// contain the `ErrorFlags` of all robots.
+const INVALID_PACKET_ERROR: u32 = 1 << 8;
+/// ms without feedback until a robot is offline if the server doesn't set another timeout
+const DEFAULT_ROBOT_TIMEOUT: u32 = 500;
+
+/// us without an acknowledgement until a configuration is given up
+const CONFIGURATION_TIMEOUT: u64 = 1_000_000;
+
+/// Settings for a robot which are sent with its packets one after another until the robot
+/// acknowledges each of them
+struct PendingConfiguration {
+    team: Team,
+    /// Time stamp of the command of the server. `None` for configurations from the console.
+    time_stamp: Option<u64>,
+    settings: RobotSettings,
+    /// Sequence of the first setting, chosen when it is sent for the first time
+    sequence: Option<u8>,
+    /// us since boot when the first setting was queued or the previous one was acknowledged
+    since: u64,
+}
 
 #[derive(Default)]
 pub struct RobotState {
     feedback_seq_id: u32,
+    /// A packet from the server was invalid since the last feedback
+    invalid_packet: bool,
     pub send_buffer: [Option<BasestationToRobot>; 16],
     pub receive_buffer: [Option<(RobotToBasestation, i32, u32)>; 16],
+    /// us, round trip time of the last feedback of each robot
+    pub last_rtt: [Option<u32>; 16],
+    pub link: [LinkStats; 16],
+    /// Set by the server to stop all robots
+    emergency_stop: bool,
+    /// Team assigned to all robots by the server
+    team: Option<Team>,
+    /// Where the feedback is sent to
+    pub server: Option<IpEndpoint>,
+    /// us since boot of the last feedback of each robot and its team
+    pub last_seen: [Option<(u64, Team)>; 16],
+    /// Online state of each robot last reported to the server
+    online: [bool; 16],
+    /// Telemetry of the last feedback of each robot
+    telemetry: [Option<RobotTelemetry>; 16],
+    /// ms, set by the server
+    robot_timeout: Option<u32>,
+    /// us since boot of the last packet from the server for each robot
+    last_command: [Option<u64>; 16],
+    /// Robot a test packet was sent to from the console
+    pub test: Option<u8>,
+    /// Robots this basestation controls. Packets for the others are refused.
+    pub owned_ids: RobotIds,
+    configurations: [Option<PendingConfiguration>; 16],
+    /// Last configuration sequence each robot acknowledged
+    configuration_acks: [Option<u8>; 16],
+    /// Reported to the server with the next feedback
+    configuration_feedback: Vec<RobotConfigurationFeedback, 16>,
+    /// Robot and outcome of the last configuration from the console, written to it with the next
+    /// feedback
+    pub console_configuration: Option<(u8, bool)>,
 }
 
 impl RobotState {
     pub fn update_from_network(&mut self, packet_wrapper: ToBasestationWrapper) {
+        if packet_wrapper.emergency_stop != self.emergency_stop {
+            if packet_wrapper.emergency_stop {
+                warn!("Emergency stop of all robots");
+            } else {
+                info!("Emergency stop cleared");
+            }
+        }
+        self.emergency_stop = packet_wrapper.emergency_stop;
+        self.team = match packet_wrapper.team_color.map(TeamColor::from_i32) {
+            Some(Some(team_color)) => Some(converter::parse_team(team_color)),
+            Some(None) => {
+                warn!("Invalid team color");
+                self.invalid_packet = true;
+                None
+            }
+            None => None,
+        };
+        self.robot_timeout = packet_wrapper.robot_timeout;
+        let now = Monotonic::now().duration_since_epoch().to_micros();
         for packet in packet_wrapper.packets {
             if let Some(parsed_packet) = converter::parse_server_to_base_station(packet) {
-                if parsed_packet.id < 16 {
-                    self.send_buffer[parsed_packet.id as usize] = Some(parsed_packet);
-                } else {
+                if parsed_packet.id >= 16 {
                     warn!("Invalid robot id");
+                    self.invalid_packet = true;
+                } else if !self.owned_ids.contains(parsed_packet.id) {
+                    warn!(
+                        "Refusing the packet for robot {} which this basestation doesn't control",
+                        parsed_packet.id
+                    );
+                    self.invalid_packet = true;
+                } else {
+                    self.last_command[parsed_packet.id as usize] = Some(now);
+                    self.send_buffer[parsed_packet.id as usize] = Some(parsed_packet);
                 }
             } else {
-                warn!("Failed to parse packet"); // TODO implement error codes
+                warn!("Failed to parse packet");
+                self.invalid_packet = true;
+            }
+        }
+        for command in packet_wrapper.configurations {
+            let id = u8::try_from(command.id).unwrap_or(u8::MAX);
+            if id >= 16 {
+                warn!("Invalid robot id");
+                self.invalid_packet = true;
+                continue;
+            }
+            if !self.owned_ids.contains(id) {
+                warn!(
+                    "Refusing the configuration for robot {} which this basestation doesn't control",
+                    id
+                );
+                self.invalid_packet = true;
+                continue;
+            }
+            // the configuration doesn't tell the team
+            let team = self
+                .team
+                .or(self.last_seen[usize::from(id)].map(|(_, team)| team));
+            match (team, converter::parse_robot_configuration(&command)) {
+                (Some(team), Some(settings)) => {
+                    self.queue_configuration(id, team, settings, Some(command.time_stamp));
+                }
+                (None, _) => {
+                    warn!("Unknown team of robot {} to configure", id);
+                    self.report_configuration(id, Some(command.time_stamp), false);
+                }
+                (_, None) => {
+                    warn!("Invalid configuration for robot {}", id);
+                    self.invalid_packet = true;
+                    self.report_configuration(id, Some(command.time_stamp), false);
+                }
+            }
+        }
+    }
+
+    /// Queues a packet which stops the robot with `id` to check its link. The answer is reported
+    /// at the end of the cycle.
+    pub fn queue_test_packet(&mut self, id: u8, team: Team) {
+        self.send_buffer[usize::from(id)] = Some(stop_packet(id, team));
+        // only the answer to the test packet is reported
+        self.receive_buffer[usize::from(id)] = None;
+        self.test = Some(id);
+    }
+
+    /// Sends `settings` to the robot with `id` in `team` until it acknowledged all of them. A
+    /// configuration which wasn't acknowledged yet is replaced.
+    pub fn queue_configuration(
+        &mut self,
+        id: u8,
+        team: Team,
+        settings: RobotSettings,
+        time_stamp: Option<u64>,
+    ) {
+        if let Some(replaced) = self.configurations[usize::from(id)].take() {
+            warn!("Replacing the configuration of robot {}", id);
+            self.report_configuration(id, replaced.time_stamp, false);
+        }
+        if settings.is_empty() {
+            self.report_configuration(id, time_stamp, true);
+            return;
+        }
+        self.configurations[usize::from(id)] = Some(PendingConfiguration {
+            team,
+            time_stamp,
+            settings,
+            sequence: None,
+            since: Monotonic::now().duration_since_epoch().to_micros(),
+        });
+    }
+
+    /// Attaches the next setting of each pending configuration to the packets of this cycle.
+    /// Robots without a packet from the server get a packet which keeps their command and game
+    /// state.
+    pub fn attach_configurations(&mut self) {
+        let now = Monotonic::now().duration_since_epoch().to_micros();
+        for id in 0..self.configurations.len() {
+            let Some(pending) = &mut self.configurations[id] else {
+                continue;
+            };
+            if now.saturating_sub(pending.since) > CONFIGURATION_TIMEOUT {
+                warn!("robot {} didn't acknowledge its configuration", id);
+                let time_stamp = pending.time_stamp;
+                self.configurations[id] = None;
+                self.report_configuration(id as u8, time_stamp, false);
+                continue;
             }
+            let team = pending.team;
+            let packet =
+                self.send_buffer[id].get_or_insert_with(|| configuration_packet(id as u8, team));
+            // the sequence has to differ from the last acknowledgement, so the robot has to answer
+            // once before
+            let answered = matches!(self.last_seen[id], Some((_, seen)) if seen == team);
+            if packet.team != team || !answered {
+                continue;
+            }
+            let acks = &self.configuration_acks;
+            let sequence = *pending
+                .sequence
+                .get_or_insert_with(|| acks[id].map_or(0, |ack| ack.wrapping_add(1)));
+            packet.configuration = Some(ConfigurationMessage {
+                sequence,
+                configuration: pending.settings[0],
+            });
+        }
+    }
+
+    /// Called with the acknowledgement in each feedback of the robot with `id`
+    pub fn acknowledge_configuration(&mut self, id: usize, team: Team, ack: Option<u8>) {
+        self.configuration_acks[id] = ack;
+        let Some(pending) = &mut self.configurations[id] else {
+            return;
+        };
+        if pending.team != team || pending.sequence.is_none() || pending.sequence != ack {
+            return;
+        }
+        info!("robot {} acknowledged {}", id, pending.settings[0]);
+        pending.settings.remove(0);
+        pending.sequence = None;
+        pending.since = Monotonic::now().duration_since_epoch().to_micros();
+        if pending.settings.is_empty() {
+            let time_stamp = pending.time_stamp;
+            self.configurations[id] = None;
+            self.report_configuration(id as u8, time_stamp, true);
+        }
+    }
+
+    /// Returns if configurations are pending while the server sends no packets, so the basestation
+    /// has to start the cycles itself
+    pub fn needs_configuration_cycle(&self, now: u64) -> bool {
+        self.configurations.iter().any(Option::is_some)
+            && !(0..self.last_command.len()).any(|id| self.is_commanded(id, now))
+    }
+
+    /// Tells the server or the console if the configuration with `time_stamp` reached the robot
+    fn report_configuration(&mut self, id: u8, time_stamp: Option<u64>, received: bool) {
+        let Some(time_stamp) = time_stamp else {
+            self.console_configuration = Some((id, received));
+            return;
+        };
+        let feedback = RobotConfigurationFeedback {
+            id: u32::from(id),
+            time_stamp,
+            received_configuration_command: received,
+        };
+        if self.configuration_feedback.push(feedback).is_err() {
+            warn!("Dropping the configuration feedback for robot {}", id);
         }
     }
 
+    /// Creates the packet sent to all robots once per cycle
+    pub fn create_broadcast(&self, game_state: GameState) -> Broadcast {
+        Broadcast {
+            game_state,
+            time_sync: None,
+            emergency_stop: self.emergency_stop,
+            team: self.team,
+        }
+    }
+
+    /// us until the robots receive a packet, estimated by the round trip times of all robots
+    pub fn mean_latency(&self) -> Option<u32> {
+        let (sum, count) = self
+            .last_rtt
+            .iter()
+            .flatten()
+            .fold((0, 0), |(sum, count), rtt| (sum + rtt / 2, count + 1));
+        (count > 0).then(|| sum / count)
+    }
+
+    /// Returns if the robot with `id` answered within the robot timeout at `now` us since boot or
+    /// `None` if it never answered.
+    pub fn is_online(&self, id: usize, now: u64) -> Option<bool> {
+        self.last_seen[id].map(|(time, _)| self.within_timeout(time, now))
+    }
+
+    /// Returns if the server sent a packet for the robot with `id` within the robot timeout at
+    /// `now` us since boot.
+    pub fn is_commanded(&self, id: usize, now: u64) -> bool {
+        self.last_command[id].map_or(false, |time| self.within_timeout(time, now))
+    }
+
+    fn within_timeout(&self, time: u64, now: u64) -> bool {
+        let timeout = self.robot_timeout.unwrap_or(DEFAULT_ROBOT_TIMEOUT);
+        now.saturating_sub(time) <= u64::from(timeout) * 1000
+    }
+
     pub fn create_network_packet(&mut self) -> Option<FromBasestationWrapper> {
         let mut packet_wrapper = FromBasestationWrapper::default();
 
+        let now = Monotonic::now().duration_since_epoch().to_micros();
         let mut any_feedback = false;
-        for packet in &mut self.receive_buffer {
+        let mut errors = ErrorFlags::empty();
+        for (id, packet) in self.receive_buffer.iter_mut().enumerate() {
             if let Some((packet, rssi, rtt)) = packet.take() {
+                errors = errors | packet.error;
+                self.telemetry[id] = Some(converter::parse_telemetry(&packet));
                 packet_wrapper
                     .packets
-                    .push(converter::parse_base_station_to_server(packet, rssi, rtt));
+                    .push(converter::parse_base_station_to_server(
+                        packet,
+                        rssi,
+                        rtt,
+                        now,
+                        &self.link[id],
+                    ));
                 any_feedback = true;
             }
         }
 
+        for id in 0..self.last_seen.len() {
+            let Some((time, team)) = self.last_seen[id] else {
+                continue;
+            };
+            let online = self.is_online(id, now) == Some(true);
+            if online != self.online[id] {
+                if online {
+                    info!("robot {} is online", id);
+                } else {
+                    warn!("robot {} is offline", id);
+                }
+                self.online[id] = online;
+            }
+            packet_wrapper.robots.push(RobotConnection {
+                id: id as u32,
+                team_color: converter::team_to_color(team) as i32,
+                online,
+                last_seen: u32::try_from(now.saturating_sub(time) / 1000).unwrap_or(u32::MAX),
+                telemetry: self.telemetry[id].clone().map(|mut telemetry| {
+                    telemetry.set_connection_qualitiy(self.link[id].quality());
+                    telemetry
+                }),
+            });
+        }
+
+        packet_wrapper
+            .configuration_feedback
+            .extend(core::mem::take(&mut self.configuration_feedback));
+
         self.feedback_seq_id = self.feedback_seq_id.wrapping_add(1);
 
-        packet_wrapper.error_code = 0;
-        packet_wrapper.firmware_version = Some(protobuf::proto::luhsoccer::FirmwareVersion {
+        packet_wrapper.error_code = u32::from(errors.bits());
+        if core::mem::take(&mut self.invalid_packet) {
+            packet_wrapper.error_code |= INVALID_PACKET_ERROR;
+        }
+        packet_wrapper.firmware_version = Some(FirmwareVersion {
             major: 0,
             minor: 0,
             patch: 0,
         });
         packet_wrapper.seq_id = self.feedback_seq_id;
 
-        if any_feedback {
+        // the server has to learn about robots going offline
+        if any_feedback
+            || !packet_wrapper.robots.is_empty()
+            || !packet_wrapper.configuration_feedback.is_empty()
+        {
             Some(packet_wrapper)
         } else {
             None
         }
     }
 }
+
+/// Stops the robot without moving the ball
+fn stop_packet(id: u8, team: Team) -> BasestationToRobot {
+    BasestationToRobot {
+        id,
+        team,
+        movement: MovementSelection::RobotVelocity(LocalVelocity {
+            forward: 0,
+            left: 0,
+            counterclockwise: 0,
+        }),
+        kicker_charge_hint: KickerChargeHint::DontCare,
+        kick_speed: KickSpeedSelection::Absolute(0),
+        kick_type: KickSelection::Kick,
+        dribbler_speed: DribblerSpeedSelection::Tristate(DribblerState::Off),
+        robot_position: None,
+        game_state: GameState::Stop,
+        time_sync: None,
+        configuration: None,
+        keep_state: false,
+    }
+}
+
+/// Only carries a configuration to a robot the server doesn't command, so it keeps doing what it
+/// did before
+fn configuration_packet(id: u8, team: Team) -> BasestationToRobot {
+    BasestationToRobot {
+        keep_state: true,
+        ..stop_packet(id, team)
+    }
+}
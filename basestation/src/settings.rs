@@ -0,0 +1,102 @@
+use atsam4_hal::{
+    efc::{Efc, EfcError},
+    pac::EFC,
+};
+use basestation_config::{Config, Error, RECORD_SIZE};
+use defmt::{info, warn, Format};
+
+const RECORD_WORDS: usize = RECORD_SIZE / 4;
+
+extern "C" {
+    /// Start of the flash. Writes to it fill the latch buffer of the flash controller, which is
+    /// then written to the user signature.
+    #[link_name = "_flash"]
+    static mut FLASH_LATCH: [u32; RECORD_WORDS];
+}
+
+#[derive(Debug, Clone, Copy, Format)]
+pub enum SaveError {
+    Encode(Error),
+    Flash(EfcError),
+}
+
+/// Keeps the configuration in the user signature of the flash. Unlike the rest of the flash, it
+/// isn't erased when new firmware is flashed.
+pub struct SettingsStore {
+    efc: Efc,
+    config: Config,
+    unique_id: Option<[u32; 4]>,
+}
+
+impl SettingsStore {
+    /// Loads the saved configuration or the defaults if there is none
+    pub fn load(efc: EFC) -> Self {
+        // Safety: The latch buffer is only written by the flash controller driver
+        let efc = Efc::new(efc, unsafe { &mut FLASH_LATCH });
+        let config = read(&efc).unwrap_or_default();
+        let unique_id = efc
+            .read_unique_id()
+            .map_err(|error| warn!("Failed to read the unique id: {}", error))
+            .ok();
+        Self {
+            efc,
+            config,
+            unique_id,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Unique ID of the chip
+    pub fn unique_id(&self) -> Option<[u32; 4]> {
+        self.unique_id
+    }
+
+    /// Changes the configuration and saves it
+    pub fn update(&mut self, update: impl FnOnce(&mut Config)) -> Result<(), SaveError> {
+        update(&mut self.config);
+
+        let mut record = [0; RECORD_SIZE];
+        let length =
+            basestation_config::encode(&self.config, &mut record).map_err(SaveError::Encode)?;
+        let mut words = [0; RECORD_WORDS];
+        for (word, bytes) in words.iter_mut().zip(record.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        self.efc.erase_user_signature().map_err(SaveError::Flash)?;
+        self.efc
+            .write_user_signature(&words[..(length + 3) / 4])
+            .map_err(SaveError::Flash)?;
+        info!("Saved the configuration");
+        Ok(())
+    }
+}
+
+fn read(efc: &Efc) -> Option<Config> {
+    let mut words = [0; RECORD_WORDS];
+    if let Err(error) = efc.read_user_signature(&mut words, RECORD_WORDS) {
+        warn!("Failed to read the configuration: {}", error);
+        return None;
+    }
+    let mut record = [0; RECORD_SIZE];
+    for (bytes, word) in record.chunks_exact_mut(4).zip(&words) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    match basestation_config::decode(&record) {
+        Ok(config) => {
+            info!("Loaded the configuration: {}", config);
+            Some(config)
+        }
+        Err(Error::Empty) => {
+            info!("No saved configuration. Using the defaults");
+            None
+        }
+        Err(error) => {
+            warn!("Invalid saved configuration: {}. Using the defaults", error);
+            None
+        }
+    }
+}
@@ -1,25 +1,66 @@
-use crate::usb_serial::UsbSerial;
-use core::fmt::Write;
+use crate::{network::AddressSource, tdma::TdmaStats};
+use core::fmt::{self, Write};
+
+/// ms without packets until a source is missing
+const TRAFFIC_TIMEOUT: u64 = 1000;
 
 #[derive(Default)]
 pub struct Status {
     pub ip: Option<[u8; 4]>,
+    pub address_source: AddressSource,
+    pub rf: TdmaStats,
+    /// ms since boot of the last packet from ssl vision
+    pub last_vision: Option<u64>,
+    /// ms since boot of the last packet from the server
+    pub last_server: Option<u64>,
 }
 
 impl Status {
-    pub fn write_to_serial(&self, serial: &mut UsbSerial<'static>) {
-        let mut info = atsam4_hal::heapless::String::<32>::new();
-        info.push_str("IP: ").unwrap();
+    /// `now` is the time since boot in ms
+    pub fn vision_missing(&self, now: u64) -> bool {
+        self.last_vision
+            .map_or(true, |time| now.saturating_sub(time) > TRAFFIC_TIMEOUT)
+    }
 
+    /// `now` is the time since boot in ms
+    pub fn server_missing(&self, now: u64) -> bool {
+        self.last_server
+            .map_or(true, |time| now.saturating_sub(time) > TRAFFIC_TIMEOUT)
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> fmt::Result {
         match self.ip {
-            None => info.push_str("None\n\r").unwrap(),
-            Some(ip) => {
-                write!(info, "{}.{}.{}.{}\n\r", ip[0], ip[1], ip[2], ip[3]).unwrap();
-            }
+            None => write!(out, "IP: None\n\r")?,
+            Some(ip) => write!(
+                out,
+                "IP: {}.{}.{}.{} ({})\n\r",
+                ip[0],
+                ip[1],
+                ip[2],
+                ip[3],
+                match self.address_source {
+                    AddressSource::None => "none",
+                    AddressSource::Dhcp => "dhcp",
+                    AddressSource::LinkLocal => "link-local",
+                    AddressSource::Static => "static",
+                }
+            )?,
         }
 
-        if serial.serial.dtr() {
-            let _ = serial.serial.write(&info.as_bytes()[..info.len()]);
-        }
+        let cycle = &self.rf.last_cycle;
+        write!(
+            out,
+            "RF cycle: {} us, {} slots, {} missed, {} skipped, {}% utilised\n\r",
+            cycle.duration,
+            cycle.slots,
+            cycle.missed_slots,
+            cycle.skipped,
+            cycle.utilisation()
+        )?;
+        write!(
+            out,
+            "RF total: {} missed slots, {} overruns\n\r",
+            self.rf.missed_slots, self.rf.overruns
+        )
     }
 }
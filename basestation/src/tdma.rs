@@ -0,0 +1,169 @@
+use atsam4_hal::heapless::Vec;
+use defmt::{info, Format};
+use intra_comms::definitions::BasestationToRobot;
+
+/// us of a slot if the round trip time of the robot wasn't measured yet
+const DEFAULT_SLOT_LENGTH: u32 = 2000;
+const MIN_SLOT_LENGTH: u32 = 500;
+const MAX_SLOT_LENGTH: u32 = 4000;
+/// us added to the last round trip time of a robot to get its slot length
+const SLOT_MARGIN: u32 = 300;
+/// Robots which didn't answer in this many cycles are skipped
+const SKIP_AFTER_CYCLES: u8 = 10;
+/// Skipped robots are still polled every this many cycles to notice them coming back
+const PROBE_INTERVAL: u32 = 25;
+
+/// Time of one robot in a cycle to receive its packet and to answer it
+#[derive(Debug, Clone, Copy, Format)]
+pub struct Slot {
+    pub id: u8,
+    /// us
+    pub length: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct CycleStats {
+    /// Robots which got a slot
+    pub slots: u8,
+    /// Slots without feedback of the robot
+    pub missed_slots: u8,
+    /// Silent robots which didn't get a slot
+    pub skipped: u8,
+    /// us from the broadcast until the end of the last slot
+    pub duration: u32,
+    /// us of all slots
+    pub allotted: u32,
+    /// us of the slots used by robots which answered
+    pub used: u32,
+}
+
+impl CycleStats {
+    /// Part of the allotted slot time in percent which was used by robots which answered
+    pub fn utilisation(&self) -> u32 {
+        if self.allotted == 0 {
+            return 0;
+        }
+        u32::try_from(u64::from(self.used) * 100 / u64::from(self.allotted)).unwrap_or(100)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Format)]
+pub struct TdmaStats {
+    pub last_cycle: CycleStats,
+    /// Slots without feedback since boot
+    pub missed_slots: u32,
+    /// Packets from the server which arrived before the cycle of the last one was done
+    pub overruns: u32,
+}
+
+/// Plans the slots of the robots in each cycle.
+///
+/// Every cycle starts with the broadcast to all robots followed by one slot for each robot with a
+/// pending packet. The length of a slot follows the round trip time of the robot.
+#[derive(Default)]
+pub struct Tdma {
+    cycle: u32,
+    /// Cycles in a row each robot didn't answer in
+    silent_cycles: [u8; 16],
+    /// Remaining slots of the current cycle in reverse order
+    slots: Vec<Slot, 16>,
+    running: bool,
+    current: CycleStats,
+    stats: TdmaStats,
+}
+
+impl Tdma {
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn stats(&self) -> TdmaStats {
+        self.stats
+    }
+
+    /// Number of the current or last cycle
+    pub fn cycle(&self) -> u32 {
+        self.cycle
+    }
+
+    /// us the current cycle takes at most if the broadcast and each packet take `tx_timeout` us to
+    /// send
+    pub fn max_duration(&self, tx_timeout: u32) -> u32 {
+        self.current.allotted + (u32::from(self.current.slots) + 1) * tx_timeout
+    }
+
+    pub fn overrun(&mut self) {
+        self.stats.overruns = self.stats.overruns.wrapping_add(1);
+    }
+
+    /// Plans a new cycle for the robots with a packet in `send_buffer`. Packets of skipped robots
+    /// are dropped.
+    pub fn start_cycle(
+        &mut self,
+        send_buffer: &mut [Option<BasestationToRobot>],
+        last_rtt: &[Option<u32>],
+    ) {
+        self.cycle = self.cycle.wrapping_add(1);
+        self.running = true;
+        self.current = CycleStats::default();
+        self.slots.clear();
+        let probe = self.cycle % PROBE_INTERVAL == 0;
+        for (id, entry) in send_buffer.iter_mut().enumerate().rev() {
+            if entry.is_none() {
+                continue;
+            }
+            if self.silent_cycles[id] >= SKIP_AFTER_CYCLES && !probe {
+                *entry = None;
+                self.current.skipped += 1;
+                continue;
+            }
+            let length = last_rtt[id].map_or(DEFAULT_SLOT_LENGTH, |rtt| {
+                (rtt + SLOT_MARGIN).clamp(MIN_SLOT_LENGTH, MAX_SLOT_LENGTH)
+            });
+            // there is one entry per robot
+            let _ = self.slots.push(Slot {
+                id: id as u8,
+                length,
+            });
+            self.current.slots += 1;
+            self.current.allotted += length;
+        }
+    }
+
+    pub fn next_slot(&mut self) -> Option<Slot> {
+        self.slots.pop()
+    }
+
+    /// Records the end of `slot`. `answered_after` is the time in us the robot needed to answer
+    /// or `None` if it didn't answer.
+    pub fn end_slot(&mut self, slot: Slot, answered_after: Option<u32>) {
+        let silent_cycles = &mut self.silent_cycles[usize::from(slot.id)];
+        if let Some(time) = answered_after {
+            if *silent_cycles >= SKIP_AFTER_CYCLES {
+                info!("robot {} answers again", slot.id);
+            }
+            *silent_cycles = 0;
+            self.current.used += time.min(slot.length);
+        } else {
+            *silent_cycles = silent_cycles.saturating_add(1);
+            if *silent_cycles == SKIP_AFTER_CYCLES {
+                info!("robot {} is silent. Skipping it", slot.id);
+            }
+            self.current.missed_slots += 1;
+            self.stats.missed_slots = self.stats.missed_slots.wrapping_add(1);
+        }
+    }
+
+    /// Ends the cycle early. The remaining slots are dropped without counting them as missed.
+    pub fn abort_cycle(&mut self, duration: u32) {
+        self.slots.clear();
+        self.end_cycle(duration);
+    }
+
+    /// Ends the cycle which took `duration` us
+    pub fn end_cycle(&mut self, duration: u32) {
+        self.current.duration = duration;
+        self.stats.last_cycle = self.current;
+        self.running = false;
+    }
+}
@@ -3,9 +3,14 @@ use atsam4_hal::gpio::{Pb10, Pb11, SysFn};
 use atsam4_hal::pac::UDP;
 use atsam4_hal::udp::usb_device::bus::UsbBusAllocator;
 use atsam4_hal::udp::usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use atsam4_hal::udp::usb_device::UsbError;
 use atsam4_hal::udp::UdpBus;
+use core::fmt;
 use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
+/// Times the device is polled to empty the send buffer before the rest of a write is dropped
+const MAX_WRITE_ATTEMPTS: u32 = 1000;
+
 pub struct UsbSerial<'a> {
     pub serial: SerialPort<'a, UdpBus>,
     pub device: UsbDevice<'a, UdpBus>,
@@ -23,12 +28,12 @@ pub fn new_allocator(
 }
 
 impl<'a> UsbSerial<'a> {
-    pub fn new(allocator: &'a UsbAllocator) -> UsbSerial<'a> {
+    pub fn new(allocator: &'a UsbAllocator, serial_number: &'a str) -> UsbSerial<'a> {
         let serial = SerialPort::new(allocator);
         let device = UsbDeviceBuilder::new(allocator, UsbVidPid(0x6c62, 0x6273))
             .manufacturer("luhbots soccer")
             .product("Base station")
-            .serial_number("2")
+            .serial_number(serial_number)
             .device_class(USB_CLASS_CDC)
             .build();
 
@@ -38,4 +43,30 @@ impl<'a> UsbSerial<'a> {
     pub fn on_interrupt(&mut self) -> bool {
         self.device.poll(&mut [&mut self.serial])
     }
+
+    /// Writes `data` if a terminal is connected. Data which doesn't fit into the send buffer after
+    /// `MAX_WRITE_ATTEMPTS` is dropped.
+    pub fn write_all(&mut self, mut data: &[u8]) {
+        if !self.serial.dtr() {
+            return;
+        }
+        let mut attempts = 0;
+        while !data.is_empty() && attempts < MAX_WRITE_ATTEMPTS {
+            match self.serial.write(data) {
+                Ok(written) => data = &data[written..],
+                Err(UsbError::WouldBlock) => {
+                    attempts += 1;
+                    self.on_interrupt();
+                }
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl fmt::Write for UsbSerial<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes());
+        Ok(())
+    }
 }
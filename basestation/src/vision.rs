@@ -0,0 +1,70 @@
+use defmt::info;
+use intra_comms::definitions::{BasestationToRobot, Position, Team};
+use protobuf::luhsoccer::proto::ssl_vision::SslWrapperPacket;
+
+use crate::converter;
+
+/// Robots detected by vision with a lower confidence are ignored
+const MIN_CONFIDENCE: f32 = 0.5;
+
+/// Latest positions detected by vision which weren't sent to the robots yet
+#[derive(Default)]
+pub struct VisionPositions {
+    blue: [Option<Position>; 16],
+    yellow: [Option<Position>; 16],
+    /// Positions aren't forwarded to the robots
+    paused: bool,
+}
+
+impl VisionPositions {
+    pub fn is_forwarding(&self) -> bool {
+        !self.paused
+    }
+
+    /// Starts or stops forwarding the positions to the robots
+    pub fn set_forwarding(&mut self, forward: bool) {
+        info!("Forwarding ssl vision: {}", forward);
+        self.paused = !forward;
+        self.blue = [None; 16];
+        self.yellow = [None; 16];
+    }
+
+    pub fn update(&mut self, packet: SslWrapperPacket) {
+        if self.paused {
+            return;
+        }
+        let Some(detection) = packet.detection else {
+            return;
+        };
+        for (positions, robots) in [
+            (&mut self.blue, detection.robots_blue),
+            (&mut self.yellow, detection.robots_yellow),
+        ] {
+            for robot in robots {
+                if robot.confidence < MIN_CONFIDENCE {
+                    continue;
+                }
+                let Some(id) = robot.robot_id.filter(|id| (*id as usize) < positions.len()) else {
+                    continue;
+                };
+                if let Some(position) = converter::parse_vision_robot(&robot) {
+                    positions[id as usize] = Some(position);
+                }
+            }
+        }
+    }
+
+    /// Attaches the latest position of each robot to its packet. Every position is only sent
+    /// once, so the robots never fuse an outdated position twice.
+    pub fn attach_to(&mut self, send_buffer: &mut [Option<BasestationToRobot>]) {
+        for packet in send_buffer.iter_mut().flatten() {
+            let positions = match packet.team {
+                Team::Blue => &mut self.blue,
+                Team::Yellow => &mut self.yellow,
+            };
+            if let Some(position) = positions.get_mut(packet.id as usize).and_then(Option::take) {
+                packet.robot_position = Some(position);
+            }
+        }
+    }
+}
@@ -636,6 +636,32 @@ macro_rules! pin {
             }
         }
 
+        impl<MODE> $PinType<Input<MODE>> {
+            /// Enables the interrupt of the PIO controller on a rising edge of this pin.
+            pub fn enable_rising_edge_interrupt(&mut self) {
+                let pio = unsafe { &*$PIO::ptr() };
+                unsafe {
+                    pio.aimer.write_with_zero(|w| w.bits(1 << $i));
+                    pio.esr.write_with_zero(|w| w.bits(1 << $i));
+                    pio.rehlsr.write_with_zero(|w| w.bits(1 << $i));
+                }
+                // discard edges from before the interrupt was enabled
+                let _ = pio.isr.read();
+                self._enable_pin_interrupt();
+            }
+
+            pub fn disable_interrupt(&mut self) {
+                self.disable_pin_interrupt();
+            }
+
+            /// Returns true if this pin caused an interrupt since the last call.
+            ///
+            /// Reading the status clears the pending interrupts of all pins of the PIO controller.
+            pub fn take_interrupt(&mut self) -> bool {
+                unsafe { (*$PIO::ptr()).isr.read().bits() & (1 << $i) != 0 }
+            }
+        }
+
         impl<MODE> InputPin for $PinType<Input<MODE>> {
             type Error = Infallible;
 
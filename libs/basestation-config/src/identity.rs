@@ -0,0 +1,82 @@
+//! Identifiers of a basestation derived from the unique ID of its chip, so several basestations
+//! can share a network without configuring each of them.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+/// DHCP host name of all basestations. The identifier of each basestation is appended.
+const HOSTNAME_PREFIX: &[u8] = b"luhbots-bs-";
+pub const HOSTNAME_LEN: usize = HOSTNAME_PREFIX.len() + 4;
+pub const SERIAL_NUMBER_LEN: usize = 8;
+
+/// Hashes the 128 bit unique ID into 32 bits
+fn short_id(unique_id: [u32; 4]) -> u32 {
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    let mut digest = crc.digest();
+    for word in unique_id {
+        digest.update(&word.to_le_bytes());
+    }
+    digest.finalize()
+}
+
+/// Locally administered unicast address. The lower four bytes are taken from the unique ID.
+pub fn mac_address(unique_id: [u32; 4]) -> [u8; 6] {
+    let [a, b, c, d] = short_id(unique_id).to_be_bytes();
+    [0x02, 0x6C, a, b, c, d]
+}
+
+/// `luhbots-bs-` followed by the last two bytes of `mac_address` in hex
+pub fn hostname(mac_address: [u8; 6]) -> [u8; HOSTNAME_LEN] {
+    let mut hostname = [0; HOSTNAME_LEN];
+    let (prefix, suffix) = hostname.split_at_mut(HOSTNAME_PREFIX.len());
+    prefix.copy_from_slice(HOSTNAME_PREFIX);
+    write_hex(&mac_address[4..], suffix);
+    hostname
+}
+
+/// Hex digits of the unique ID hashed like for the MAC address
+pub fn serial_number(unique_id: [u32; 4]) -> [u8; SERIAL_NUMBER_LEN] {
+    let mut serial_number = [0; SERIAL_NUMBER_LEN];
+    write_hex(&short_id(unique_id).to_be_bytes(), &mut serial_number);
+    serial_number
+}
+
+fn write_hex(bytes: &[u8], out: &mut [u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for (byte, digits) in bytes.iter().zip(out.chunks_exact_mut(2)) {
+        digits[0] = DIGITS[usize::from(byte >> 4)];
+        digits[1] = DIGITS[usize::from(byte & 0xf)];
+    }
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use super::{hostname, mac_address, serial_number};
+
+    const UNIQUE_ID: [u32; 4] = [0x3433_3836, 0x3931_3730, 0x3031_3033, 0x0000_0050];
+
+    #[test]
+    fn mac_address_is_locally_administered_unicast() {
+        let mac = mac_address(UNIQUE_ID);
+        assert_eq!(mac[0] & 0b11, 0b10);
+        assert_eq!(mac, mac_address(UNIQUE_ID));
+    }
+
+    #[test]
+    fn different_chips_get_different_identifiers() {
+        let other = [UNIQUE_ID[0], UNIQUE_ID[1], UNIQUE_ID[2], 0x51];
+        assert_ne!(mac_address(UNIQUE_ID), mac_address(other));
+        assert_ne!(serial_number(UNIQUE_ID), serial_number(other));
+    }
+
+    #[test]
+    fn identifiers_match_the_mac_address() {
+        let mac = mac_address(UNIQUE_ID);
+        let serial_number = serial_number(UNIQUE_ID);
+        let expected: String = mac[2..].iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(core::str::from_utf8(&serial_number).unwrap(), expected);
+        assert_eq!(
+            core::str::from_utf8(&hostname(mac)).unwrap(),
+            format!("luhbots-bs-{}", &expected[4..])
+        );
+    }
+}
@@ -0,0 +1,403 @@
+//! Configuration of the basestation which is kept in its flash.
+//!
+//! A record starts with a magic word and the length of the encoded configuration, both as little
+//! endian `u32`. The configuration is versioned and protected by a checksum, so a record of older
+//! firmware is migrated and a corrupted record is never used.
+
+#![cfg_attr(any(not(test), target_arch = "arm"), no_std)]
+
+pub mod identity;
+
+use core::fmt;
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+/// Bytes of a record. The user signature of the ATSAM4E has 512 bytes.
+pub const RECORD_SIZE: usize = 512;
+/// Locally administered address used if the unique ID of the chip can't be read
+pub const DEFAULT_MAC_ADDRESS: [u8; 6] = [0x02, 0x6C, 0x75, 0x68, 0x62, 0x73];
+
+/// Marks a record with a [`DiscConfig`]
+const MAGIC: u32 = 0x6c62_7332;
+/// Marks a record of the first firmware with a configuration. It contains a [`ConfigV0`] without
+/// a checksum.
+const LEGACY_MAGIC: u32 = 0x6c62_7331;
+/// Magic word and length
+const HEADER_SIZE: usize = 8;
+
+/// The latest version of the configuration
+pub type Config = ConfigV2;
+
+/// How the basestation gets its IPv4 address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub enum AddressConfig {
+    /// Falls back to a link-local address if no DHCP server answers
+    Dhcp,
+    Static {
+        address: [u8; 4],
+        prefix: u8,
+        gateway: Option<[u8; 4]>,
+    },
+}
+
+/// Robot IDs 0 to 15, one bit each
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RobotIds(u16);
+
+/// A basestation controls all robots unless configured otherwise
+impl Default for RobotIds {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl RobotIds {
+    pub const ALL: Self = Self(u16::MAX);
+
+    pub const fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// Returns `false` for IDs above 15
+    pub const fn contains(self, id: u8) -> bool {
+        id < 16 && self.0 & (1 << id) != 0
+    }
+
+    /// Consecutive IDs as inclusive ranges
+    pub fn ranges(self) -> impl Iterator<Item = (u8, u8)> {
+        let mut id = 0;
+        core::iter::from_fn(move || {
+            while id < 16 && !self.contains(id) {
+                id += 1;
+            }
+            let start = id;
+            while id < 16 && self.contains(id) {
+                id += 1;
+            }
+            (start < id).then(|| (start, id - 1))
+        })
+    }
+}
+
+/// Lists the IDs like `0-3,8` or `none`, as the console parses them
+impl fmt::Display for RobotIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("none");
+        }
+        for (i, (start, end)) in self.ranges().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Format for RobotIds {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", defmt::Display2Format(self));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigV0 {
+    pub address: AddressConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub struct ConfigV1 {
+    pub address: AddressConfig,
+    /// `None` derives the address from the unique ID of the chip
+    pub mac_address: Option<[u8; 6]>,
+    /// MHz
+    pub rf_frequency: u16,
+    /// dBm
+    pub tx_power: i8,
+    /// UDP port the server sends its packets to
+    pub server_port: u16,
+}
+
+impl Default for ConfigV1 {
+    fn default() -> Self {
+        Self {
+            address: AddressConfig::Dhcp,
+            mac_address: None,
+            rf_frequency: 2400,
+            tx_power: -2,
+            server_port: 0xb45e,
+        }
+    }
+}
+
+impl From<ConfigV0> for ConfigV1 {
+    fn from(config: ConfigV0) -> Self {
+        Self {
+            address: config.address,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub struct ConfigV2 {
+    pub address: AddressConfig,
+    /// `None` derives the address from the unique ID of the chip
+    pub mac_address: Option<[u8; 6]>,
+    /// MHz
+    pub rf_frequency: u16,
+    /// dBm
+    pub tx_power: i8,
+    /// UDP port the server sends its packets to
+    pub server_port: u16,
+    /// Robots this basestation controls. Other basestations on the field own the rest.
+    pub owned_ids: RobotIds,
+}
+
+impl Default for ConfigV2 {
+    fn default() -> Self {
+        ConfigV1::default().into()
+    }
+}
+
+impl ConfigV2 {
+    pub fn mac_address(&self, unique_id: Option<[u32; 4]>) -> [u8; 6] {
+        self.mac_address
+            .unwrap_or_else(|| unique_id.map_or(DEFAULT_MAC_ADDRESS, identity::mac_address))
+    }
+}
+
+impl From<ConfigV1> for ConfigV2 {
+    fn from(config: ConfigV1) -> Self {
+        Self {
+            address: config.address,
+            mac_address: config.mac_address,
+            rf_frequency: config.rf_frequency,
+            tx_power: config.tx_power,
+            server_port: config.server_port,
+            owned_ids: RobotIds::ALL,
+        }
+    }
+}
+
+/// New versions are only appended, so older records can still be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum ConfigSelection {
+    V0(ConfigV0),
+    V1(ConfigV1),
+    V2(ConfigV2),
+}
+
+impl ConfigSelection {
+    fn into_latest(self) -> Config {
+        match self {
+            Self::V0(config) => ConfigV1::from(config).into(),
+            Self::V1(config) => config.into(),
+            Self::V2(config) => config,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiscConfig {
+    config: ConfigSelection,
+    checksum: u32,
+}
+
+impl DiscConfig {
+    fn new(config: ConfigSelection) -> Result<Self, Error> {
+        let checksum = Self::calc_checksum(&config)?;
+        Ok(Self { config, checksum })
+    }
+
+    fn calc_checksum(config: &ConfigSelection) -> Result<u32, Error> {
+        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let config_bytes = postcard::to_vec::<_, RECORD_SIZE>(config).map_err(|_| Error::Encode)?;
+        Ok(crc.checksum(&config_bytes[..]))
+    }
+
+    fn valid(&self) -> bool {
+        Self::calc_checksum(&self.config) == Ok(self.checksum)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Error {
+    /// The record wasn't written yet or was erased
+    Empty,
+    InvalidLength,
+    Decode,
+    /// The record is corrupted
+    Checksum,
+    Encode,
+}
+
+/// Encodes `config` into a record. Returns the number of bytes used, the rest is filled with
+/// `0xff` like erased flash.
+pub fn encode(config: &Config, record: &mut [u8; RECORD_SIZE]) -> Result<usize, Error> {
+    record.fill(0xff);
+    let disc_config = DiscConfig::new(ConfigSelection::V2(*config))?;
+    let (header, data) = record.split_at_mut(HEADER_SIZE);
+    let length = postcard::to_slice(&disc_config, data)
+        .map_err(|_| Error::Encode)?
+        .len();
+    header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..].copy_from_slice(&(length as u32).to_le_bytes());
+    Ok(HEADER_SIZE + length)
+}
+
+/// Decodes a record and migrates it to the latest version
+pub fn decode(record: &[u8]) -> Result<Config, Error> {
+    if record.len() < HEADER_SIZE {
+        return Err(Error::InvalidLength);
+    }
+    let (header, data) = record.split_at(HEADER_SIZE);
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    if magic != MAGIC && magic != LEGACY_MAGIC {
+        return Err(Error::Empty);
+    }
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let data = data.get(..length).ok_or(Error::InvalidLength)?;
+    if magic == LEGACY_MAGIC {
+        let config: ConfigV0 = postcard::from_bytes(data).map_err(|_| Error::Decode)?;
+        return Ok(ConfigSelection::V0(config).into_latest());
+    }
+    let disc_config: DiscConfig = postcard::from_bytes(data).map_err(|_| Error::Decode)?;
+    if !disc_config.valid() {
+        return Err(Error::Checksum);
+    }
+    Ok(disc_config.config.into_latest())
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use super::{
+        decode, encode, AddressConfig, Config, ConfigSelection, ConfigV0, ConfigV1, DiscConfig,
+        Error, RobotIds, HEADER_SIZE, LEGACY_MAGIC, MAGIC, RECORD_SIZE,
+    };
+
+    fn static_config() -> Config {
+        Config {
+            address: AddressConfig::Static {
+                address: [10, 0, 0, 2],
+                prefix: 24,
+                gateway: Some([10, 0, 0, 1]),
+            },
+            mac_address: Some([0x02, 1, 2, 3, 4, 5]),
+            rf_frequency: 2450,
+            tx_power: 10,
+            server_port: 4000,
+            owned_ids: RobotIds::from_bits(0x00ff),
+        }
+    }
+
+    fn record_with(magic: u32, data: &[u8]) -> [u8; RECORD_SIZE] {
+        let mut record = [0xff; RECORD_SIZE];
+        record[..4].copy_from_slice(&magic.to_le_bytes());
+        record[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        record[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn roundtrip() {
+        for config in [Config::default(), static_config()] {
+            let mut record = [0; RECORD_SIZE];
+            let length = encode(&config, &mut record).unwrap();
+            assert!(record[length..].iter().all(|byte| *byte == 0xff));
+            assert_eq!(decode(&record), Ok(config));
+            assert_eq!(decode(&record[..length]), Ok(config));
+        }
+    }
+
+    #[test]
+    fn erased_flash() {
+        assert_eq!(decode(&[0xff; RECORD_SIZE]), Err(Error::Empty));
+        assert_eq!(decode(&[0; RECORD_SIZE]), Err(Error::Empty));
+        assert_eq!(decode(&[0; 4]), Err(Error::InvalidLength));
+    }
+
+    #[test]
+    fn corrupted_record() {
+        let mut record = [0; RECORD_SIZE];
+        let length = encode(&static_config(), &mut record).unwrap();
+        for index in HEADER_SIZE..length {
+            for bit in 0..8 {
+                let mut corrupted = record;
+                corrupted[index] ^= 1 << bit;
+                assert!(decode(&corrupted).is_err(), "byte {index} bit {bit}");
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_length() {
+        let mut record = [0; RECORD_SIZE];
+        let length = encode(&static_config(), &mut record).unwrap();
+        assert_eq!(decode(&record[..length - 1]), Err(Error::InvalidLength));
+        record[4..8].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+        assert_eq!(decode(&record), Err(Error::InvalidLength));
+    }
+
+    #[test]
+    fn migrate_legacy_record() {
+        let address = AddressConfig::Static {
+            address: [192, 168, 1, 5],
+            prefix: 16,
+            gateway: None,
+        };
+        let data = postcard::to_vec::<_, 32>(&ConfigV0 { address }).unwrap();
+        let config = decode(&record_with(LEGACY_MAGIC, &data)).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                address,
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn migrate_v0() {
+        let disc_config = DiscConfig::new(ConfigSelection::V0(ConfigV0 {
+            address: AddressConfig::Dhcp,
+        }))
+        .unwrap();
+        let data = postcard::to_vec::<_, 64>(&disc_config).unwrap();
+        assert_eq!(decode(&record_with(MAGIC, &data)), Ok(Config::default()));
+    }
+
+    #[test]
+    fn migrate_v1() {
+        let v1 = ConfigV1 {
+            rf_frequency: 2450,
+            ..ConfigV1::default()
+        };
+        let disc_config = DiscConfig::new(ConfigSelection::V1(v1)).unwrap();
+        let data = postcard::to_vec::<_, 64>(&disc_config).unwrap();
+        let config = decode(&record_with(MAGIC, &data)).unwrap();
+        assert_eq!(config.rf_frequency, 2450);
+        assert_eq!(config.owned_ids, RobotIds::ALL);
+    }
+
+    #[test]
+    fn robot_ids() {
+        let ids = RobotIds::from_bits(0b1000_0001_0000_1111);
+        assert!(ids.contains(0) && ids.contains(3) && ids.contains(8) && ids.contains(15));
+        assert!(!ids.contains(4) && !ids.contains(16));
+        assert_eq!(ids.to_string(), "0-3,8,15");
+        assert_eq!(RobotIds::ALL.to_string(), "0-15");
+        assert_eq!(RobotIds::from_bits(0).to_string(), "none");
+    }
+}
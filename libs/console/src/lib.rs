@@ -0,0 +1,412 @@
+//! Line based command console of the basestation.
+//!
+//! [`LineBuffer`] collects the bytes received over the serial port into lines, which [`parse`]
+//! turns into [`Command`]s. Executing them is up to the firmware.
+
+#![cfg_attr(any(not(test), target_arch = "arm"), no_std)]
+
+use defmt::Format;
+use intra_comms::{definitions::Team, MAX_RF_FREQUENCY, MIN_RF_FREQUENCY};
+
+/// Shown by the `help` command
+pub const HELP: &str = "\
+status                  show the status and rf statistics\n\r\
+robots                  list the robots which answered\n\r\
+frequency <MHz>         set the rf frequency (2400-2500)\n\r\
+power <dBm>             set the rf tx power (-18-13)\n\r\
+ip <a.b.c.d>/<prefix> [<gateway>]\n\r\
+                        use a static ip address\n\r\
+ip dhcp                 get the ip address using dhcp\n\r\
+test <id> <blue|yellow> send a stop packet to a robot\n\r\
+ids <all|none|list>     robot ids this basestation controls, e.g. 0-5,8\n\r\
+robot-frequency <id> <blue|yellow> <MHz>\n\r\
+                        move a robot to another rf frequency\n\r\
+vision <on|off>         forward ssl vision to the robots\n\r\
+referee <on|off>        forward the game controller to the robots\n\r";
+
+const MIN_POWER: i8 = -18;
+const MAX_POWER: i8 = 13;
+
+#[derive(Debug, PartialEq, Clone, Copy, Format)]
+pub enum Command {
+    Help,
+    Status,
+    Robots,
+    /// MHz
+    Frequency(u16),
+    /// dBm
+    TxPower(i8),
+    StaticIp {
+        address: [u8; 4],
+        prefix: u8,
+        gateway: Option<[u8; 4]>,
+    },
+    Dhcp,
+    Test {
+        id: u8,
+        team: Team,
+    },
+    /// Bit `n` is set if the basestation controls the robot with id `n`
+    Ids(u16),
+    RobotFrequency {
+        id: u8,
+        team: Team,
+        /// MHz
+        frequency: u16,
+    },
+    Vision(bool),
+    Referee(bool),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Format)]
+pub enum ParseError {
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument,
+    TooManyArguments,
+}
+
+impl ParseError {
+    pub const fn message(&self) -> &'static str {
+        match self {
+            Self::UnknownCommand => "unknown command. Try help",
+            Self::MissingArgument => "missing argument",
+            Self::InvalidArgument => "invalid argument",
+            Self::TooManyArguments => "too many arguments",
+        }
+    }
+}
+
+/// Parses a line without the line ending. Returns `Ok(None)` for an empty line.
+pub fn parse(line: &str) -> Result<Option<Command>, ParseError> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+    let mut argument = || words.next().ok_or(ParseError::MissingArgument);
+    let command = match name {
+        "help" => Command::Help,
+        "status" => Command::Status,
+        "robots" => Command::Robots,
+        "frequency" => Command::Frequency(parse_in_range(
+            argument()?,
+            MIN_RF_FREQUENCY,
+            MAX_RF_FREQUENCY,
+        )?),
+        "power" => Command::TxPower(parse_in_range(argument()?, MIN_POWER, MAX_POWER)?),
+        "ip" => match argument()? {
+            "dhcp" => Command::Dhcp,
+            cidr => {
+                let (address, prefix) = parse_cidr(cidr)?;
+                let gateway = words.next().map(parse_address).transpose()?;
+                Command::StaticIp {
+                    address,
+                    prefix,
+                    gateway,
+                }
+            }
+        },
+        "test" => {
+            let id = parse_in_range(argument()?, 0, 15)?;
+            let team = parse_team(argument()?)?;
+            Command::Test { id, team }
+        }
+        "ids" => Command::Ids(parse_ids(argument()?)?),
+        "robot-frequency" => {
+            let id = parse_in_range(argument()?, 0, 15)?;
+            let team = parse_team(argument()?)?;
+            let frequency = parse_in_range(argument()?, MIN_RF_FREQUENCY, MAX_RF_FREQUENCY)?;
+            Command::RobotFrequency {
+                id,
+                team,
+                frequency,
+            }
+        }
+        "vision" => Command::Vision(parse_switch(argument()?)?),
+        "referee" => Command::Referee(parse_switch(argument()?)?),
+        _ => return Err(ParseError::UnknownCommand),
+    };
+    if words.next().is_some() {
+        return Err(ParseError::TooManyArguments);
+    }
+    Ok(Some(command))
+}
+
+fn parse_in_range<T: core::str::FromStr + PartialOrd>(
+    word: &str,
+    min: T,
+    max: T,
+) -> Result<T, ParseError> {
+    word.parse()
+        .ok()
+        .filter(|value| (min..=max).contains(value))
+        .ok_or(ParseError::InvalidArgument)
+}
+
+fn parse_team(word: &str) -> Result<Team, ParseError> {
+    match word {
+        "blue" => Ok(Team::Blue),
+        "yellow" => Ok(Team::Yellow),
+        _ => Err(ParseError::InvalidArgument),
+    }
+}
+
+/// Parses `all`, `none` or a comma separated list of ids and ranges like `0-5,8`
+fn parse_ids(word: &str) -> Result<u16, ParseError> {
+    match word {
+        "all" => return Ok(u16::MAX),
+        "none" => return Ok(0),
+        _ => (),
+    }
+    let mut ids = 0;
+    for part in word.split(',') {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (parse_in_range(start, 0, 15)?, parse_in_range(end, 0, 15)?),
+            None => {
+                let id = parse_in_range(part, 0, 15)?;
+                (id, id)
+            }
+        };
+        if start > end {
+            return Err(ParseError::InvalidArgument);
+        }
+        for id in start..=end {
+            ids |= 1 << id;
+        }
+    }
+    Ok(ids)
+}
+
+fn parse_switch(word: &str) -> Result<bool, ParseError> {
+    match word {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(ParseError::InvalidArgument),
+    }
+}
+
+fn parse_cidr(word: &str) -> Result<([u8; 4], u8), ParseError> {
+    let (address, prefix) = word.split_once('/').ok_or(ParseError::InvalidArgument)?;
+    Ok((parse_address(address)?, parse_in_range(prefix, 0, 32)?))
+}
+
+fn parse_address(word: &str) -> Result<[u8; 4], ParseError> {
+    let mut octets = word.split('.');
+    let mut parsed = [0; 4];
+    for octet in &mut parsed {
+        *octet = parse_in_range(octets.next().ok_or(ParseError::InvalidArgument)?, 0, 255)?;
+    }
+    if octets.next().is_some() {
+        return Err(ParseError::InvalidArgument);
+    }
+    Ok(parsed)
+}
+
+/// Collects received bytes into lines
+pub struct LineBuffer<const N: usize> {
+    buffer: [u8; N],
+    length: usize,
+    /// The current line is longer than the buffer and is dropped
+    overflow: bool,
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            length: 0,
+            overflow: false,
+        }
+    }
+
+    /// Adds a received byte. Returns the line when `byte` ends it. Lines which are too long or not
+    /// valid UTF-8 are returned as `Err`.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&str, ()>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let length = core::mem::take(&mut self.length);
+                if core::mem::take(&mut self.overflow) {
+                    return Some(Err(()));
+                }
+                Some(core::str::from_utf8(&self.buffer[..length]).map_err(|_| ()))
+            }
+            // backspace and delete
+            0x08 | 0x7f => {
+                self.length = self.length.saturating_sub(1);
+                None
+            }
+            _ if self.length < N => {
+                self.buffer[self.length] = byte;
+                self.length += 1;
+                None
+            }
+            _ => {
+                self.overflow = true;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use intra_comms::definitions::Team;
+
+    use super::{parse, Command, LineBuffer, ParseError};
+
+    fn push_all<const N: usize>(
+        buffer: &mut LineBuffer<N>,
+        data: &[u8],
+    ) -> Vec<Result<String, ()>> {
+        data.iter()
+            .filter_map(|byte| buffer.push(*byte).map(|line| line.map(String::from)))
+            .collect()
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(parse("help"), Ok(Some(Command::Help)));
+        assert_eq!(parse("  status "), Ok(Some(Command::Status)));
+        assert_eq!(parse("robots"), Ok(Some(Command::Robots)));
+        assert_eq!(parse(""), Ok(None));
+        assert_eq!(parse("   "), Ok(None));
+        assert_eq!(parse("reboot"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("status now"), Err(ParseError::TooManyArguments));
+    }
+
+    #[test]
+    fn rf_settings() {
+        assert_eq!(parse("frequency 2450"), Ok(Some(Command::Frequency(2450))));
+        assert_eq!(parse("frequency 2600"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("frequency"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("power -2"), Ok(Some(Command::TxPower(-2))));
+        assert_eq!(parse("power 14"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("power high"), Err(ParseError::InvalidArgument));
+    }
+
+    #[test]
+    fn ip_address() {
+        assert_eq!(
+            parse("ip 192.168.0.10/24"),
+            Ok(Some(Command::StaticIp {
+                address: [192, 168, 0, 10],
+                prefix: 24,
+                gateway: None
+            }))
+        );
+        assert_eq!(
+            parse("ip 10.0.0.2/8 10.0.0.1"),
+            Ok(Some(Command::StaticIp {
+                address: [10, 0, 0, 2],
+                prefix: 8,
+                gateway: Some([10, 0, 0, 1])
+            }))
+        );
+        assert_eq!(parse("ip dhcp"), Ok(Some(Command::Dhcp)));
+        for invalid in [
+            "ip 192.168.0.10",
+            "ip 192.168.0/24",
+            "ip 192.168.0.10.1/24",
+            "ip 192.168.0.256/24",
+            "ip 192.168.0.10/33",
+            "ip 10.0.0.2/8 10.0.0",
+        ] {
+            assert_eq!(
+                parse(invalid),
+                Err(ParseError::InvalidArgument),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_packet() {
+        assert_eq!(
+            parse("test 3 yellow"),
+            Ok(Some(Command::Test {
+                id: 3,
+                team: Team::Yellow
+            }))
+        );
+        assert_eq!(parse("test 16 blue"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("test 3"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("test 3 red"), Err(ParseError::InvalidArgument));
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(parse("ids all"), Ok(Some(Command::Ids(0xffff))));
+        assert_eq!(parse("ids 0-7"), Ok(Some(Command::Ids(0x00ff))));
+        assert_eq!(parse("ids 3"), Ok(Some(Command::Ids(0x0008))));
+        assert_eq!(parse("ids 0,2-3,15"), Ok(Some(Command::Ids(0x800d))));
+        assert_eq!(parse("ids none"), Ok(Some(Command::Ids(0))));
+        for invalid in ["ids 7-0", "ids 0-16", "ids 1,", "ids -3"] {
+            assert_eq!(
+                parse(invalid),
+                Err(ParseError::InvalidArgument),
+                "{invalid}"
+            );
+        }
+        assert_eq!(parse("ids"), Err(ParseError::MissingArgument));
+    }
+
+    #[test]
+    fn robot_frequency() {
+        assert_eq!(
+            parse("robot-frequency 4 blue 2450"),
+            Ok(Some(Command::RobotFrequency {
+                id: 4,
+                team: Team::Blue,
+                frequency: 2450
+            }))
+        );
+        assert_eq!(
+            parse("robot-frequency 4 blue 2399"),
+            Err(ParseError::InvalidArgument)
+        );
+        assert_eq!(
+            parse("robot-frequency 4 2450"),
+            Err(ParseError::InvalidArgument)
+        );
+        assert_eq!(
+            parse("robot-frequency 4 blue"),
+            Err(ParseError::MissingArgument)
+        );
+    }
+
+    #[test]
+    fn forwarding() {
+        assert_eq!(parse("vision off"), Ok(Some(Command::Vision(false))));
+        assert_eq!(parse("referee on"), Ok(Some(Command::Referee(true))));
+        assert_eq!(parse("referee maybe"), Err(ParseError::InvalidArgument));
+    }
+
+    #[test]
+    fn lines() {
+        let mut buffer = LineBuffer::<16>::new();
+        assert_eq!(
+            push_all(&mut buffer, b"status\r\nrobots\r"),
+            [Ok("status".into()), Ok(String::new()), Ok("robots".into())]
+        );
+        // backspace
+        assert_eq!(push_all(&mut buffer, b"helpp\x7f\r"), [Ok("help".into())]);
+        assert_eq!(
+            push_all(&mut buffer, b"\x08\x08status\r"),
+            [Ok("status".into())]
+        );
+    }
+
+    #[test]
+    fn line_too_long() {
+        let mut buffer = LineBuffer::<4>::new();
+        assert_eq!(push_all(&mut buffer, b"status\r"), [Err(())]);
+        assert_eq!(push_all(&mut buffer, b"help\r"), [Ok("help".into())]);
+        assert_eq!(push_all(&mut buffer, b"\xff\r"), [Err(())]);
+    }
+}
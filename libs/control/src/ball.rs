@@ -0,0 +1,333 @@
+//! Ball detection using the light barrier and the load on the dribbler motor.
+//!
+//! A ball in the dribbler brakes the dribbler motor. The [`RpmBallDetector`] learns the RPM
+//! reported by the ESC per throttle step of the free running dribbler while the light barrier sees
+//! no ball and detects a ball by its drop. Using the ratio detects the ball with a fixed throttle, where the RPM drops, as well as
+//! with the RPM control, which increases the throttle to keep the RPM.
+//! [`BallFusion`] combines it with the light barrier and suspects the light barrier to be broken
+//! if both disagree for too long. In that case only the RPM is used.
+
+/// The RPM is too unreliable below this speed
+const MIN_RPM: f32 = 500.0;
+/// The dribbler needs this long to reach a new speed
+const SETTLE_TIME: f32 = 0.5;
+/// Time constant used to follow a rising ratio
+const RISE_TIME: f32 = 0.05;
+/// Time constant used to follow a slowly falling ratio without a ball
+const DECAY_TIME: f32 = 2.0;
+/// Relative drop of the ratio which is still considered noise
+const MIN_DROP: f32 = 0.05;
+/// Relative drop of the ratio which certainly is a ball
+const FULL_DROP: f32 = 0.2;
+
+/// Weight of the light barrier if it is trusted. The RPM gets the remaining weight
+const LIGHT_BARRIER_WEIGHT: f32 = 0.6;
+/// Both sources disagree if their probabilities differ by more than this
+const DISAGREEMENT: f32 = 0.75;
+/// Both sources agree if their probabilities differ by less than this
+const AGREEMENT: f32 = 0.25;
+/// The light barrier is suspected broken after disagreeing with the RPM for this long
+const DISAGREEMENT_TIME: f32 = 2.0;
+/// The light barrier is trusted again after changing its mind and agreeing for this long
+const AGREEMENT_TIME: f32 = 0.5;
+
+/// Factor of a first order low pass with time constant `tau` running with period `dt`
+fn low_pass_factor(dt: f32, tau: f32) -> f32 {
+    dt / (tau + dt)
+}
+
+/// Detects a ball by the RPM drop of the dribbler.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RpmBallDetector {
+    /// rpm per throttle step of the free running dribbler
+    baseline: Option<f32>,
+    /// s since the last reset
+    running: f32,
+}
+
+impl RpmBallDetector {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            baseline: None,
+            running: 0.0,
+        }
+    }
+
+    /// Forgets the free running speed. Needs to be called when the dribbler speed is changed.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Updates the detector with the `rpm` measured `dt` s after the last update while sending
+    /// `throttle` to the ESC. `light_barrier` tells if the light barrier might see a ball.
+    ///
+    /// Returns the probability of a ball in the dribbler or `None` if it can't be detected, e.g.
+    /// because the dribbler is turned off, still changing its speed or hasn't run without a ball
+    /// yet.
+    pub fn update(
+        &mut self,
+        rpm: Option<u16>,
+        throttle: u16,
+        light_barrier: bool,
+        dt: f32,
+    ) -> Option<f32> {
+        let Some(rpm) = rpm.map(f32::from).filter(|rpm| *rpm >= MIN_RPM && throttle > 0) else {
+            self.reset();
+            return None;
+        };
+        self.running += dt;
+        let ratio = rpm / f32::from(throttle);
+        let baseline = match self.baseline {
+            Some(baseline) => baseline,
+            // a ball held since the start would be learned as the free running speed
+            None if light_barrier => return None,
+            None => *self.baseline.insert(ratio),
+        };
+
+        let drop = 1.0 - ratio / baseline;
+        if drop < 0.0 {
+            self.baseline = Some(baseline + (ratio - baseline) * low_pass_factor(dt, RISE_TIME));
+        } else if drop < MIN_DROP {
+            self.baseline = Some(baseline + (ratio - baseline) * low_pass_factor(dt, DECAY_TIME));
+        }
+        // the baseline is kept while there is a ball
+
+        if self.running < SETTLE_TIME {
+            return None;
+        }
+        Some(((drop - MIN_DROP) / (FULL_DROP - MIN_DROP)).clamp(0.0, 1.0))
+    }
+}
+
+/// Result of the ball detection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BallEstimate {
+    /// Probability of a ball in the dribbler in 0..=1
+    pub confidence: f32,
+    pub light_barrier_suspected_broken: bool,
+}
+
+impl BallEstimate {
+    #[must_use]
+    pub fn has_ball(&self) -> bool {
+        self.confidence >= 0.5
+    }
+}
+
+/// Fuses the light barrier with the [`RpmBallDetector`].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BallFusion {
+    /// s the sources disagree
+    disagreement: f32,
+    /// s the light barrier agrees with the RPM after being suspected broken
+    agreement: f32,
+    /// The light barrier reading (has ball) which is suspected to be wrong
+    suspected_reading: Option<bool>,
+}
+
+impl BallFusion {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            disagreement: 0.0,
+            agreement: 0.0,
+            suspected_reading: None,
+        }
+    }
+
+    /// Combines the probability of a ball given by the light barrier with the one of the
+    /// [`RpmBallDetector`]. `dt` is the time in s since the last update.
+    pub fn update(&mut self, light_barrier: f32, rpm: Option<f32>, dt: f32) -> BallEstimate {
+        let Some(rpm) = rpm else {
+            // nothing to compare the light barrier with, so even a suspicious one is used
+            self.disagreement = 0.0;
+            self.agreement = 0.0;
+            return BallEstimate {
+                confidence: light_barrier,
+                light_barrier_suspected_broken: self.suspected_reading.is_some(),
+            };
+        };
+        let difference = (light_barrier - rpm).abs();
+        let reading = light_barrier >= 0.5;
+
+        match self.suspected_reading {
+            None if difference > DISAGREEMENT => {
+                self.disagreement += dt;
+                if self.disagreement >= DISAGREEMENT_TIME {
+                    self.suspected_reading = Some(reading);
+                    self.agreement = 0.0;
+                }
+            }
+            None => self.disagreement = 0.0,
+            // the light barrier has to show the other reading to prove it works
+            Some(suspected) if suspected != reading && difference < AGREEMENT => {
+                self.agreement += dt;
+                if self.agreement >= AGREEMENT_TIME {
+                    self.suspected_reading = None;
+                    self.disagreement = 0.0;
+                }
+            }
+            Some(_) => self.agreement = 0.0,
+        }
+
+        if self.suspected_reading.is_some() {
+            BallEstimate {
+                confidence: rpm,
+                light_barrier_suspected_broken: true,
+            }
+        } else {
+            BallEstimate {
+                confidence: LIGHT_BARRIER_WEIGHT * light_barrier
+                    + (1.0 - LIGHT_BARRIER_WEIGHT) * rpm,
+                light_barrier_suspected_broken: false,
+            }
+        }
+    }
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use super::{BallFusion, RpmBallDetector};
+
+    const DT: f32 = 0.01;
+
+    /// Runs the detector for `duration` s with a constant measurement and a light barrier seeing no
+    /// ball
+    fn run(detector: &mut RpmBallDetector, rpm: u16, throttle: u16, duration: f32) -> Option<f32> {
+        run_with_light_barrier(detector, rpm, throttle, false, duration)
+    }
+
+    fn run_with_light_barrier(
+        detector: &mut RpmBallDetector,
+        rpm: u16,
+        throttle: u16,
+        light_barrier: bool,
+        duration: f32,
+    ) -> Option<f32> {
+        let mut result = None;
+        for _ in 0..(duration / DT) as usize {
+            result = detector.update(Some(rpm), throttle, light_barrier, DT);
+        }
+        result
+    }
+
+    #[test]
+    fn needs_running_dribbler() {
+        let mut detector = RpmBallDetector::new();
+        assert_eq!(run(&mut detector, 0, 0, 1.0), None);
+        assert_eq!(run(&mut detector, 100, 50, 1.0), None);
+        assert_eq!(detector.update(None, 200, false, DT), None);
+    }
+
+    #[test]
+    fn waits_for_the_dribbler_to_settle() {
+        let mut detector = RpmBallDetector::new();
+        assert_eq!(run(&mut detector, 5000, 200, 0.4), None);
+        assert_eq!(run(&mut detector, 5000, 200, 0.2), Some(0.0));
+        detector.reset();
+        assert_eq!(detector.update(Some(5000), 200, false, DT), None);
+    }
+
+    #[test]
+    fn rpm_drop_with_fixed_throttle() {
+        let mut detector = RpmBallDetector::new();
+        assert_eq!(run(&mut detector, 5000, 200, 1.0), Some(0.0));
+        assert_eq!(run(&mut detector, 4900, 200, 0.2), Some(0.0));
+        assert_eq!(run(&mut detector, 3500, 200, 0.2), Some(1.0));
+        // the free running speed is kept while holding the ball
+        assert_eq!(run(&mut detector, 3500, 200, 10.0), Some(1.0));
+        assert_eq!(run(&mut detector, 5000, 200, 0.2), Some(0.0));
+    }
+
+    #[test]
+    fn throttle_rise_with_fixed_rpm() {
+        let mut detector = RpmBallDetector::new();
+        assert_eq!(run(&mut detector, 5000, 200, 1.0), Some(0.0));
+        assert_eq!(run(&mut detector, 5000, 300, 0.2), Some(1.0));
+        assert_eq!(run(&mut detector, 5000, 200, 0.2), Some(0.0));
+    }
+
+    #[test]
+    fn ball_from_the_start() {
+        let mut detector = RpmBallDetector::new();
+        // the braked dribbler isn't learned as the free running one
+        assert_eq!(
+            run_with_light_barrier(&mut detector, 3500, 200, true, 5.0),
+            None
+        );
+        // so the ball is detected after it was released once
+        assert_eq!(run(&mut detector, 5000, 200, 0.2), Some(0.0));
+        assert_eq!(
+            run_with_light_barrier(&mut detector, 3500, 200, true, 0.2),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn follows_free_running_speed() {
+        let mut detector = RpmBallDetector::new();
+        run(&mut detector, 4000, 200, 1.0);
+        // the dribbler got faster after the start
+        assert_eq!(run(&mut detector, 5000, 200, 1.0), Some(0.0));
+        // and slowly looses speed with the battery voltage
+        for rpm in (4500..5000).rev().step_by(10) {
+            assert_eq!(run(&mut detector, rpm, 200, 1.0), Some(0.0));
+        }
+    }
+
+    #[test]
+    fn fusion_trusts_light_barrier() {
+        let mut fusion = BallFusion::new();
+        let estimate = fusion.update(1.0, None, DT);
+        assert!(estimate.has_ball());
+        assert!(!estimate.light_barrier_suspected_broken);
+        let estimate = fusion.update(1.0, Some(0.0), DT);
+        assert!(estimate.has_ball());
+        let estimate = fusion.update(0.0, Some(1.0), DT);
+        assert!(!estimate.has_ball());
+        let estimate = fusion.update(1.0, Some(1.0), DT);
+        assert_eq!(estimate.confidence, 1.0);
+    }
+
+    #[test]
+    fn blocked_light_barrier() {
+        let mut fusion = BallFusion::new();
+        for _ in 0..100 {
+            assert!(fusion.update(1.0, Some(0.0), DT).has_ball());
+        }
+        let estimate = (0..200)
+            .map(|_| fusion.update(1.0, Some(0.0), DT))
+            .last()
+            .unwrap();
+        assert!(estimate.light_barrier_suspected_broken);
+        assert!(!estimate.has_ball());
+        // agreeing on a ball doesn't prove anything
+        let estimate = (0..100)
+            .map(|_| fusion.update(1.0, Some(1.0), DT))
+            .last()
+            .unwrap();
+        assert!(estimate.light_barrier_suspected_broken);
+        // the light barrier works again
+        let estimate = (0..100)
+            .map(|_| fusion.update(0.0, Some(0.0), DT))
+            .last()
+            .unwrap();
+        assert!(!estimate.light_barrier_suspected_broken);
+    }
+
+    #[test]
+    fn blind_light_barrier() {
+        let mut fusion = BallFusion::new();
+        let estimate = (0..300)
+            .map(|_| fusion.update(0.0, Some(1.0), DT))
+            .last()
+            .unwrap();
+        assert!(estimate.light_barrier_suspected_broken);
+        assert!(estimate.has_ball());
+        // without the RPM the light barrier is used anyway
+        let estimate = fusion.update(0.0, None, DT);
+        assert!(estimate.light_barrier_suspected_broken);
+        assert!(!estimate.has_ball());
+    }
+}
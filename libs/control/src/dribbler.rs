@@ -0,0 +1,129 @@
+//! Closed loop dribbler speed control using the eRPM telemetry of the ESC.
+
+use pidcontroller::{Controller as _, PIDController};
+
+/// Highest throttle accepted by the ESC
+pub const MAX_THROTTLE: u16 = 1999;
+
+/// The ESC reports the largest possible eRPM period when the motor doesn't turn
+const STOPPED_ERPM_PERIOD: u16 = 0x1FF << 7;
+
+/// Converts the eRPM period in us reported by the ESC into the mechanical RPM of the motor.
+#[must_use]
+pub fn erpm_period_to_rpm(erpm_period: u16, pole_pairs: u8) -> u16 {
+    if erpm_period == 0 || erpm_period >= STOPPED_ERPM_PERIOD || pole_pairs == 0 {
+        return 0;
+    }
+    let rpm = 60_000_000 / (u32::from(erpm_period) * u32::from(pole_pairs));
+    u16::try_from(rpm).unwrap_or(u16::MAX)
+}
+
+/// PI controller regulating the throttle of the ESC to reach a target RPM.
+///
+/// The controller is meant to be run at a fixed rate, so the gains already include the period.
+pub struct RpmController {
+    pid: PIDController<f32>,
+    target: u16,
+}
+
+impl RpmController {
+    #[must_use]
+    pub fn new(p_gain: f32, i_gain: f32) -> Self {
+        let mut controller = Self {
+            pid: PIDController::new().with_limit(f32::from(MAX_THROTTLE)),
+            target: 0,
+        };
+        controller.set_gains(p_gain, i_gain);
+        controller
+    }
+
+    pub fn set_gains(&mut self, p_gain: f32, i_gain: f32) {
+        self.pid.p_gain = p_gain;
+        self.pid.i_gain = i_gain;
+        // the integral alone must not be able to exceed the throttle range
+        self.pid.i_sum_limit = (i_gain > 0.0).then(|| f32::from(MAX_THROTTLE) / i_gain);
+    }
+
+    /// Sets the target RPM. A target of 0 stops the motor without braking.
+    pub fn set_target(&mut self, rpm: u16) {
+        if rpm == 0 {
+            self.pid.clear_integral();
+        }
+        self.target = rpm;
+        self.pid.set_target(&f32::from(rpm));
+    }
+
+    #[must_use]
+    pub const fn target(&self) -> u16 {
+        self.target
+    }
+
+    /// Returns the throttle for the measured RPM.
+    pub fn regulate(&mut self, rpm: u16) -> u16 {
+        if self.target == 0 {
+            return 0;
+        }
+        let throttle = self.pid.regulate(&f32::from(rpm));
+        // the ESC can't brake, so negative outputs just let the motor slow down
+        throttle.clamp(0.0, f32::from(MAX_THROTTLE)) as u16
+    }
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use super::{erpm_period_to_rpm, RpmController, MAX_THROTTLE};
+
+    /// Motor which turns 10 RPM per throttle step
+    fn motor(throttle: u16) -> u16 {
+        throttle * 10
+    }
+
+    #[test]
+    fn rpm_from_erpm_period() {
+        // 1000us per electrical revolution are 60_000 eRPM
+        assert_eq!(erpm_period_to_rpm(1000, 1), 60_000);
+        assert_eq!(erpm_period_to_rpm(1000, 7), 8571);
+        assert_eq!(erpm_period_to_rpm(100, 1), u16::MAX);
+    }
+
+    #[test]
+    fn stopped_motor() {
+        assert_eq!(erpm_period_to_rpm(0x1FF << 7, 7), 0);
+        assert_eq!(erpm_period_to_rpm(0, 7), 0);
+        assert_eq!(erpm_period_to_rpm(1000, 0), 0);
+    }
+
+    #[test]
+    fn reaches_target() {
+        let mut controller = RpmController::new(0.01, 0.01);
+        controller.set_target(10_000);
+        let mut rpm = 0;
+        for _ in 0..1000 {
+            rpm = motor(controller.regulate(rpm));
+        }
+        assert!((9_900..=10_100).contains(&rpm), "rpm is {rpm}");
+    }
+
+    #[test]
+    fn throttle_is_limited() {
+        let mut controller = RpmController::new(1.0, 0.1);
+        controller.set_target(u16::MAX);
+        assert_eq!(controller.regulate(0), MAX_THROTTLE);
+        controller.set_target(100);
+        assert_eq!(controller.regulate(u16::MAX), 0);
+    }
+
+    #[test]
+    fn zero_target_stops() {
+        let mut controller = RpmController::new(0.01, 0.01);
+        controller.set_target(10_000);
+        for _ in 0..100 {
+            controller.regulate(0);
+        }
+        controller.set_target(0);
+        assert_eq!(controller.regulate(10_000), 0);
+        // the integral is cleared so starting again doesn't overshoot
+        controller.set_target(100);
+        assert!(controller.regulate(0) < 10);
+    }
+}
@@ -0,0 +1,228 @@
+//! Conversions between the camera coordinate system and the local coordinate system of the robot.
+//!
+//! The camera coordinate system is the global coordinate system used by ssl vision. The heading
+//! of the robot is the angle between the x axis of the camera coordinate system and the forward
+//! direction of the robot, measured counterclockwise.
+
+use core::f32::consts::{PI, TAU};
+
+use intra_comms::definitions::{CameraVelocity, LocalVelocity, Position};
+
+/// Angular velocities are given in rad/s * 2^10
+const ANGULAR_VELOCITY_SCALING: f32 = 1024.0;
+
+/// Wraps an angle in rad into the range `-PI..PI`.
+#[must_use]
+pub fn wrap_angle(angle: f32) -> f32 {
+    let wrapped = libm::remainderf(angle, TAU);
+    if wrapped >= PI {
+        wrapped - TAU
+    } else {
+        wrapped
+    }
+}
+
+/// Heading of the robot at `position` in rad.
+#[must_use]
+pub fn position_heading(position: &Position) -> f32 {
+    wrap_angle(position.theta_rad())
+}
+
+/// Rotates a velocity in the camera coordinate system into the local coordinate system of a robot
+/// with the given heading in rad.
+#[must_use]
+pub fn camera_to_local(velocity: CameraVelocity, heading: f32) -> LocalVelocity {
+    let (sin, cos) = libm::sincosf(heading);
+    let x = f32::from(velocity.x);
+    let y = f32::from(velocity.y);
+    LocalVelocity {
+        forward: libm::roundf(x * cos + y * sin) as i16,
+        left: libm::roundf(y * cos - x * sin) as i16,
+        counterclockwise: velocity.counterclockwise,
+    }
+}
+
+/// Rotates a velocity in the local coordinate system of a robot with the given heading in rad into
+/// the camera coordinate system.
+#[must_use]
+pub fn local_to_camera(velocity: LocalVelocity, heading: f32) -> CameraVelocity {
+    let (sin, cos) = libm::sincosf(heading);
+    let forward = f32::from(velocity.forward);
+    let left = f32::from(velocity.left);
+    CameraVelocity {
+        x: libm::roundf(forward * cos - left * sin) as i16,
+        y: libm::roundf(forward * sin + left * cos) as i16,
+        counterclockwise: velocity.counterclockwise,
+    }
+}
+
+/// Estimates the heading of the robot.
+///
+/// The angular velocity measured by the odometry is integrated to follow fast rotations. Absolute
+/// headings from the vision are blended in using a complementary filter to remove the drift of the
+/// integration while smoothing the noisy and delayed vision data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadingEstimator {
+    heading: Option<f32>,
+    gain: f32,
+}
+
+impl HeadingEstimator {
+    /// Creates a new estimator with an unknown heading.
+    ///
+    /// `gain` is the weight of an absolute measurement in `0.0..=1.0`. Higher values trust the
+    /// vision more, lower values trust the odometry more.
+    #[must_use]
+    pub const fn new(gain: f32) -> Self {
+        Self {
+            heading: None,
+            gain,
+        }
+    }
+
+    /// The current heading estimate in rad. `None` until the first absolute measurement was
+    /// received.
+    #[must_use]
+    pub const fn heading(&self) -> Option<f32> {
+        self.heading
+    }
+
+    /// Integrates the measured angular velocity (rad/s * 2^10) over `dt` seconds.
+    pub fn predict(&mut self, counterclockwise: i16, dt: f32) {
+        if let Some(heading) = self.heading.as_mut() {
+            *heading =
+                wrap_angle(*heading + f32::from(counterclockwise) / ANGULAR_VELOCITY_SCALING * dt);
+        }
+    }
+
+    /// Blends in an absolute heading measurement in rad.
+    ///
+    /// The first measurement is taken as is.
+    pub fn correct(&mut self, measurement: f32) {
+        self.heading = Some(match self.heading {
+            Some(heading) => wrap_angle(heading + self.gain * wrap_angle(measurement - heading)),
+            None => wrap_angle(measurement),
+        });
+    }
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use core::f32::consts::{FRAC_PI_2, PI};
+
+    use intra_comms::definitions::{CameraVelocity, LocalVelocity, Position};
+
+    use super::{camera_to_local, local_to_camera, position_heading, wrap_angle, HeadingEstimator};
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{a} != {b}");
+    }
+
+    #[test]
+    fn wrap() {
+        assert_close(wrap_angle(0.0), 0.0);
+        assert_close(wrap_angle(3.0 * PI / 2.0), -FRAC_PI_2);
+        assert_close(wrap_angle(-3.0 * PI / 2.0), FRAC_PI_2);
+        assert_close(wrap_angle(5.0 * PI), -PI);
+    }
+
+    #[test]
+    fn heading_from_position() {
+        let position = Position {
+            x: 0,
+            y: 0,
+            theta: (FRAC_PI_2 * 4096.0) as u16,
+        };
+        assert_close(position_heading(&position), FRAC_PI_2);
+        let position = Position {
+            x: 0,
+            y: 0,
+            theta: (3.0 * FRAC_PI_2 * 4096.0) as u16,
+        };
+        assert_close(position_heading(&position), -FRAC_PI_2);
+    }
+
+    #[test]
+    fn rotate_velocity() {
+        let velocity = CameraVelocity {
+            x: 1000,
+            y: 500,
+            counterclockwise: 42,
+        };
+        assert_eq!(
+            camera_to_local(velocity, 0.0),
+            LocalVelocity {
+                forward: 1000,
+                left: 500,
+                counterclockwise: 42
+            }
+        );
+        // robot looks along the camera y axis. Camera x is to the right of the robot
+        assert_eq!(
+            camera_to_local(velocity, FRAC_PI_2),
+            LocalVelocity {
+                forward: 500,
+                left: -1000,
+                counterclockwise: 42
+            }
+        );
+        assert_eq!(
+            camera_to_local(velocity, PI),
+            LocalVelocity {
+                forward: -1000,
+                left: -500,
+                counterclockwise: 42
+            }
+        );
+    }
+
+    #[test]
+    fn rotate_back() {
+        let velocity = CameraVelocity {
+            x: 1234,
+            y: -567,
+            counterclockwise: -42,
+        };
+        for heading in [0.0, 0.5, FRAC_PI_2, 2.0, PI, -1.0] {
+            assert_eq!(
+                local_to_camera(camera_to_local(velocity, heading), heading),
+                velocity
+            );
+        }
+    }
+
+    #[test]
+    fn estimator_needs_measurement() {
+        let mut estimator = HeadingEstimator::new(0.5);
+        assert_eq!(estimator.heading(), None);
+        estimator.predict(1024, 1.0);
+        assert_eq!(estimator.heading(), None);
+        estimator.correct(1.0);
+        assert_close(estimator.heading().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn estimator_integrates_odometry() {
+        let mut estimator = HeadingEstimator::new(0.5);
+        estimator.correct(0.0);
+        for _ in 0..100 {
+            estimator.predict(1024, 0.01);
+        }
+        assert_close(estimator.heading().unwrap(), 1.0);
+        estimator.predict(-2048, 1.0);
+        assert_close(estimator.heading().unwrap(), -1.0);
+    }
+
+    #[test]
+    fn estimator_blends_measurements() {
+        let mut estimator = HeadingEstimator::new(0.25);
+        estimator.correct(0.0);
+        estimator.correct(1.0);
+        assert_close(estimator.heading().unwrap(), 0.25);
+        // take the short way around
+        let mut estimator = HeadingEstimator::new(0.5);
+        estimator.correct(PI - 0.1);
+        estimator.correct(-PI + 0.1);
+        assert_close(estimator.heading().unwrap().abs(), PI);
+    }
+}
@@ -0,0 +1,93 @@
+//! Restrictions of the robot during the game states of the referee.
+
+use intra_comms::definitions::LocalVelocity;
+
+use crate::position::limit_length;
+
+/// Scales the linear part of `velocity` down so its magnitude is at most `max_velocity` in m/s.
+/// The direction of travel and the rotation are kept.
+#[must_use]
+pub fn limit_velocity(velocity: LocalVelocity, max_velocity: f32) -> LocalVelocity {
+    let (forward, left) = limit_length(
+        f32::from(velocity.forward),
+        f32::from(velocity.left),
+        max_velocity.max(0.0) * 1000.0,
+    );
+    // truncating keeps the velocity below the limit
+    LocalVelocity {
+        forward: forward as i16,
+        left: left as i16,
+        ..velocity
+    }
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use intra_comms::definitions::LocalVelocity;
+
+    use super::limit_velocity;
+
+    #[test]
+    fn slow_velocity_is_kept() {
+        let velocity = LocalVelocity {
+            forward: 1000,
+            left: -1000,
+            counterclockwise: 3000,
+        };
+        assert_eq!(limit_velocity(velocity, 1.5), velocity);
+    }
+
+    #[test]
+    fn fast_velocity_is_limited() {
+        let velocity = limit_velocity(
+            LocalVelocity {
+                forward: -3000,
+                left: 0,
+                counterclockwise: 3000,
+            },
+            1.5,
+        );
+        assert_eq!(
+            velocity,
+            LocalVelocity {
+                forward: -1500,
+                left: 0,
+                counterclockwise: 3000,
+            }
+        );
+    }
+
+    #[test]
+    fn direction_is_kept() {
+        let velocity = limit_velocity(
+            LocalVelocity {
+                forward: 3000,
+                left: 3000,
+                counterclockwise: 0,
+            },
+            1.5,
+        );
+        assert_eq!(velocity.forward, velocity.left);
+        let magnitude = libm::hypotf(f32::from(velocity.forward), f32::from(velocity.left));
+        assert!((1490.0..=1500.0).contains(&magnitude), "{magnitude}");
+    }
+
+    #[test]
+    fn no_velocity_allowed() {
+        let velocity = LocalVelocity {
+            forward: 1000,
+            left: 500,
+            counterclockwise: 100,
+        };
+        for max_velocity in [0.0, -1.0] {
+            assert_eq!(
+                limit_velocity(velocity, max_velocity),
+                LocalVelocity {
+                    forward: 0,
+                    left: 0,
+                    counterclockwise: 100,
+                }
+            );
+        }
+    }
+}
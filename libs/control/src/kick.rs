@@ -0,0 +1,51 @@
+//! Kick speed conversions.
+
+/// Converts a kick speed relative to the ground into a kick speed relative to the robot.
+///
+/// The ball already has the forward velocity of the robot when it is kicked, so it is subtracted
+/// from the requested speed. All speeds are in mm/s.
+///
+/// A speed of 0 means not to kick and is kept. If the robot is already faster than the requested
+/// speed the weakest possible kick is returned, so the ball still leaves the robot.
+#[must_use]
+pub fn relative_kick_speed(absolute: u16, forward_velocity: i16) -> u16 {
+    if absolute == 0 {
+        return 0;
+    }
+    let relative = i32::from(absolute) - i32::from(forward_velocity);
+    u16::try_from(relative.max(1)).unwrap_or(u16::MAX)
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use super::relative_kick_speed;
+
+    #[test]
+    fn standing_still() {
+        assert_eq!(relative_kick_speed(3000, 0), 3000);
+    }
+
+    #[test]
+    fn driving_forward() {
+        assert_eq!(relative_kick_speed(3000, 1000), 2000);
+    }
+
+    #[test]
+    fn driving_backward() {
+        assert_eq!(relative_kick_speed(3000, -1000), 4000);
+        assert_eq!(relative_kick_speed(u16::MAX - 10, -1000), u16::MAX);
+    }
+
+    #[test]
+    fn faster_than_requested() {
+        assert_eq!(relative_kick_speed(1000, 1000), 1);
+        assert_eq!(relative_kick_speed(1000, 3000), 1);
+    }
+
+    #[test]
+    fn no_kick() {
+        assert_eq!(relative_kick_speed(0, 0), 0);
+        assert_eq!(relative_kick_speed(0, -1000), 0);
+        assert_eq!(relative_kick_speed(0, 1000), 0);
+    }
+}
@@ -0,0 +1,14 @@
+//! Hardware independent control algorithms used by the robot firmware.
+//!
+//! Everything in here works on the types defined in `intra-comms` so it can be used directly on
+//! the values received from the basestation and tested on the host.
+
+#![cfg_attr(any(not(test), target_arch = "arm"), no_std)]
+
+pub mod ball;
+pub mod dribbler;
+pub mod frame;
+pub mod game_state;
+pub mod kick;
+pub mod position;
+pub mod timesync;
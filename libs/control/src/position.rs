@@ -0,0 +1,570 @@
+//! Position control in the camera coordinate system.
+//!
+//! The [`PositionController`] calculates the velocity needed to drive to a target pose. The
+//! velocity is limited so the robot is always able to brake in time using the configured
+//! accelleration. The accelleration and jerk limits of the motorcontroller are applied afterwards
+//! on the velocity in the local coordinate system, so the configured accelleration must not
+//! exceed the one of the motorcontroller and the reaction time has to cover its jerk limit.
+
+use intra_comms::definitions::{CameraVelocity, Position};
+use pidcontroller::{Controller, PIDController};
+
+use crate::frame::{position_heading, wrap_angle};
+
+/// Angular velocities are given in rad/s * 2^10
+const ANGULAR_VELOCITY_SCALING: f32 = 1024.0;
+
+/// Pose of a robot in the camera coordinate system
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Pose {
+    /// m
+    pub x: f32,
+    /// m
+    pub y: f32,
+    /// rad
+    pub theta: f32,
+}
+
+impl From<Position> for Pose {
+    fn from(position: Position) -> Self {
+        Self {
+            x: f32::from(position.x) / 1000.0,
+            y: f32::from(position.y) / 1000.0,
+            theta: position_heading(&position),
+        }
+    }
+}
+
+/// Velocity of a robot in the camera coordinate system
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Velocity {
+    /// m/s
+    pub x: f32,
+    /// m/s
+    pub y: f32,
+    /// rad/s
+    pub counterclockwise: f32,
+}
+
+impl From<CameraVelocity> for Velocity {
+    fn from(velocity: CameraVelocity) -> Self {
+        Self {
+            x: f32::from(velocity.x) / 1000.0,
+            y: f32::from(velocity.y) / 1000.0,
+            counterclockwise: f32::from(velocity.counterclockwise) / ANGULAR_VELOCITY_SCALING,
+        }
+    }
+}
+
+impl From<Velocity> for CameraVelocity {
+    fn from(velocity: Velocity) -> Self {
+        Self {
+            x: libm::roundf(velocity.x * 1000.0) as i16,
+            y: libm::roundf(velocity.y * 1000.0) as i16,
+            counterclockwise: libm::roundf(velocity.counterclockwise * ANGULAR_VELOCITY_SCALING)
+                as i16,
+        }
+    }
+}
+
+/// Limits of the velocity calculated by the [`PositionController`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// m/s
+    pub linear_velocity: f32,
+    /// m/s²
+    pub linear_accelleration: f32,
+    /// rad/s
+    pub angular_velocity: f32,
+    /// rad/s²
+    pub angular_accelleration: f32,
+    /// m/s², used to plan the braking. Only lower than `linear_accelleration` makes a difference.
+    pub linear_braking: f32,
+    /// rad/s², used to plan the braking. Only lower than `angular_accelleration` makes a
+    /// difference.
+    pub angular_braking: f32,
+    /// Time in s until a change in the velocity takes effect
+    pub reaction_time: f32,
+}
+
+/// Controls the position of the robot in the camera coordinate system.
+pub struct PositionController {
+    /// Controllers for x, y and theta. Their output is the velocity in m/s and rad/s.
+    controllers: [PIDController<f32>; 3],
+    target: Pose,
+    limits: Limits,
+    velocity: Velocity,
+}
+
+impl PositionController {
+    /// Creates a new position controller.
+    ///
+    /// `linear_gain` and `angular_gain` are the p gains in 1/s.
+    #[must_use]
+    pub fn new(linear_gain: f32, angular_gain: f32, limits: Limits) -> Self {
+        let mut controller = Self {
+            controllers: [
+                PIDController::new(),
+                PIDController::new(),
+                PIDController::new(),
+            ],
+            target: Pose::default(),
+            limits,
+            velocity: Velocity::default(),
+        };
+        controller.set_gains(linear_gain, angular_gain);
+        controller
+    }
+
+    /// Set the p gains in 1/s.
+    pub fn set_gains(&mut self, linear_gain: f32, angular_gain: f32) {
+        self.controllers[0].p_gain = linear_gain;
+        self.controllers[1].p_gain = linear_gain;
+        self.controllers[2].p_gain = angular_gain;
+    }
+
+    /// Set the limits of the calculated velocity.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Set the pose to drive to.
+    pub fn set_target(&mut self, target: Pose) {
+        self.target = Pose {
+            theta: wrap_angle(target.theta),
+            ..target
+        };
+        self.controllers
+            .set_target(&[self.target.x, self.target.y, self.target.theta]);
+    }
+
+    /// Restart the control from the current velocity of the robot.
+    ///
+    /// This has to be called when the controller was not used for some time.
+    pub fn reset(&mut self, velocity: Velocity) {
+        for controller in &mut self.controllers {
+            controller.clear_integral();
+        }
+        self.velocity = velocity;
+    }
+
+    /// Calculate the velocity to drive to the target from the `current` pose. `dt` is the time in
+    /// s since the last call.
+    pub fn regulate(&mut self, current: Pose, dt: f32) -> Velocity {
+        // give the controller the current angle on the short way around
+        let theta_error = wrap_angle(self.target.theta - current.theta);
+        let [x, y, counterclockwise] =
+            self.controllers
+                .regulate(&[current.x, current.y, self.target.theta - theta_error]);
+
+        // limit the velocity so the robot can still brake in time. The distance the robot travels
+        // until the brake takes effect is subtracted.
+        let distance = libm::hypotf(self.target.x - current.x, self.target.y - current.y)
+            - libm::hypotf(self.velocity.x, self.velocity.y) * self.limits.reaction_time;
+        let linear_braking = self
+            .limits
+            .linear_braking
+            .min(self.limits.linear_accelleration);
+        let max_linear_velocity = self
+            .limits
+            .linear_velocity
+            .min(libm::sqrtf(2.0 * linear_braking * distance.max(0.0)));
+        let (x, y) = limit_length(x, y, max_linear_velocity);
+        let angle =
+            theta_error.abs() - self.velocity.counterclockwise.abs() * self.limits.reaction_time;
+        let angular_braking = self
+            .limits
+            .angular_braking
+            .min(self.limits.angular_accelleration);
+        let max_angular_velocity = self
+            .limits
+            .angular_velocity
+            .min(libm::sqrtf(2.0 * angular_braking * angle.max(0.0)));
+        let counterclockwise = counterclockwise.clamp(-max_angular_velocity, max_angular_velocity);
+
+        // limit the accelleration
+        let max_linear_change = self.limits.linear_accelleration * dt;
+        let (x_change, y_change) =
+            limit_length(x - self.velocity.x, y - self.velocity.y, max_linear_change);
+        let max_angular_change = self.limits.angular_accelleration * dt;
+        let counterclockwise_change = (counterclockwise - self.velocity.counterclockwise)
+            .clamp(-max_angular_change, max_angular_change);
+
+        self.velocity = Velocity {
+            x: self.velocity.x + x_change,
+            y: self.velocity.y + y_change,
+            counterclockwise: self.velocity.counterclockwise + counterclockwise_change,
+        };
+        self.velocity
+    }
+}
+
+/// Scales the vector (x, y) down so its length is at most `max_length`.
+pub(crate) fn limit_length(x: f32, y: f32, max_length: f32) -> (f32, f32) {
+    let length = libm::hypotf(x, y);
+    if length > max_length && length > 0.0 {
+        let scaling = max_length / length;
+        (x * scaling, y * scaling)
+    } else {
+        (x, y)
+    }
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use core::f32::consts::{FRAC_PI_2, PI};
+
+    use intra_comms::definitions::{CameraVelocity, Position};
+
+    use super::{Limits, Pose, PositionController, Velocity};
+    use crate::frame::{camera_to_local, wrap_angle};
+
+    const LIMITS: Limits = Limits {
+        linear_velocity: 2.0,
+        linear_accelleration: 3.0,
+        angular_velocity: 6.0,
+        angular_accelleration: 20.0,
+        linear_braking: 3.0,
+        angular_braking: 20.0,
+        reaction_time: 0.05,
+    };
+
+    /// Simulation of the drivetrain as it is done by the motorcontroller in `odometry.rs`.
+    mod drivetrain {
+        use core::f32::consts::FRAC_1_SQRT_2;
+
+        use intra_comms::definitions::LocalVelocity;
+
+        use super::Pose;
+
+        const SIN_FRONT_WHEELS_ANGLE: f32 = 0.5;
+        const COS_FRONT_WHEELS_ANGLE: f32 = 0.866_025_4;
+        const SIN_BACK_WHEELS_ANGLE: f32 = FRAC_1_SQRT_2;
+        const COS_BACK_WHEELS_ANGLE: f32 = FRAC_1_SQRT_2;
+        const ROBOT_RADIUS: f32 = 0.08;
+        const WHEEL_RADIUS: f32 = 0.031;
+
+        // default limits from the motorcontroller config
+        const LINEAR_ACCELLERATION: f32 = 7.0;
+        const ANGULAR_ACCELLERATION: f32 = 42.0;
+        const LINEAR_JERK: f32 = 50.0;
+        const ANGULAR_JERK: f32 = 300.0;
+
+        pub const CONTROL_DURATION: f32 = 0.001;
+
+        /// Same as `calculate_wheel_speeds` in the motorcontroller
+        fn wheel_speeds(velocity: [f32; 3]) -> [f32; 4] {
+            let [forward, left, counterclockwise] = velocity;
+            let rotation = ROBOT_RADIUS * counterclockwise;
+            [
+                (COS_BACK_WHEELS_ANGLE * forward - SIN_BACK_WHEELS_ANGLE * left + rotation)
+                    / WHEEL_RADIUS,
+                (-COS_BACK_WHEELS_ANGLE * forward - SIN_BACK_WHEELS_ANGLE * left + rotation)
+                    / WHEEL_RADIUS,
+                (COS_FRONT_WHEELS_ANGLE * forward + SIN_FRONT_WHEELS_ANGLE * left + rotation)
+                    / WHEEL_RADIUS,
+                (-COS_FRONT_WHEELS_ANGLE * forward + SIN_FRONT_WHEELS_ANGLE * left + rotation)
+                    / WHEEL_RADIUS,
+            ]
+        }
+
+        /// Same as `calculate_velocity` in the motorcontroller
+        fn velocity(wheel_speeds: [f32; 4]) -> [f32; 3] {
+            const SIN_FRONT_SIN_BACK: f32 = SIN_BACK_WHEELS_ANGLE + SIN_FRONT_WHEELS_ANGLE;
+            const COS_FRONT_COS_BACK_SQUARED: f32 = COS_BACK_WHEELS_ANGLE * COS_BACK_WHEELS_ANGLE
+                + COS_FRONT_WHEELS_ANGLE * COS_FRONT_WHEELS_ANGLE;
+            const LEFT_FACTOR: f32 = WHEEL_RADIUS / SIN_FRONT_SIN_BACK / 2.0;
+            const FRONT_FACTOR: f32 = COS_FRONT_COS_BACK_SQUARED * 2.0 / WHEEL_RADIUS;
+            const ROTATION_FACTOR: f32 = SIN_FRONT_SIN_BACK * 2.0 * ROBOT_RADIUS / WHEEL_RADIUS;
+            let [m0, m1, m2, m3] = wheel_speeds;
+            [
+                (COS_BACK_WHEELS_ANGLE * (m0 - m1) + COS_FRONT_WHEELS_ANGLE * (m2 - m3))
+                    / FRONT_FACTOR,
+                LEFT_FACTOR * (m2 + m3 - m0 - m1),
+                (SIN_FRONT_WHEELS_ANGLE * (m0 + m1) + SIN_BACK_WHEELS_ANGLE * (m2 + m3))
+                    / ROTATION_FACTOR,
+            ]
+        }
+
+        /// Same as `calc_accelleration` in the motorcontroller
+        fn accelleration(
+            accelleration: f32,
+            velocity_error: f32,
+            jerk: f32,
+            max_accelleration: f32,
+        ) -> f32 {
+            let velocity_margin = accelleration * accelleration / (jerk * 2.0);
+            if velocity_error.abs() <= velocity_margin {
+                if accelleration < 0.0 {
+                    (accelleration + jerk * CONTROL_DURATION).min(0.0)
+                } else {
+                    (accelleration - jerk * CONTROL_DURATION).max(0.0)
+                }
+            } else if velocity_error < 0.0 {
+                (accelleration - jerk * CONTROL_DURATION).max(-max_accelleration)
+            } else {
+                (accelleration + jerk * CONTROL_DURATION).min(max_accelleration)
+            }
+        }
+
+        pub struct Drivetrain {
+            pub pose: Pose,
+            velocity: [f32; 3],
+            accelleration: [f32; 3],
+        }
+
+        impl Drivetrain {
+            /// A drivetrain standing still at `pose`
+            pub const fn new(pose: Pose) -> Self {
+                Self {
+                    pose,
+                    velocity: [0.0; 3],
+                    accelleration: [0.0; 3],
+                }
+            }
+
+            /// Simulates one control cycle of the motorcontroller
+            pub fn step(&mut self, setpoint: LocalVelocity) {
+                let setpoint = [
+                    f32::from(setpoint.forward) / 1000.0,
+                    f32::from(setpoint.left) / 1000.0,
+                    f32::from(setpoint.counterclockwise) / 1024.0,
+                ];
+                let limits = [
+                    (LINEAR_JERK, LINEAR_ACCELLERATION),
+                    (LINEAR_JERK, LINEAR_ACCELLERATION),
+                    (ANGULAR_JERK, ANGULAR_ACCELLERATION),
+                ];
+                for i in 0..3 {
+                    self.accelleration[i] = accelleration(
+                        self.accelleration[i],
+                        setpoint[i] - self.velocity[i],
+                        limits[i].0,
+                        limits[i].1,
+                    );
+                    self.velocity[i] += self.accelleration[i] * CONTROL_DURATION;
+                }
+
+                // the motors follow the wheel speeds perfectly
+                let [forward, left, counterclockwise] = velocity(wheel_speeds(self.velocity));
+                let (sin, cos) = libm::sincosf(self.pose.theta);
+                self.pose.x += (forward * cos - left * sin) * CONTROL_DURATION;
+                self.pose.y += (forward * sin + left * cos) * CONTROL_DURATION;
+                self.pose.theta += counterclockwise * CONTROL_DURATION;
+            }
+        }
+    }
+
+    /// Drives from `start` to `target` and returns the poses of the robot every 10ms.
+    fn simulate(start: Pose, target: Pose, seconds: f32) -> impl Iterator<Item = Pose> {
+        const CONTROL_RATE: usize = 100;
+        const STEPS_PER_CONTROL: usize =
+            (1.0 / drivetrain::CONTROL_DURATION) as usize / CONTROL_RATE;
+
+        let mut drivetrain = drivetrain::Drivetrain::new(start);
+        let mut controller = PositionController::new(4.0, 6.0, LIMITS);
+        controller.set_target(target);
+        controller.reset(Velocity::default());
+        let controls = (seconds * CONTROL_RATE as f32) as usize;
+        (0..controls).map(move |_| {
+            let velocity = controller.regulate(drivetrain.pose, 1.0 / CONTROL_RATE as f32);
+            let setpoint = camera_to_local(velocity.into(), drivetrain.pose.theta);
+            for _ in 0..STEPS_PER_CONTROL {
+                drivetrain.step(setpoint);
+            }
+            drivetrain.pose
+        })
+    }
+
+    /// Checks that the robot reaches the target and that it never gets further past the target
+    /// than the tolerance.
+    fn check_convergence(start: Pose, target: Pose) {
+        const LINEAR_TOLERANCE: f32 = 0.01;
+        const ANGULAR_TOLERANCE: f32 = 0.02;
+
+        let direction = (target.x - start.x, target.y - start.y);
+        let distance = libm::hypotf(direction.0, direction.1);
+        let rotation = wrap_angle(target.theta - start.theta);
+        let mut last = start;
+        for pose in simulate(start, target, 5.0) {
+            // distance travelled along the direction to the target
+            let travelled = if distance > 0.0 {
+                ((pose.x - start.x) * direction.0 + (pose.y - start.y) * direction.1) / distance
+            } else {
+                0.0
+            };
+            assert!(
+                travelled < distance + LINEAR_TOLERANCE,
+                "overshoot of {}m",
+                travelled - distance
+            );
+            let rotated = wrap_angle(pose.theta - start.theta);
+            assert!(
+                rotated.abs() < rotation.abs() + ANGULAR_TOLERANCE
+                    || rotated.signum() != rotation.signum(),
+                "overshoot of {}rad",
+                rotated.abs() - rotation.abs()
+            );
+            last = pose;
+        }
+        assert!(
+            libm::hypotf(target.x - last.x, target.y - last.y) < LINEAR_TOLERANCE,
+            "target {target:?} not reached: {last:?}"
+        );
+        assert!(
+            wrap_angle(target.theta - last.theta).abs() < ANGULAR_TOLERANCE,
+            "target {target:?} not reached: {last:?}"
+        );
+    }
+
+    #[test]
+    fn drive_straight() {
+        check_convergence(
+            Pose::default(),
+            Pose {
+                x: 2.0,
+                y: 0.0,
+                theta: 0.0,
+            },
+        );
+        check_convergence(
+            Pose::default(),
+            Pose {
+                x: 0.0,
+                y: -0.05,
+                theta: 0.0,
+            },
+        );
+    }
+
+    #[test]
+    fn drive_diagonal_while_rotated() {
+        check_convergence(
+            Pose {
+                x: -1.0,
+                y: 0.5,
+                theta: FRAC_PI_2,
+            },
+            Pose {
+                x: 1.5,
+                y: -1.0,
+                theta: FRAC_PI_2,
+            },
+        );
+    }
+
+    #[test]
+    fn rotate() {
+        check_convergence(
+            Pose::default(),
+            Pose {
+                x: 0.0,
+                y: 0.0,
+                theta: 2.0,
+            },
+        );
+        // take the short way around
+        check_convergence(
+            Pose {
+                x: 0.0,
+                y: 0.0,
+                theta: PI - 0.3,
+            },
+            Pose {
+                x: 0.0,
+                y: 0.0,
+                theta: -PI + 0.3,
+            },
+        );
+    }
+
+    #[test]
+    fn drive_and_rotate() {
+        check_convergence(
+            Pose {
+                x: 0.5,
+                y: 1.0,
+                theta: -1.0,
+            },
+            Pose {
+                x: -1.0,
+                y: -0.5,
+                theta: 2.5,
+            },
+        );
+    }
+
+    #[test]
+    fn respect_limits() {
+        let mut controller = PositionController::new(100.0, 100.0, LIMITS);
+        controller.set_target(Pose {
+            x: 10.0,
+            y: 10.0,
+            theta: 3.0,
+        });
+        controller.reset(Velocity::default());
+        let velocity = controller.regulate(Pose::default(), 0.01);
+        assert!(libm::hypotf(velocity.x, velocity.y) <= LIMITS.linear_accelleration * 0.01 + 1e-6);
+        assert!(velocity.counterclockwise <= LIMITS.angular_accelleration * 0.01 + 1e-6);
+        let mut velocity = velocity;
+        for _ in 0..1000 {
+            velocity = controller.regulate(Pose::default(), 0.01);
+        }
+        assert!(libm::hypotf(velocity.x, velocity.y) <= LIMITS.linear_velocity + 1e-6);
+        assert!(velocity.counterclockwise <= LIMITS.angular_velocity + 1e-6);
+    }
+
+    #[test]
+    fn brake_earlier() {
+        /// Highest speed while driving 2m straight with the pose following the velocity exactly
+        fn top_speed(limits: Limits) -> f32 {
+            let mut controller = PositionController::new(4.0, 6.0, limits);
+            controller.set_target(Pose {
+                x: 2.0,
+                y: 0.0,
+                theta: 0.0,
+            });
+            controller.reset(Velocity::default());
+            let mut pose = Pose::default();
+            let mut top_speed = 0.0_f32;
+            for _ in 0..500 {
+                let velocity = controller.regulate(pose, 0.01);
+                pose.x += velocity.x * 0.01;
+                top_speed = top_speed.max(velocity.x);
+            }
+            top_speed
+        }
+
+        assert!(top_speed(LIMITS) > 1.9);
+        // the robot can't reach the velocity limit if it has to brake gently
+        assert!(
+            top_speed(Limits {
+                linear_braking: 1.0,
+                ..LIMITS
+            }) < 1.8
+        );
+    }
+
+    #[test]
+    fn convert_units() {
+        let pose = Pose::from(Position {
+            x: 1500,
+            y: -250,
+            theta: 4096,
+        });
+        assert_eq!(
+            pose,
+            Pose {
+                x: 1.5,
+                y: -0.25,
+                theta: 1.0
+            }
+        );
+        let velocity = CameraVelocity {
+            x: 1000,
+            y: -20,
+            counterclockwise: 512,
+        };
+        assert_eq!(CameraVelocity::from(Velocity::from(velocity)), velocity);
+    }
+}
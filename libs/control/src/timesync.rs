@@ -0,0 +1,135 @@
+//! Synchronisation of the robot clock with the basestation.
+//!
+//! The basestation sends its time with every packet, already advanced by half of the round trip
+//! time it measured, so it matches the moment the robot receives the packet. [`TimeSync`]
+//! estimates the offset and the drift between both clocks from these samples, so times measured
+//! on the robot can be reported in the clock of the basestation.
+
+/// Weight of a new measurement in the offset
+const OFFSET_GAIN: f64 = 0.1;
+/// Weight of a new measurement in the drift
+const DRIFT_GAIN: f64 = 0.0025;
+/// A measurement deviating further from the prediction in us means the basestation was restarted
+const MAX_ERROR: f64 = 10_000.0;
+
+/// Estimates the time of the basestation.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TimeSync {
+    /// Local time in us of the last measurement and the estimated offset in us at that time
+    reference: Option<(u64, f64)>,
+    /// Drift of the offset in us per us
+    drift: f64,
+}
+
+impl TimeSync {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            reference: None,
+            drift: 0.0,
+        }
+    }
+
+    /// Adds the time of the basestation `remote` received at the local time `local`, both in us.
+    pub fn update(&mut self, local: u64, remote: u64) {
+        let measured = remote as f64 - local as f64;
+        let Some((last_local, offset)) = self.reference else {
+            self.reset(local, measured);
+            return;
+        };
+        let elapsed = local.saturating_sub(last_local) as f64;
+        let predicted = offset + self.drift * elapsed;
+        let error = measured - predicted;
+        if error.abs() > MAX_ERROR {
+            self.reset(local, measured);
+            return;
+        }
+        if elapsed > 0.0 {
+            self.drift += DRIFT_GAIN * error / elapsed;
+        }
+        self.reference = Some((local, predicted + OFFSET_GAIN * error));
+    }
+
+    /// Converts the local time `local` in us into the time of the basestation in us. `None` until
+    /// the first time of the basestation was received.
+    #[must_use]
+    pub fn to_remote(&self, local: u64) -> Option<u64> {
+        let (last_local, offset) = self.reference?;
+        let elapsed = local as f64 - last_local as f64;
+        let remote = local as f64 + offset + self.drift * elapsed;
+        (remote >= 0.0).then(|| libm::round(remote) as u64)
+    }
+
+    /// Drift of the local clock relative to the basestation in us per us
+    #[must_use]
+    pub const fn drift(&self) -> f64 {
+        self.drift
+    }
+
+    fn reset(&mut self, local: u64, offset: f64) {
+        self.reference = Some((local, offset));
+        self.drift = 0.0;
+    }
+}
+
+#[cfg(not(any(not(test), target_arch = "arm")))]
+mod tests {
+    use super::TimeSync;
+
+    /// Basestation which booted 5 s earlier and whose clock runs 100 ppm faster
+    fn remote(local: u64) -> u64 {
+        5_000_000 + local + local / 10_000
+    }
+
+    /// Deterministic latency jitter in -20..20 us
+    fn jitter(i: u64) -> i64 {
+        (i * 7919 % 41) as i64 - 20
+    }
+
+    /// Runs the synchronisation for `samples` packets sent every 10 ms starting at `start`
+    fn run(sync: &mut TimeSync, start: u64, samples: u64, remote: impl Fn(u64) -> u64) -> u64 {
+        let mut local = start;
+        for i in 0..samples {
+            local = start + i * 10_000;
+            sync.update(local, (remote(local) as i64 + jitter(i)) as u64);
+        }
+        local
+    }
+
+    #[test]
+    fn unknown_before_first_packet() {
+        let mut sync = TimeSync::new();
+        assert_eq!(sync.to_remote(1000), None);
+        sync.update(1000, 6000);
+        assert_eq!(sync.to_remote(1000), Some(6000));
+        assert_eq!(sync.to_remote(2000), Some(7000));
+    }
+
+    #[test]
+    fn follows_offset_and_drift() {
+        let mut sync = TimeSync::new();
+        let local = run(&mut sync, 1_000_000, 6000, remote);
+        assert!(
+            (sync.drift() - 1e-4).abs() < 1e-5,
+            "drift is {}",
+            sync.drift()
+        );
+        // still correct a while after the last packet
+        for local in [local, local + 100_000, local + 1_000_000] {
+            let error = sync.to_remote(local).unwrap() as i64 - remote(local) as i64;
+            assert!(error.abs() < 50, "error is {error} us");
+        }
+    }
+
+    #[test]
+    fn basestation_restart() {
+        let mut sync = TimeSync::new();
+        let local = run(&mut sync, 1_000_000, 1000, remote);
+        let restarted = |local| local - 2_000_000;
+        sync.update(local + 10_000, restarted(local + 10_000));
+        assert_eq!(
+            sync.to_remote(local + 10_000),
+            Some(restarted(local + 10_000))
+        );
+    }
+}
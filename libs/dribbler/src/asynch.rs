@@ -0,0 +1,141 @@
+//! Bidirectional DSHOT for firmwares using embassy.
+
+use defmt::{trace, warn};
+use embassy_rp::{
+    clocks::clk_sys_freq,
+    gpio::{Level, Pull},
+    pio::{
+        self as pio_mod, Common, Direction, FifoJoin, Instance, PioPin, ShiftConfig,
+        ShiftDirection, StateMachine,
+    },
+    pio_instr_util,
+    relocate::RelocatedProgram,
+    Peripheral,
+};
+use fixed::{types::extra::U8, FixedU32};
+use fugit::HertzU32;
+
+use crate::{
+    protocol::{bidir_program, decode_telemetry, DshotCommand, DshotFrame, BIDIR_CYCLES_PER_BIT},
+    Dribbler, Version,
+};
+
+/// Bidirectional DSHOT using a PIO state machine.
+///
+/// The ESC detects bidirectional DSHOT by the inverted signal and answers every frame with the
+/// eRPM period of the motor.
+pub struct PioDshot<'d, PIO, const SM: usize>
+where
+    PIO: Instance,
+{
+    sm: StateMachine<'d, PIO, SM>,
+    origin: u8,
+    erpm_period: Option<u16>,
+}
+
+impl<'d, PIO, const SM: usize> PioDshot<'d, PIO, SM>
+where
+    PIO: Instance,
+{
+    /// Creates a new [`PioDshot`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `version` is DSHOT 150 which doesn't support bidirectional DSHOT or if no divider
+    /// for the system clock can be found.
+    pub fn new(
+        mut sm: StateMachine<'d, PIO, SM>,
+        pio: &mut Common<'d, PIO>,
+        pin: impl Peripheral<P = impl PioPin + 'd> + 'd,
+        version: Version,
+    ) -> Self {
+        assert!(
+            version != Version::Dshot150,
+            "DSHOT 150 doesn't support bidirectional DSHOT"
+        );
+        let bit_freq = HertzU32::from(version).to_Hz() * BIDIR_CYCLES_PER_BIT;
+        let divider = u64::from(clk_sys_freq()) * 256 / u64::from(bit_freq);
+
+        let mut pin = pio.make_pio_pin(pin);
+        // the line is idle high while the ESC is not answering
+        pin.set_pull(Pull::Up);
+        let relocated = RelocatedProgram::new(&bidir_program(pin.pin()));
+        let origin = relocated.origin();
+
+        let mut cfg = pio_mod::Config::default();
+        cfg.use_program(&pio.load_program(&relocated), &[&pin]);
+        cfg.set_set_pins(&[&pin]);
+        cfg.set_in_pins(&[&pin]);
+        cfg.clock_divider = FixedU32::<U8>::from_bits(u32::try_from(divider).unwrap_or(u32::MAX));
+        cfg.shift_out = ShiftConfig {
+            auto_fill: true,
+            threshold: 16,
+            direction: ShiftDirection::Left,
+        };
+        cfg.shift_in = ShiftConfig {
+            auto_fill: true,
+            threshold: 21,
+            direction: ShiftDirection::Left,
+        };
+        cfg.fifo_join = FifoJoin::Duplex;
+        sm.set_config(&cfg);
+        sm.set_pins(Level::High, &[&pin]);
+        sm.set_pin_dirs(Direction::Out, &[&pin]);
+        sm.set_enable(true);
+
+        Self {
+            sm,
+            origin,
+            erpm_period: None,
+        }
+    }
+
+    /// Sets the throttle. Values outside of 0..2000 are ignored.
+    pub fn throttle(&mut self, throttle: u16) {
+        if (0..2000).contains(&throttle) {
+            self.send_command(DshotCommand::Throttle(throttle));
+        }
+    }
+
+    pub fn disarm(&mut self) {
+        self.send_command(DshotCommand::Disarm);
+    }
+
+    /// The eRPM period in us received as answer to the last frame. `None` if the ESC didn't
+    /// answer or the answer was corrupted.
+    pub fn erpm_period(&self) -> Option<u16> {
+        self.erpm_period
+    }
+
+    fn read_telemetry(&mut self) {
+        self.erpm_period = self.sm.rx().try_pull().and_then(decode_telemetry);
+    }
+
+    fn send_command(&mut self, command: DshotCommand) {
+        let frame = DshotFrame {
+            command,
+            telemetry: false,
+        };
+        // the checksum of bidirectional DSHOT is inverted
+        let value = u16::from(frame) ^ 0x000F;
+        trace!("sending bidirectional dshot value {}", value);
+        self.read_telemetry();
+        self.sm.clear_fifos();
+        self.sm.restart();
+        // the state machine is still waiting for the telemetry if the ESC didn't answer
+        unsafe { pio_instr_util::exec_jmp(&mut self.sm, self.origin) };
+        if !self.sm.tx().try_push(u32::from(value) << 16) {
+            warn!("dshot tx fifo full");
+        }
+    }
+}
+
+impl<'d, PIO, const SM: usize> Dribbler<u16> for PioDshot<'d, PIO, SM>
+where
+    PIO: Instance,
+{
+    fn send(&mut self, speed: u16) {
+        let throttle = u32::from(speed) * 1999 / u32::from(u16::MAX);
+        self.throttle(u16::try_from(throttle).unwrap_or(1999));
+    }
+}
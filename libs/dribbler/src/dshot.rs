@@ -70,12 +70,15 @@
 
 use core::marker::PhantomData;
 
-use crate::Dribbler;
+use crate::{
+    protocol::{bidir_program, decode_telemetry, DshotCommand, DshotFrame, BIDIR_CYCLES_PER_BIT},
+    Dribbler,
+};
 
-use defmt::{trace, warn, Format};
+use defmt::trace;
 use fixed::{types::extra::U2, FixedU8};
-use fugit::{HertzU32, Rate, RateExtU32, RateExtU64};
-use pio::{Assembler, InSource, JmpCondition, OutDestination, SetDestination, SideSet, WaitSource};
+use fugit::{HertzU32, RateExtU32};
+use pio::{Assembler, JmpCondition, OutDestination, SideSet};
 use rp2040_hal::{
     gpio::{Function, FunctionConfig, Pin, PinId, ValidPinMode},
     pio::{
@@ -88,210 +91,7 @@ use units::{
     types::{Ampere, Volt},
 };
 
-#[repr(u16)]
-#[allow(dead_code)]
-#[derive(Debug, Format, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-enum DshotCommand {
-    /// Disarm the motor. Can only be send when stopped.
-    Disarm = 0,
-    /// Beep using the motor. Can only be send when stopped. Wait at least 260ms before next
-    /// command.
-    Beep1 = 1,
-    /// Beep using the motor. Can only be send when stopped. Wait at least 260ms before next
-    /// command.
-    Beep2 = 2,
-    /// Beep using the motor. Can only be send when stopped. Wait at least 260ms before next
-    /// command.
-    Beep3 = 3,
-    /// Beep using the motor. Can only be send when stopped. Wait at least 260ms before next
-    /// command.
-    Beep4 = 4,
-    /// Beep using the motor. Can only be send when stopped. Wait at least 260ms before next
-    /// command.
-    Beep5 = 5,
-    /// Request ESC info. Can only be send when stopped. Wait at least 12ms before next command.
-    EscInfo = 6,
-    /// Set spin direction to direction 1. Can only be send when stopped. Needs to be send 6 times.
-    SpinDirection1 = 7,
-    /// Set spin direction to direction 2. Can only be send when stopped. Needs to be send 6 times.
-    SpinDirection2 = 8,
-    /// Disable 3D mode (spinning in both directions). Can only be send when stopped. Needs to be
-    /// send 6 times.
-    Disable3DMode = 9,
-    /// Enable 3D mode (spinning in both directions). Can only be send when stopped. Needs to be
-    /// send 6 times.
-    Enable3DMode = 10,
-    /// Request current ESC settings. Can only be send when stopped.
-    SettingsRequest = 11,
-    /// Save the current ESC settings. Can only be send when stopped. Needs to be send 6 times.
-    /// Wait at least 35ms before next command.
-    SaveSettings = 12,
-    /// Enable extended telemetry. Can only be send when stopped. Needs to be send 6 times.
-    EnableExtendedTelemetry = 13,
-    /// Disable extended telemetry. Can only be send when stopped. Needs to be send 6 times.
-    DisableExtendedTelemetry = 14,
-    /// Set spin direction to Normal. Can only be send when stopped. Needs to be send 6 times.
-    SpinDirectionNormal = 20,
-    /// Set spin direction to Reverse. Can only be send when stopped. Needs to be send 6 times.
-    SpinDirectionReversed = 21,
-    /// Turn Led 0 on. Can only be send when stopped.
-    Led0On = 22,
-    /// Turn Led 1 on. Can only be send when stopped.
-    Led1On = 23,
-    /// Turn Led 2 on. Can only be send when stopped.
-    Led2On = 24,
-    /// Turn Led 3 on. Can only be send when stopped.
-    Led3On = 25,
-    /// Turn Led 0 off. Can only be send when stopped.
-    Led0Off = 26,
-    /// Turn Led 1 off. Can only be send when stopped.
-    Led1Off = 27,
-    /// Turn Led 2 off. Can only be send when stopped.
-    Led2Off = 28,
-    /// Turn Led 3 off. Can only be send when stopped.
-    Led3Off = 29,
-    /// Toggle audio stream mode. Can only be send when stopped.
-    AudioStreamModeToggle = 30,
-    /// Toggle silent mode. Can only be send when stopped.
-    SilentModeToggle = 31,
-    /// Disables commands 42..=47. Can only be send when stopped. Needs to be send 6 times.
-    DisableSignalLineTelemetry = 32,
-    /// Enables commands 42..=47. Can only be send when stopped. Needs to be send 6 times.
-    EnableSignalLineTelemetry = 33,
-    /// Enables commands 42..=47 and sends erpm if normal DSHOT frame. Can only be send when stopped. Needs to be send 6 times.
-    SignalLineContinuousErpmTelemetry = 34,
-    /// Enables commands 42..=47 and sends erpm period if normal DSHOT frame. Can only be send when stopped. Needs to be send 6 times.
-    SignalLineContinuousErpmPeriodTelemetry = 35,
-    /// Send temperature telemetry. 1°C per LSB.
-    SignalLineTemperatueTelemetry = 42,
-    /// Send voltage telemetry. 10mV per LSB.
-    SignalLineVoltageTelemetry = 43,
-    /// Send current telemetry. 100mA per LSB.
-    SignalLineCurrentTelemetry = 44,
-    /// Send consumption telemetry. 10mAh per LSB.
-    SignalLineConsumptionTelemetry = 45,
-    /// Send erpm telemetry. 100erpm per LSB.
-    SignalLineErpmTelemetry = 46,
-    /// Send erpm period telemetry. 16us per LSB.
-    SignalLineErpmPeriodTelemetry = 47,
-    /// Throttle value in 0..2000.
-    Throttle(u16) = 48,
-}
-
-impl From<DshotCommand> for u16 {
-    fn from(value: DshotCommand) -> Self {
-        match value {
-            DshotCommand::Throttle(v) => {
-                if (0..2000).contains(&v) {
-                    v + 48
-                } else {
-                    unreachable!()
-                }
-            }
-            DshotCommand::Disarm => 0,
-            DshotCommand::Beep1 => 1,
-            DshotCommand::Beep2 => 2,
-            DshotCommand::Beep3 => 3,
-            DshotCommand::Beep4 => 4,
-            DshotCommand::Beep5 => 5,
-            DshotCommand::EscInfo => 6,
-            DshotCommand::SpinDirection1 => 7,
-            DshotCommand::SpinDirection2 => 8,
-            DshotCommand::Disable3DMode => 9,
-            DshotCommand::Enable3DMode => 10,
-            DshotCommand::SettingsRequest => 11,
-            DshotCommand::SaveSettings => 12,
-            DshotCommand::EnableExtendedTelemetry => 13,
-            DshotCommand::DisableExtendedTelemetry => 14,
-            DshotCommand::SpinDirectionNormal => 20,
-            DshotCommand::SpinDirectionReversed => 21,
-            DshotCommand::Led0On => 22,
-            DshotCommand::Led1On => 23,
-            DshotCommand::Led2On => 24,
-            DshotCommand::Led3On => 25,
-            DshotCommand::Led0Off => 26,
-            DshotCommand::Led1Off => 27,
-            DshotCommand::Led2Off => 28,
-            DshotCommand::Led3Off => 29,
-            DshotCommand::AudioStreamModeToggle => 30,
-            DshotCommand::SilentModeToggle => 31,
-            DshotCommand::DisableSignalLineTelemetry => 32,
-            DshotCommand::EnableSignalLineTelemetry => 33,
-            DshotCommand::SignalLineContinuousErpmTelemetry => 34,
-            DshotCommand::SignalLineContinuousErpmPeriodTelemetry => 35,
-            DshotCommand::SignalLineTemperatueTelemetry => 42,
-            DshotCommand::SignalLineVoltageTelemetry => 43,
-            DshotCommand::SignalLineCurrentTelemetry => 44,
-            DshotCommand::SignalLineConsumptionTelemetry => 45,
-            DshotCommand::SignalLineErpmTelemetry => 46,
-            DshotCommand::SignalLineErpmPeriodTelemetry => 47,
-        }
-    }
-}
-
-#[derive(Debug, Format, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-struct DshotFrame {
-    command: DshotCommand,
-    telemetry: bool,
-}
-
-impl From<DshotFrame> for u16 {
-    fn from(value: DshotFrame) -> Self {
-        let value = Self::from(value.command);
-        let value = if value < 48 && value != 0 {
-            (value << 5) | (1 << 4)
-        } else {
-            value << 5
-        };
-        // xor bytes
-        let csum = (value >> 8) ^ value;
-        // xor nibbles
-        let csum = ((csum >> 4) ^ csum) & 0xF;
-        value | csum
-    }
-}
-
-#[derive(Debug, Format, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-pub enum Version {
-    Dshot150,
-    Dshot300,
-    Dshot600,
-    Dshot1200,
-}
-
-impl<const NOM: u32, const DENOM: u32> From<Version> for Rate<u32, NOM, DENOM> {
-    fn from(value: Version) -> Self {
-        match value {
-            Version::Dshot150 => 150u32.kHz(),
-            Version::Dshot300 => 300u32.kHz(),
-            Version::Dshot600 => 600u32.kHz(),
-            Version::Dshot1200 => 1200u32.kHz(),
-        }
-    }
-}
-
-impl<const NOM: u32, const DENOM: u32> From<Version> for Rate<u64, NOM, DENOM> {
-    fn from(value: Version) -> Self {
-        match value {
-            Version::Dshot150 => 150u64.kHz(),
-            Version::Dshot300 => 300u64.kHz(),
-            Version::Dshot600 => 600u64.kHz(),
-            Version::Dshot1200 => 1200u64.kHz(),
-        }
-    }
-}
-
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-enum Telemetry {
-    Erpm(u16),
-    Temperature(u8),
-    Voltage(Volt<FixedU8<U2>>),
-    Current(Ampere<u8>),
-    Debug1(u8),
-    Debug2(u8),
-    Debug3(u8),
-    StateEvent(u8),
-}
+pub use crate::protocol::Version;
 
 /// Marker trait for valid DSHOT modes
 pub trait ValidMode {}
@@ -350,81 +150,6 @@ impl ValidBidirVersion for Version300 {}
 impl ValidBidirVersion for Version600 {}
 impl ValidBidirVersion for Version1200 {}
 
-/// Decodes the 5 LSBs from value using gcr
-const fn decode_gcr(value: u32) -> Option<u8> {
-    match value & 0x1F {
-        0x19 => Some(0x0),
-        0x1B => Some(0x1),
-        0x12 => Some(0x2),
-        0x13 => Some(0x3),
-        0x1D => Some(0x4),
-        0x15 => Some(0x5),
-        0x16 => Some(0x6),
-        0x17 => Some(0x7),
-        0x1A => Some(0x8),
-        0x09 => Some(0x9),
-        0x0A => Some(0xA),
-        0x0B => Some(0xB),
-        0x1E => Some(0xC),
-        0x0D => Some(0xD),
-        0x0E => Some(0xE),
-        0x0F => Some(0xF),
-        _ => None,
-    }
-}
-
-fn decode_telemetry_bits(bits: u32) -> Option<u16> {
-    let bits = bits ^ bits >> 1;
-    let value = u16::from(decode_gcr(bits)?)
-        | u16::from(decode_gcr(bits >> 5)?) << 4
-        | u16::from(decode_gcr(bits >> 10)?) << 8
-        | u16::from(decode_gcr(bits >> 15)?) << 12;
-    let csum = (value >> 8) ^ value;
-    let csum = ((csum >> 4) ^ csum) & 0xF;
-    if csum != 0xF {
-        warn!("checksum failed. got {}", csum);
-        return None;
-    }
-    Some(value >> 4)
-}
-
-/// Decodes a non extended telemetry frame. `bits` contains the 21 received bits.
-fn decode_telemetry(bits: u32) -> Option<u16> {
-    let value = decode_telemetry_bits(bits)?;
-    Some((value & 0x1FF) << ((value & 0xE00) >> 9))
-}
-
-#[allow(dead_code)]
-fn decode_extended_telemetry(bits: u32) -> Option<Telemetry> {
-    let value = decode_telemetry_bits(bits)?;
-    match value & 0xf00 {
-        0x200 => Some(Telemetry::Temperature(
-            (value & 0x0ff).try_into().expect("range is checked"),
-        )),
-        0x400 => Some(Telemetry::Voltage(
-            FixedU8::from_bits((value & 0x0ff).try_into().expect("range is checked")).V(),
-        )),
-        0x600 => Some(Telemetry::Current(
-            TryInto::<u8>::try_into(value & 0x0ff)
-                .expect("range is checked")
-                .A(),
-        )),
-        0x800 => Some(Telemetry::Debug1(
-            (value & 0x0ff).try_into().expect("range is checked"),
-        )),
-        0xA00 => Some(Telemetry::Debug2(
-            (value & 0x0ff).try_into().expect("range is checked"),
-        )),
-        0xC00 => Some(Telemetry::Debug3(
-            (value & 0x0ff).try_into().expect("range is checked"),
-        )),
-        0xE00 => Some(Telemetry::StateEvent(
-            (value & 0x0ff).try_into().expect("range is checked"),
-        )),
-        _ => Some(Telemetry::Erpm((value & 0x1FF) << ((value & 0xE00) >> 9))),
-    }
-}
-
 pub struct Dshot<P, S, I, M, V>
 where
     I: PinId,
@@ -556,55 +281,7 @@ fn install_bidir_pio_program<P>(pio: &mut PIO<P>, pin: u8) -> InstalledProgram<P
 where
     P: PIOExt,
 {
-    const T_START: u8 = 3 * 5;
-    const T_MID: u8 = 3 * 5;
-    const T_STOP: u8 = 2 * 5;
-    const CYCLES_PER_BIT: u8 = T_START + T_MID + T_STOP;
-    const T_RECV: u8 = CYCLES_PER_BIT * 2 / 5;
-    const HIGH: u8 = 0;
-    const LOW: u8 = 1;
-
-    // assemble the bidirectional program
-    let side_set = SideSet::new(false, 1, false);
-    let mut a = Assembler::new_with_side_set(side_set);
-    let mut wrap_target = a.label();
-    let mut wrap_source = a.label();
-    let mut do_zero = a.label();
-    let mut do_start = a.label();
-    let mut do_stop = a.label();
-    let mut receive = a.label();
-    // Set pin direction to output
-    a.bind(&mut wrap_target);
-    a.set_with_side_set(SetDestination::PINDIRS, 1, LOW);
-    // Create 16 bit bit counter
-    a.set_with_side_set(SetDestination::Y, 15, LOW);
-    // Read bit into x
-    a.bind(&mut do_start);
-    a.out_with_side_set(OutDestination::X, 1, LOW);
-    // Do start bit
-    a.jmp_with_delay_and_side_set(JmpCondition::XIsZero, &mut do_zero, T_START - 1, HIGH);
-    // Do data bit = 1
-    a.jmp_with_delay_and_side_set(JmpCondition::Always, &mut do_stop, T_MID - 1, HIGH);
-    // Do data bit = 0
-    a.bind(&mut do_zero);
-    a.nop_with_delay_and_side_set(T_MID - 1, LOW);
-    // Do stop bit and start receiving when all bits are send
-    a.bind(&mut do_stop);
-    a.jmp_with_delay_and_side_set(JmpCondition::YDecNonZero, &mut do_start, T_STOP - 2, LOW);
-    // Create 21 bit bit counter
-    a.set_with_side_set(SetDestination::Y, 20, LOW);
-    // Set pin directions to input
-    a.set_with_side_set(SetDestination::PINDIRS, 0, LOW);
-    // Wait for the first bit and wait half a bit to be in the center
-    a.wait_with_delay_and_side_set(HIGH, WaitSource::GPIO, pin, false, T_RECV - 1, LOW);
-    // Read the bit and delay for half a bit
-    a.bind(&mut receive);
-    a.in_with_delay_and_side_set(InSource::PINS, 1, T_RECV - 1, LOW);
-    // Receive next bit if not all bits where received yet. Delay for another half bit
-    a.jmp_with_delay_and_side_set(JmpCondition::YDecNonZero, &mut receive, T_RECV - 1, LOW);
-    a.bind(&mut wrap_source);
-    let program = a.assemble_with_wrap(wrap_source, wrap_target);
-    pio.install(&program).unwrap()
+    pio.install(&bidir_program(pin)).unwrap()
 }
 
 impl<P, S, I, M, V> Dshot<P, S, I, M, V>
@@ -706,7 +383,7 @@ where
     pub fn enable_telemetry(self) -> Dshot<P, S, I, BidirectionalMode, V> {
         self.change_mode(
             DshotCommand::EnableSignalLineTelemetry,
-            8 * 5,
+            BIDIR_CYCLES_PER_BIT,
             Buffers::RxTx,
             PinState::High,
         )
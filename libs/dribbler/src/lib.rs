@@ -1,5 +1,11 @@
 #![no_std]
+#[cfg(feature = "embassy")]
+pub mod asynch;
+#[cfg(feature = "rp2040-hal")]
 pub mod dshot;
+mod protocol;
+
+pub use protocol::Version;
 
 use embedded_hal::PwmPin;
 
@@ -1,3 +1,6 @@
+use core::f32::consts::TAU;
+use core::ops::{BitAnd, BitOr};
+
 use defmt::Format;
 use serde::{Deserialize, Serialize};
 
@@ -14,6 +17,31 @@ pub enum Main2Motor {
     BallNotInDribbler,
     CalibrateCapVoltage(u8),
     ChargeHint(KickerChargeHint),
+    Configure(MotorConfiguration),
+}
+
+/// A setting of the motorcontroller changed over the air. The motorcontroller saves it.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
+pub enum MotorConfiguration {
+    /// V, the kicker caps are charged to
+    ChargeVoltage(u8),
+    /// m/s², limit of every change of the linear velocity
+    LinearAcceleration(f32),
+    /// rad/s², limit of every change of the angular velocity
+    AngularAcceleration(f32),
+}
+
+/// The limits the motorcontroller applies to every change of the velocity
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
+pub struct MotionLimits {
+    /// m/s²
+    pub linear_acceleration: f32,
+    /// rad/s²
+    pub angular_acceleration: f32,
+    /// m/s³
+    pub linear_jerk: f32,
+    /// rad/s³
+    pub angular_jerk: f32,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
@@ -21,6 +49,14 @@ pub enum Motor2Main {
     MotorVelocity(LocalVelocity),
     // V
     CapVoltage(u8),
+    KickerState(KickerState),
+    KickerFault(KickerFault),
+    /// The errors detected by the motorcontroller. Sent periodically.
+    Errors(ErrorFlags),
+    /// Answers [`Main2Motor::Configure`]. The bool tells if the setting was applied and saved.
+    Configured(MotorConfiguration, bool),
+    /// Sent periodically, so the maincontroller plans with the limits the motorcontroller applies
+    Limits(MotionLimits),
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
@@ -34,7 +70,64 @@ pub struct BasestationToRobot {
     pub dribbler_speed: DribblerSpeedSelection,
     pub robot_position: Option<Position>,
     pub game_state: GameState,
+    /// Time of the basestation when the robot receives this packet
     pub time_sync: Option<TimesyncTimestamp>,
+    /// Changes the saved configuration of the robot
+    pub configuration: Option<ConfigurationMessage>,
+    /// Set if the packet only carries the configuration. The robot keeps its last command and
+    /// game state and ignores the other fields.
+    pub keep_state: bool,
+}
+
+/// Sent until the robot acknowledges its `sequence` in
+/// [`RobotToBasestation::configuration_ack`]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
+pub struct ConfigurationMessage {
+    /// Differs from the sequence of the previous message
+    pub sequence: u8,
+    pub configuration: RobotConfiguration,
+}
+
+/// A setting of a robot changed over the air. The robot saves it like a setting changed on the
+/// robot itself.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
+pub enum RobotConfiguration {
+    /// MHz. The robot switches to the new frequency after acknowledging it.
+    RfFrequency(u16),
+    /// The robot uses the new id after acknowledging it
+    Id(u8),
+    /// m/s, limit of the position controller
+    MaxVelocity(f32),
+    /// rad/s, limit of the position controller
+    MaxAngularVelocity(f32),
+    /// m/s², applied by the motorcontroller. The position controller plans with its limits.
+    MaxAcceleration(f32),
+    /// rad/s², applied by the motorcontroller. The position controller plans with its limits.
+    MaxAngularAcceleration(f32),
+    /// m/s², the position controller plans to brake with
+    MaxBraking(f32),
+    /// rad/s², the position controller plans to brake with
+    MaxAngularBraking(f32),
+    /// 1/s, gain of the position controller
+    PositionGain(f32),
+    /// 1/s, gain of the position controller
+    AngularGain(f32),
+    /// % of the full throttle, used when the dribbler runs at full speed
+    MaxDribblerSpeed(u8),
+    /// V, forwarded to the motorcontroller
+    MaxCapVoltage(u8),
+}
+
+/// Sent once per cycle to all robots using the [`crate::BROADCAST_SYNC_WORD`]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
+pub struct Broadcast {
+    pub game_state: GameState,
+    /// Time of the basestation when the robots receive this packet
+    pub time_sync: Option<TimesyncTimestamp>,
+    /// All robots stop immediately and stay stopped until this is cleared
+    pub emergency_stop: bool,
+    /// Team all robots of this basestation play for. `None` keeps the configured team
+    pub team: Option<Team>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
@@ -46,7 +139,7 @@ pub struct RobotToBasestation {
     /// V
     pub kicker_voltage: u8,
     pub has_ball: BallState,
-    pub error: u8,
+    pub error: ErrorFlags,
     /// A * 8
     pub battery_current: Option<u8>,
     /// mAh / 8
@@ -56,6 +149,17 @@ pub struct RobotToBasestation {
     pub velocity: Option<VelocitySelection>,
     pub position: Option<Position>,
     pub firmware_version: SemVersion,
+    /// rpm measured by the ESC
+    pub dribbler_rpm: Option<u16>,
+    /// probability of a ball in the dribbler in percent
+    pub ball_confidence: u8,
+    /// false if the light barrier disagrees with the dribbler load for too long
+    pub light_barrier_working: bool,
+    /// Time of the basestation when the older of the velocity and the ball state was sampled.
+    /// `None` until the robot received the time of the basestation.
+    pub sample_time: Option<TimesyncTimestamp>,
+    /// [`ConfigurationMessage::sequence`] of the last configuration the robot received
+    pub configuration_ack: Option<u8>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
@@ -85,6 +189,166 @@ pub enum KickerChargeHint {
     DontCare,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Format)]
+pub enum KickerState {
+    /// The charger is off
+    Discharged,
+    /// The charger is charging the caps
+    Charging,
+    /// The caps are charged
+    Ready,
+    /// The charger is off because of a [`KickerFault`]
+    Fault,
+    /// The charger is paused after a kick
+    Cooldown,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Format)]
+pub enum KickerFault {
+    /// The charger reported a fault
+    Charger,
+    /// The charger didn't finish charging in time
+    ChargeTimeout,
+}
+
+impl From<KickerFault> for ErrorFlags {
+    fn from(fault: KickerFault) -> Self {
+        match fault {
+            KickerFault::Charger => Self::KICKER_CHARGER,
+            KickerFault::ChargeTimeout => Self::KICKER_CHARGE_TIMEOUT,
+        }
+    }
+}
+
+/// Errors of a robot. Every bit is one error, so multiple errors can be reported at once.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct ErrorFlags(u8);
+
+impl ErrorFlags {
+    /// A motor driver couldn't be initialized
+    pub const MOTOR_INIT: Self = Self(1 << 0);
+    /// The encoder calibration of a motor failed
+    pub const ENCODER_CALIBRATION: Self = Self(1 << 1);
+    /// The kicker charger reported a fault
+    pub const KICKER_CHARGER: Self = Self(1 << 2);
+    /// The kicker charger didn't finish charging in time
+    pub const KICKER_CHARGE_TIMEOUT: Self = Self(1 << 3);
+    /// The maincontroller didn't receive anything from the motorcontroller for some time
+    pub const MOTORCONTROLLER_LINK: Self = Self(1 << 4);
+    /// The battery is low or critical
+    pub const LOW_BATTERY: Self = Self(1 << 5);
+    /// Receiving a packet from the basestation timed out since the last feedback
+    pub const RF_TIMEOUT: Self = Self(1 << 6);
+    /// The maincontroller or motorcontroller was reset by its watchdog
+    pub const WATCHDOG_RESET: Self = Self(1 << 7);
+
+    pub const MOTORS: Self = Self::MOTOR_INIT.union(Self::ENCODER_CALIBRATION);
+    pub const KICKER: Self = Self::KICKER_CHARGER.union(Self::KICKER_CHARGE_TIMEOUT);
+    pub const COMMUNICATION: Self = Self::MOTORCONTROLLER_LINK.union(Self::RF_TIMEOUT);
+    /// The errors the motorcontroller is responsible for
+    pub const MOTORCONTROLLER: Self = Self::MOTORS.union(Self::KICKER);
+
+    const NAMES: [(Self, &'static str); 8] = [
+        (Self::MOTOR_INIT, "motor init"),
+        (Self::ENCODER_CALIBRATION, "encoder calibration"),
+        (Self::KICKER_CHARGER, "kicker charger"),
+        (Self::KICKER_CHARGE_TIMEOUT, "kicker charge timeout"),
+        (Self::MOTORCONTROLLER_LINK, "motorcontroller link"),
+        (Self::LOW_BATTERY, "low battery"),
+        (Self::RF_TIMEOUT, "rf timeout"),
+        (Self::WATCHDOG_RESET, "watchdog reset"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if all errors of `other` are set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if any error of `other` is set
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    #[must_use]
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    #[must_use]
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Sets the errors of `other` if `value` is `true`, otherwise clears them
+    #[must_use]
+    pub const fn with(self, other: Self, value: bool) -> Self {
+        if value {
+            self.union(other)
+        } else {
+            self.difference(other)
+        }
+    }
+
+    /// The names of all set errors
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .into_iter()
+            .filter(move |(flag, _)| self.contains(*flag))
+            .map(|(_, name)| name)
+    }
+}
+
+impl BitOr for ErrorFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        self.union(rhs)
+    }
+}
+
+impl BitAnd for ErrorFlags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.intersection(rhs)
+    }
+}
+
+impl Format for ErrorFlags {
+    fn format(&self, fmt: defmt::Formatter) {
+        if self.is_empty() {
+            defmt::write!(fmt, "no errors");
+        }
+        for (i, name) in self.names().enumerate() {
+            if i > 0 {
+                defmt::write!(fmt, " | ");
+            }
+            defmt::write!(fmt, "{=str}", name);
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
 pub enum KickSpeedSelection {
     /// mm/s
@@ -93,7 +357,7 @@ pub enum KickSpeedSelection {
     Absolute(u16),
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Format)]
 pub enum KickSelection {
     Kick,
     Chip,
@@ -115,20 +379,60 @@ pub enum DribblerState {
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
 pub struct Position {
-    /// mm/s
+    /// mm
     pub x: i16,
-    /// mm/s
+    /// mm
     pub y: i16,
     /// rad * 2^12
     pub theta: u16,
 }
 
+impl Position {
+    /// Factor between [`Position::theta`] and rad
+    pub const THETA_SCALING: f32 = 4096.0;
+
+    /// Encodes an angle in rad as [`Position::theta`]. The angle is wrapped into the range
+    /// `0..2PI` first, so the scaled value always fits.
+    #[must_use]
+    pub fn theta_from_rad(rad: f32) -> u16 {
+        let mut wrapped = rad % TAU;
+        if wrapped < 0.0 {
+            wrapped += TAU;
+        }
+        (wrapped * Self::THETA_SCALING) as u16
+    }
+
+    /// [`Position::theta`] in rad, in the range `0..2PI`
+    #[must_use]
+    pub fn theta_rad(&self) -> f32 {
+        f32::from(self.theta) / Self::THETA_SCALING
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
 pub struct TimesyncTimestamp {
+    /// s
     pub seconds: u32,
+    /// s * 2^-32
     pub fraction: u32,
 }
 
+impl TimesyncTimestamp {
+    #[must_use]
+    pub const fn from_micros(micros: u64) -> Self {
+        Self {
+            seconds: (micros / 1_000_000) as u32,
+            fraction: (((micros % 1_000_000) << 32) / 1_000_000) as u32,
+        }
+    }
+
+    #[must_use]
+    pub const fn as_micros(&self) -> u64 {
+        // rounded, so converting back and forth keeps the value
+        self.seconds as u64 * 1_000_000 + ((self.fraction as u64 * 1_000_000 + (1 << 31)) >> 32)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Format)]
 pub enum BallState {
     NotInDribbler,
@@ -17,6 +17,7 @@
 //!
 //! All the structs used in postcard communication are defined in `definitions`.
 //! The Basestation sends `BasestationToRobot` structs to the Maincontroller.
+//! Once per cycle it also sends a `Broadcast` to all Maincontrollers at once.
 //! The Maincontroller sends `RobotToBasestation` structs to the Basestation.
 //! The Maincontroller only sends one packet to the basestation after receiving a packet.
 
@@ -25,6 +26,15 @@ pub use konst;
 pub mod definitions;
 pub mod uart;
 
+use definitions::Team;
+
+/// MHz, the frequencies a basestation and the robots can use
+pub const MIN_RF_FREQUENCY: u16 = 2400;
+pub const MAX_RF_FREQUENCY: u16 = 2500;
+
+/// V, the kicker caps are rated for
+pub const MAX_CHARGE_VOLTAGE: u8 = 230;
+
 pub const BASESTATION_SYNC_WORD: u32 = 0x9cd6_040c;
 pub const BROADCAST_SYNC_WORD: u32 = 0xb9d1_6e9c;
 pub const ROBOT_BLUE_SYNC_WORDS: [u32; 16] = [
@@ -64,6 +74,17 @@ pub const ROBOT_YELLOW_SYNC_WORDS: [u32; 16] = [
     0x99fb_1ae9,
 ];
 
+/// Returns the sync word used for the robot with `id` in `team`.
+///
+/// Returns `None` if the id is out of range.
+pub fn robot_sync_word(team: Team, id: u8) -> Option<u32> {
+    let sync_words = match team {
+        Team::Blue => &ROBOT_BLUE_SYNC_WORDS,
+        Team::Yellow => &ROBOT_YELLOW_SYNC_WORDS,
+    };
+    sync_words.get(usize::from(id)).copied()
+}
+
 #[macro_export]
 macro_rules! crate_version {
     () => {
@@ -84,7 +105,18 @@ pub const VERSION: definitions::SemVersion = crate_version!();
 
 #[cfg(test)]
 mod tests {
-    use crate::definitions::SemVersion;
+    use core::f32::consts::{FRAC_PI_2, TAU};
+
+    use crate::definitions::{
+        BallState, BasestationToRobot, Broadcast, CameraVelocity, ConfigurationMessage,
+        DribblerSpeedSelection, ErrorFlags, GameState, KickSelection, KickSpeedSelection,
+        KickerChargeHint, MovementSelection, Position, RobotConfiguration, RobotToBasestation,
+        SemVersion, Team, TimesyncTimestamp, VelocitySelection,
+    };
+    use crate::{
+        robot_sync_word, BASESTATION_SYNC_WORD, BROADCAST_SYNC_WORD, ROBOT_BLUE_SYNC_WORDS,
+        ROBOT_YELLOW_SYNC_WORDS,
+    };
 
     #[test]
     fn version() {
@@ -97,4 +129,238 @@ mod tests {
             }
         )
     }
+
+    #[test]
+    fn robot_sync_word_selects_team() {
+        for id in 0..16 {
+            assert_eq!(
+                robot_sync_word(Team::Blue, id),
+                Some(ROBOT_BLUE_SYNC_WORDS[usize::from(id)])
+            );
+            assert_eq!(
+                robot_sync_word(Team::Yellow, id),
+                Some(ROBOT_YELLOW_SYNC_WORDS[usize::from(id)])
+            );
+        }
+        assert_eq!(robot_sync_word(Team::Blue, 16), None);
+        assert_eq!(robot_sync_word(Team::Yellow, 16), None);
+    }
+
+    #[test]
+    fn same_id_different_team_never_matches() {
+        for id in 0..16 {
+            assert_ne!(
+                robot_sync_word(Team::Blue, id),
+                robot_sync_word(Team::Yellow, id)
+            );
+        }
+    }
+
+    /// Whether a robot receives a packet sent with `sync_word`. The robots configure the radio to
+    /// match sync word 1 or 2 (`SyncWord12`), with their own sync word as sync word 1 and the one
+    /// of the broadcasts as sync word 2.
+    fn robot_receives(team: Team, id: u8, sync_word: u32) -> bool {
+        let sync_word1 = robot_sync_word(team, id).unwrap();
+        let sync_word2 = BROADCAST_SYNC_WORD;
+        sync_word == sync_word1 || sync_word == sync_word2
+    }
+
+    #[test]
+    fn robot_receives_its_packets_and_broadcasts() {
+        for team in [Team::Blue, Team::Yellow] {
+            for id in 0..16 {
+                assert!(robot_receives(team, id, robot_sync_word(team, id).unwrap()));
+                assert!(robot_receives(team, id, BROADCAST_SYNC_WORD));
+                // feedback of the other robots
+                assert!(!robot_receives(team, id, BASESTATION_SYNC_WORD));
+            }
+        }
+    }
+
+    #[test]
+    fn robot_rejects_packets_of_other_robots() {
+        for team in [Team::Blue, Team::Yellow] {
+            for id in 0..16 {
+                for other_team in [Team::Blue, Team::Yellow] {
+                    for other_id in 0..16 {
+                        if (other_team, other_id) == (team, id) {
+                            continue;
+                        }
+                        let sync_word = robot_sync_word(other_team, other_id).unwrap();
+                        assert!(!robot_receives(team, id, sync_word));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sync_words_are_far_apart() {
+        // The radio only hands packets with a matching sync word to the robot. Keep a large
+        // hamming distance between all of them so bit errors can't turn one into another.
+        const MIN_DISTANCE: u32 = 8;
+        let mut sync_words = [0; 34];
+        sync_words[..16].copy_from_slice(&ROBOT_BLUE_SYNC_WORDS);
+        sync_words[16..32].copy_from_slice(&ROBOT_YELLOW_SYNC_WORDS);
+        sync_words[32] = BASESTATION_SYNC_WORD;
+        sync_words[33] = BROADCAST_SYNC_WORD;
+        for (i, a) in sync_words.iter().enumerate() {
+            for b in &sync_words[i + 1..] {
+                assert!(
+                    (a ^ b).count_ones() >= MIN_DISTANCE,
+                    "0x{:08x} and 0x{:08x} are too similar",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn error_flags_are_one_byte() {
+        let mut buffer = [0; 4];
+        let errors = ErrorFlags::KICKER_CHARGER | ErrorFlags::WATCHDOG_RESET;
+        let encoded = postcard::to_slice(&errors, &mut buffer).unwrap();
+        assert_eq!(encoded, &[errors.bits()]);
+        assert_eq!(postcard::from_bytes::<ErrorFlags>(encoded), Ok(errors));
+    }
+
+    #[test]
+    fn every_error_flag_has_a_name() {
+        for bit in 0..8 {
+            assert_eq!(ErrorFlags::from_bits(1 << bit).names().count(), 1);
+        }
+        assert_eq!(ErrorFlags::from_bits(u8::MAX).names().count(), 8);
+        assert_eq!(ErrorFlags::empty().names().count(), 0);
+    }
+
+    #[test]
+    fn error_flags_set_and_clear() {
+        let errors = ErrorFlags::empty()
+            .with(ErrorFlags::KICKER, true)
+            .with(ErrorFlags::KICKER_CHARGER, false);
+        assert_eq!(errors, ErrorFlags::KICKER_CHARGE_TIMEOUT);
+        assert!(errors.intersects(ErrorFlags::KICKER));
+        assert!(!errors.contains(ErrorFlags::KICKER));
+        assert!(!errors.intersects(ErrorFlags::MOTORS));
+    }
+
+    #[test]
+    fn feedback_fits_into_rf_buffer() {
+        // the basestation reads at most 64 bytes including 3 bytes of spi header
+        let mut buffer = [0; 61];
+        let feedback = RobotToBasestation {
+            id: u8::MAX,
+            team: Team::Yellow,
+            battery_voltage: u8::MAX,
+            kicker_voltage: u8::MAX,
+            has_ball: BallState::InDribbler,
+            error: ErrorFlags::from_bits(u8::MAX),
+            battery_current: Some(u8::MAX),
+            battery_capacity_used: Some(u8::MAX),
+            rssi: u8::MAX,
+            velocity: Some(VelocitySelection::CameraVelocity(CameraVelocity {
+                x: i16::MIN,
+                y: i16::MIN,
+                counterclockwise: i16::MIN,
+            })),
+            position: Some(Position {
+                x: i16::MIN,
+                y: i16::MIN,
+                theta: u16::MAX,
+            }),
+            firmware_version: SemVersion {
+                major: u8::MAX,
+                minor: u8::MAX,
+                patch: u8::MAX,
+            },
+            dribbler_rpm: Some(u16::MAX),
+            ball_confidence: u8::MAX,
+            light_barrier_working: true,
+            sample_time: Some(TimesyncTimestamp {
+                seconds: u32::MAX,
+                fraction: u32::MAX,
+            }),
+            configuration_ack: Some(u8::MAX),
+        };
+        assert!(postcard::to_slice(&feedback, &mut buffer).is_ok());
+    }
+
+    #[test]
+    fn command_fits_into_rf_buffer() {
+        // the robot reads at most 64 bytes including 3 bytes of spi header
+        let mut buffer = [0; 61];
+        let command = BasestationToRobot {
+            id: u8::MAX,
+            team: Team::Yellow,
+            movement: MovementSelection::CameraVelocity(CameraVelocity {
+                x: i16::MIN,
+                y: i16::MIN,
+                counterclockwise: i16::MIN,
+            }),
+            kicker_charge_hint: KickerChargeHint::DontCare,
+            kick_speed: KickSpeedSelection::Absolute(u16::MAX),
+            kick_type: KickSelection::Chip,
+            dribbler_speed: DribblerSpeedSelection::Rpm(u16::MAX),
+            robot_position: Some(Position {
+                x: i16::MIN,
+                y: i16::MIN,
+                theta: u16::MAX,
+            }),
+            game_state: GameState::Normal,
+            time_sync: Some(TimesyncTimestamp {
+                seconds: u32::MAX,
+                fraction: u32::MAX,
+            }),
+            configuration: Some(ConfigurationMessage {
+                sequence: u8::MAX,
+                configuration: RobotConfiguration::MaxAngularAcceleration(f32::MAX),
+            }),
+            keep_state: false,
+        };
+        assert!(postcard::to_slice(&command, &mut buffer).is_ok());
+    }
+
+    #[test]
+    fn broadcast_fits_into_rf_buffer() {
+        // the robot reads at most 64 bytes including 3 bytes of spi header
+        let mut buffer = [0; 61];
+        let broadcast = Broadcast {
+            game_state: GameState::Normal,
+            time_sync: Some(TimesyncTimestamp {
+                seconds: u32::MAX,
+                fraction: u32::MAX,
+            }),
+            emergency_stop: true,
+            team: Some(Team::Yellow),
+        };
+        assert!(postcard::to_slice(&broadcast, &mut buffer).is_ok());
+    }
+
+    #[test]
+    fn timestamp_from_micros() {
+        let timestamp = TimesyncTimestamp::from_micros(1_500_000);
+        assert_eq!(timestamp.seconds, 1);
+        assert_eq!(timestamp.fraction, 1 << 31);
+        for micros in [0, 1, 999_999, 1_000_001, 4_294_967_295_999_999] {
+            assert_eq!(TimesyncTimestamp::from_micros(micros).as_micros(), micros);
+        }
+    }
+
+    #[test]
+    fn theta_encoding() {
+        assert_eq!(Position::theta_from_rad(0.0), 0);
+        assert_eq!(Position::theta_from_rad(1.0), 4096);
+        assert_eq!(Position::theta_from_rad(FRAC_PI_2), 6433);
+        assert_eq!(Position::theta_from_rad(-FRAC_PI_2), 19301);
+        assert_eq!(Position::theta_from_rad(TAU + 1.0), 4096);
+        for rad in [0.0, 0.5, FRAC_PI_2, 3.0, 6.0] {
+            let position = Position {
+                x: 0,
+                y: 0,
+                theta: Position::theta_from_rad(rad),
+            };
+            assert!((position.theta_rad() - rad).abs() < 1.0 / 4096.0);
+        }
+    }
 }
@@ -14,7 +14,10 @@ use postcard::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::definitions::{KickerChargeHint, LocalVelocity, Main2Motor, Motor2Main};
+use crate::definitions::{
+    ErrorFlags, KickerChargeHint, KickerFault, KickerState, LocalVelocity, Main2Motor,
+    MotionLimits, Motor2Main, MotorConfiguration,
+};
 
 pub struct MotorControllerSender<Tx>
 where
@@ -68,6 +71,15 @@ where
             .send::<8>(&Main2Motor::CalibrateCapVoltage(value))
             .await
     }
+
+    pub async fn configure(
+        &mut self,
+        configuration: MotorConfiguration,
+    ) -> Result<(), SendError<Tx>> {
+        self.sender
+            .send::<16>(&Main2Motor::Configure(configuration))
+            .await
+    }
 }
 
 pub struct MainControllerSender<Tx>
@@ -98,6 +110,32 @@ where
             .send::<8>(&Motor2Main::CapVoltage(voltage))
             .await
     }
+
+    pub async fn kicker_state(&mut self, state: KickerState) -> Result<(), SendError<Tx>> {
+        self.sender.send::<8>(&Motor2Main::KickerState(state)).await
+    }
+
+    pub async fn kicker_fault(&mut self, fault: KickerFault) -> Result<(), SendError<Tx>> {
+        self.sender.send::<8>(&Motor2Main::KickerFault(fault)).await
+    }
+
+    pub async fn errors(&mut self, errors: ErrorFlags) -> Result<(), SendError<Tx>> {
+        self.sender.send::<8>(&Motor2Main::Errors(errors)).await
+    }
+
+    pub async fn configured(
+        &mut self,
+        configuration: MotorConfiguration,
+        applied: bool,
+    ) -> Result<(), SendError<Tx>> {
+        self.sender
+            .send::<16>(&Motor2Main::Configured(configuration, applied))
+            .await
+    }
+
+    pub async fn limits(&mut self, limits: MotionLimits) -> Result<(), SendError<Tx>> {
+        self.sender.send::<24>(&Motor2Main::Limits(limits)).await
+    }
 }
 
 pub struct MotorControllerReceiver<Tx>
@@ -118,7 +156,8 @@ where
     }
 
     pub async fn receive(&mut self) -> Result<Motor2Main, ReceiveError<Tx>> {
-        let mut buf = [0; 16];
+        // fits the motion limits
+        let mut buf = [0; 24];
         self.receiver.receive(&mut buf).await
     }
 }
@@ -7,10 +7,13 @@ fn main() -> Result<(), std::io::Error> {
     compile_protos(
         &[
             "files/luhsoccer/luhsoccer_basestation.proto",
+            "files/luhsoccer/luhsoccer_robot_interface.proto",
             "files/ssl_vision/ssl_vision_wrapper.proto",
+            "files/ssl_gc/ssl_gc_referee_message.proto",
         ],
         &[
             "files/luhsoccer",
+            "files/ssl_gc",
             "files/ssl_simulation",
             "files/ssl_vision",
         ],
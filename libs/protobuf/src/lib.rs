@@ -1,9 +1,19 @@
 #![no_std]
-pub mod proto {
-    pub mod luhsoccer {
-        include!(concat!(env!("OUT_DIR"), "/luhsoccer.proto.basestation.rs"));
-    }
-    pub mod ssl_vision {
-        include!(concat!(env!("OUT_DIR"), "/luhsoccer.proto.ssl_vision.rs"));
+//! The modules mirror the protobuf packages, as the generated code refers to messages of other
+//! packages relative to its own package. The game controller and simulation protos don't declare
+//! a package, so they are part of the crate root.
+
+include!(concat!(env!("OUT_DIR"), "/_.rs"));
+
+pub mod luhsoccer {
+    pub mod proto {
+        include!(concat!(env!("OUT_DIR"), "/luhsoccer.proto.rs"));
+
+        pub mod basestation {
+            include!(concat!(env!("OUT_DIR"), "/luhsoccer.proto.basestation.rs"));
+        }
+        pub mod ssl_vision {
+            include!(concat!(env!("OUT_DIR"), "/luhsoccer.proto.ssl_vision.rs"));
+        }
     }
 }
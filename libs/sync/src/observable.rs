@@ -55,6 +55,23 @@ impl<M: RawMutex, T, const SUBS: usize> Observable<M, T, SUBS> {
         })
     }
 
+    /// Replaces the value with the result of `f` while holding the lock. Subscribers are only
+    /// notified if the value changed.
+    pub fn update(&self, f: impl FnOnce(&T) -> T)
+    where
+        T: PartialEq,
+    {
+        self.inner.lock(|cell| {
+            let mut inner = cell.borrow_mut();
+            let value = f(&inner.value);
+            if inner.value != value {
+                inner.value = value;
+                inner.id += 1;
+                inner.wakers.wake();
+            }
+        })
+    }
+
     pub fn subscriber(&self) -> Result<Subscriber<'_, M, T, SUBS>, Error> {
         self.inner.lock(|cell| {
             let mut inner = cell.borrow_mut();
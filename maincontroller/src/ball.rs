@@ -0,0 +1,111 @@
+use control::ball::{BallFusion, RpmBallDetector};
+use defmt::{info, warn, Format};
+use embassy_executor::task;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
+use embassy_time::{Duration, Instant, Ticker};
+use sync::observable::Observable;
+
+use crate::{dribbler::DribblerTelemetry, lightbarrier::LightBarrierState, DribblerSpeed};
+
+const UPDATE_RATE: u64 = 100;
+/// Probability of a ball while the light barrier lost contact to it. It's below the threshold of
+/// [`BallDetection::has_ball`], so the dribbler has to confirm the ball to keep the kicker armed.
+const CONTACT_LOST_CONFIDENCE: f32 = 0.4;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Format)]
+pub struct BallDetection {
+    pub light_barrier: LightBarrierState,
+    /// Probability of a ball in the dribbler in percent
+    pub confidence: u8,
+    pub light_barrier_suspected_broken: bool,
+    /// When the ball state was sampled
+    pub time: Instant,
+}
+
+impl BallDetection {
+    pub const fn new() -> Self {
+        Self {
+            light_barrier: LightBarrierState::NoBall,
+            confidence: 0,
+            light_barrier_suspected_broken: false,
+            time: Instant::from_ticks(0),
+        }
+    }
+
+    pub const fn has_ball(&self) -> bool {
+        self.confidence >= 50
+    }
+}
+
+#[task]
+pub async fn ball_task(
+    light_barrier: &'static Observable<CriticalSectionRawMutex, LightBarrierState, 8>,
+    dribbler_speed: &'static Observable<CriticalSectionRawMutex, DribblerSpeed, 8>,
+    dribbler_telemetry: &'static Observable<CriticalSectionRawMutex, DribblerTelemetry, 8>,
+    has_ball: &'static Observable<CriticalSectionRawMutex, BallDetection, 8>,
+) {
+    ball_detection(light_barrier, dribbler_speed, dribbler_telemetry, has_ball).await;
+}
+
+async fn ball_detection<
+    const SUBS1: usize,
+    const SUBS2: usize,
+    const SUBS3: usize,
+    const SUBS4: usize,
+>(
+    light_barrier: &Observable<impl RawMutex, LightBarrierState, SUBS1>,
+    dribbler_speed: &Observable<impl RawMutex, DribblerSpeed, SUBS2>,
+    dribbler_telemetry: &Observable<impl RawMutex, DribblerTelemetry, SUBS3>,
+    has_ball: &Observable<impl RawMutex, BallDetection, SUBS4>,
+) {
+    const DT: f32 = 1.0 / UPDATE_RATE as f32;
+
+    let mut detector = RpmBallDetector::new();
+    let mut fusion = BallFusion::new();
+    let mut last_speed = dribbler_speed.get();
+    let mut ticker = Ticker::every(Duration::from_hz(UPDATE_RATE));
+    loop {
+        ticker.next().await;
+
+        let speed = dribbler_speed.get();
+        if speed != last_speed {
+            // the free running speed needs to be learned again
+            detector.reset();
+            last_speed = speed;
+        }
+        let light_barrier = light_barrier.get();
+        let telemetry = dribbler_telemetry.get();
+        let rpm_estimate = detector.update(
+            telemetry.rpm,
+            telemetry.throttle,
+            light_barrier != LightBarrierState::NoBall,
+            DT,
+        );
+
+        let light_barrier_estimate = match light_barrier {
+            LightBarrierState::HasBall => 1.0,
+            LightBarrierState::ContactLost => CONTACT_LOST_CONFIDENCE,
+            LightBarrierState::NoBall => 0.0,
+        };
+        let estimate = fusion.update(light_barrier_estimate, rpm_estimate, DT);
+
+        let detection = BallDetection {
+            light_barrier,
+            confidence: (estimate.confidence * 100.0) as u8,
+            light_barrier_suspected_broken: estimate.light_barrier_suspected_broken,
+            time: Instant::now(),
+        };
+        let last = has_ball.get();
+        if detection.light_barrier_suspected_broken != last.light_barrier_suspected_broken {
+            if detection.light_barrier_suspected_broken {
+                warn!("light barrier disagrees with the dribbler. Suspecting it to be broken");
+            } else {
+                info!("light barrier agrees with the dribbler again");
+            }
+        }
+        if detection.has_ball() != last.has_ball() {
+            info!("ball detection changed to {}", detection);
+        }
+        has_ball.set_if_different(detection);
+    }
+}
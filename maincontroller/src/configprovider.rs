@@ -10,6 +10,7 @@ use embassy_sync::{
     signal::Signal,
 };
 use embassy_time::{Duration, Timer};
+use intra_comms::definitions::Team;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sync::observable::{Observable, Subscriber};
 
@@ -91,15 +92,96 @@ impl<M: RawMutex> Default for ConfigV0<M> {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct ConfigV1<M: RawMutex> {
+    pub rf_frequency: Parameter<M, u32, 1>,
+    pub id: Parameter<M, u8, 1>,
+    pub dribbler_low: Parameter<M, u16, 1>,
+    pub dribbler_high: Parameter<M, u16, 1>,
+    pub lightbarrier_filter_time: Parameter<M, u32, 1>,
+    pub team: Parameter<M, Team, 1>,
+    pub position_linear_gain: Parameter<M, f32, 1>,
+    pub position_angular_gain: Parameter<M, f32, 1>,
+    /// The position controller plans with these limits and with the accelleration and jerk
+    /// limits the motorcontroller reports, which it applies to every velocity.
+    pub position_max_velocity: Parameter<M, f32, 1>,
+    pub position_max_angular_velocity: Parameter<M, f32, 1>,
+    pub position_max_braking: Parameter<M, f32, 1>,
+    pub position_max_angular_braking: Parameter<M, f32, 1>,
+    pub dribbler_pole_pairs: Parameter<M, u8, 1>,
+    pub dribbler_p_gain: Parameter<M, f32, 1>,
+    pub dribbler_i_gain: Parameter<M, f32, 1>,
+    pub stop_max_velocity: Parameter<M, f32, 1>,
+}
+
+impl<M: RawMutex> ConfigV1<M> {
+    pub const fn new() -> Self {
+        Self {
+            rf_frequency: Parameter::new(2_400),
+            id: Parameter::new(0),
+            dribbler_low: Parameter::new(u16::MAX / 20), // 5%
+            dribbler_high: Parameter::new(u16::MAX / 10), // 10%
+            lightbarrier_filter_time: Parameter::new(200), // ms
+            team: Parameter::new(Team::Blue),
+            position_linear_gain: Parameter::new(4.0), // 1/s
+            position_angular_gain: Parameter::new(6.0), // 1/s
+            position_max_velocity: Parameter::new(2.0), // m/s
+            position_max_angular_velocity: Parameter::new(6.0), // rad/s
+            position_max_braking: Parameter::new(3.0), // m/s²
+            position_max_angular_braking: Parameter::new(20.0), // rad/s²
+            dribbler_pole_pairs: Parameter::new(7),
+            dribbler_p_gain: Parameter::new(0.05), // throttle / rpm
+            dribbler_i_gain: Parameter::new(0.002), // throttle / (rpm * ms)
+            // m/s. The rules allow 1.5 m/s during STOP
+            stop_max_velocity: Parameter::new(1.3),
+        }
+    }
+}
+
+impl<M: RawMutex> Default for ConfigV1<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex> From<ConfigV0<M>> for ConfigV1<M> {
+    fn from(config: ConfigV0<M>) -> Self {
+        Self {
+            rf_frequency: config.rf_frequency,
+            id: config.id,
+            dribbler_low: config.dribbler_low,
+            dribbler_high: config.dribbler_high,
+            lightbarrier_filter_time: config.lightbarrier_filter_time,
+            ..Self::new()
+        }
+    }
+}
+
+/// New versions are only appended, so older records can still be decoded
 #[derive(Serialize, Deserialize)]
 #[serde(bound = "")]
 enum ConfigSelection<M: RawMutex> {
     V0(ConfigV0<M>),
+    V1(ConfigV1<M>),
+}
+
+impl<M: RawMutex> ConfigSelection<M> {
+    fn is_latest(&self) -> bool {
+        matches!(self, Self::V1(_))
+    }
+
+    fn into_latest(self) -> ConfigV1<M> {
+        match self {
+            Self::V0(config) => config.into(),
+            Self::V1(config) => config,
+        }
+    }
 }
 
 impl<M: RawMutex> Default for ConfigSelection<M> {
     fn default() -> Self {
-        Self::V0(ConfigV0::default())
+        Self::V1(ConfigV1::default())
     }
 }
 
@@ -155,15 +237,7 @@ impl<M: RawMutex> DiscConfig<M> {
         };
 
         if config.valid() {
-            match config.config {
-                ConfigSelection::V0(configv0) => {
-                    configv0.id.set(configv0.id.get().clamp(0, 15));
-                    Some(Self {
-                        config: ConfigSelection::V0(configv0),
-                        checksum: config.checksum,
-                    })
-                }
-            }
+            Some(config)
         } else {
             error!("Loaded config is not valid! Using default config");
             None
@@ -221,12 +295,19 @@ async fn config_inner<
 ) {
     if let Some(disc_config) = DiscConfig::<NoopRawMutex>::load_from_flash(&mut flash) {
         info!("Successfully loaded config");
-        update_config(config, &disc_config.config);
+        let outdated = !disc_config.config.is_latest();
+        let loaded = disc_config.config.into_latest();
+        loaded.id.set(loaded.id.get().clamp(0, 15));
+        update_config(config, &loaded);
+        if outdated {
+            info!("Migrating config to the latest version");
+            save.signal(());
+        }
     }
     loop {
         save.wait().await;
         let temp_config = clone_config::<NoopRawMutex>(config);
-        let disc_config = DiscConfig::new(ConfigSelection::V0(temp_config));
+        let disc_config = DiscConfig::new(ConfigSelection::V1(temp_config));
         assert!(disc_config.valid());
         disc_config.save_to_flash(&mut flash);
     }
@@ -240,22 +321,58 @@ fn clone_config<M: RawMutex>(config: &crate::Config<impl RawMutex>) -> crate::Co
     res.dribbler_high.set(config.dribbler_high.get());
     res.lightbarrier_filter_time
         .set(config.lightbarrier_filter_time.get());
+    res.team.set(config.team.get());
+    res.position_linear_gain
+        .set(config.position_linear_gain.get());
+    res.position_angular_gain
+        .set(config.position_angular_gain.get());
+    res.position_max_velocity
+        .set(config.position_max_velocity.get());
+    res.position_max_angular_velocity
+        .set(config.position_max_angular_velocity.get());
+    res.position_max_braking
+        .set(config.position_max_braking.get());
+    res.position_max_angular_braking
+        .set(config.position_max_angular_braking.get());
+    res.dribbler_pole_pairs
+        .set(config.dribbler_pole_pairs.get());
+    res.dribbler_p_gain.set(config.dribbler_p_gain.get());
+    res.dribbler_i_gain.set(config.dribbler_i_gain.get());
+    res.stop_max_velocity.set(config.stop_max_velocity.get());
     res
 }
 
-fn update_config(
-    config: &crate::Config<impl RawMutex>,
-    disc_config: &ConfigSelection<impl RawMutex>,
-) {
-    match disc_config {
-        ConfigSelection::V0(config_v0) => {
-            config.id.set(config_v0.id.get());
-            config.rf_frequency.set(config_v0.rf_frequency.get());
-            config.dribbler_low.set(config_v0.dribbler_low.get());
-            config.dribbler_high.set(config_v0.dribbler_high.get());
-            config
-                .lightbarrier_filter_time
-                .set(config_v0.lightbarrier_filter_time.get());
-        }
-    }
+fn update_config(config: &crate::Config<impl RawMutex>, loaded: &ConfigV1<impl RawMutex>) {
+    config.id.set(loaded.id.get());
+    config.rf_frequency.set(loaded.rf_frequency.get());
+    config.dribbler_low.set(loaded.dribbler_low.get());
+    config.dribbler_high.set(loaded.dribbler_high.get());
+    config
+        .lightbarrier_filter_time
+        .set(loaded.lightbarrier_filter_time.get());
+    config.team.set(loaded.team.get());
+    config
+        .position_linear_gain
+        .set(loaded.position_linear_gain.get());
+    config
+        .position_angular_gain
+        .set(loaded.position_angular_gain.get());
+    config
+        .position_max_velocity
+        .set(loaded.position_max_velocity.get());
+    config
+        .position_max_angular_velocity
+        .set(loaded.position_max_angular_velocity.get());
+    config
+        .position_max_braking
+        .set(loaded.position_max_braking.get());
+    config
+        .position_max_angular_braking
+        .set(loaded.position_max_angular_braking.get());
+    config
+        .dribbler_pole_pairs
+        .set(loaded.dribbler_pole_pairs.get());
+    config.dribbler_p_gain.set(loaded.dribbler_p_gain.get());
+    config.dribbler_i_gain.set(loaded.dribbler_i_gain.get());
+    config.stop_max_velocity.set(loaded.stop_max_velocity.get());
 }
@@ -1,88 +1,136 @@
-use defmt::{info, unwrap};
+use control::dribbler::{erpm_period_to_rpm, RpmController, MAX_THROTTLE};
+use defmt::{info, unwrap, warn, Format};
+use dribbler::{asynch::PioDshot, Version};
 use embassy_executor::task;
+use embassy_futures::select::{select, Either};
 use embassy_rp::{
-    peripherals::{PIN_20, PWM_CH2},
-    pwm::{Channel, Config, Pwm},
+    peripherals::{PIN_20, PIO1},
+    pio::{Common, Instance, StateMachine},
 };
 use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
 #[cfg(feature = "test_dribbler")]
-use embassy_time::{Duration, Timer};
-use fixed::types::U12F4;
+use embassy_time::Timer;
+use embassy_time::{Duration, Ticker};
 use sync::observable::Observable;
 
+use crate::{Config, DribblerSpeed};
+
+/// Period of the RPM control. A DSHOT frame is sent to the ESC every period.
+const CONTROL_PERIOD: Duration = Duration::from_millis(1);
+/// The ESC only arms after receiving disarm frames for a while
+const ARM_DURATION: Duration = Duration::from_millis(500);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Format)]
+pub struct DribblerTelemetry {
+    /// rpm measured by the ESC. `None` if the ESC doesn't answer
+    pub rpm: Option<u16>,
+    /// throttle sent to the ESC in 0..=1999
+    pub throttle: u16,
+}
+
 #[task]
 pub async fn dribbler_task(
     pin: PIN_20,
-    pwm: PWM_CH2,
-    command_speed: &'static Observable<CriticalSectionRawMutex, u16, 8>,
+    mut pio: Common<'static, PIO1>,
+    sm: StateMachine<'static, PIO1, 0>,
+    config: &'static Config<CriticalSectionRawMutex>,
+    command_speed: &'static Observable<CriticalSectionRawMutex, DribblerSpeed, 8>,
+    telemetry: &'static Observable<CriticalSectionRawMutex, DribblerTelemetry, 8>,
 ) {
-    let pwm = Pwm::new_output_a(pwm, pin, Config::default());
+    let dshot = PioDshot::new(sm, &mut pio, pin, Version::Dshot300);
 
-    dribbler(pwm, command_speed).await;
+    dribbler(dshot, config, command_speed, telemetry).await;
 }
 
-const fn u16_to_dutycycle<const MIN: u16, const MAX: u16>(value: u16) -> u16 {
-    ((value - u16::MIN) as u32 * (MAX - MIN) as u32 / (u16::MAX - u16::MIN) as u32 + (MIN) as u32)
-        as u16
-}
-
-async fn dribbler<const SUBS: usize>(
-    mut pwm: Pwm<'_, impl Channel>,
-    command_speed: &Observable<impl RawMutex, u16, SUBS>,
+async fn dribbler<const SM: usize, const SUBS1: usize, const SUBS2: usize>(
+    mut dshot: PioDshot<'_, impl Instance, SM>,
+    config: &Config<impl RawMutex>,
+    command_speed: &Observable<impl RawMutex, DribblerSpeed, SUBS1>,
+    telemetry: &Observable<impl RawMutex, DribblerTelemetry, SUBS2>,
 ) {
-    const PWM_FREQUENCY: u32 = 500;
-    const SYSTEM_FREQUENCY: u32 = 125_000_000;
-    const DIVIDER: u32 = 4;
-    const TOP: u16 = (SYSTEM_FREQUENCY / (DIVIDER * PWM_FREQUENCY)) as u16;
-    const MIN_DUTY_CYCLE: u16 = TOP / 2;
-    const MAX_DUTY_CYCLE: u16 = TOP;
-
-    let mut config = Config::default();
-    config.divider = U12F4::from_num(DIVIDER);
-    config.compare_a = MIN_DUTY_CYCLE;
-    config.top = TOP;
-    pwm.set_config(&config);
-
     let mut command_speed_sub = unwrap!(command_speed.subscriber());
+    let mut controller =
+        RpmController::new(config.dribbler_p_gain.get(), config.dribbler_i_gain.get());
+    let mut speed = DribblerSpeed::Throttle(0);
+    let mut ticker = Ticker::every(CONTROL_PERIOD);
+
+    for _ in 0..(ARM_DURATION.as_ticks() / CONTROL_PERIOD.as_ticks()) {
+        dshot.disarm();
+        ticker.next().await;
+    }
 
     loop {
-        let new_speed = command_speed_sub.next_value().await;
-        config.compare_a = u16_to_dutycycle::<MIN_DUTY_CYCLE, MAX_DUTY_CYCLE>(new_speed);
-        pwm.set_config(&config);
-        info!(
-            "set new dribbler_speed to {}%",
-            u16_to_dutycycle::<0, 100>(new_speed)
-        );
+        match select(command_speed_sub.next_value(), ticker.next()).await {
+            Either::First(new_speed) => {
+                info!("set new dribbler speed to {}", new_speed);
+                controller.set_target(match new_speed {
+                    DribblerSpeed::Rpm(rpm) => rpm,
+                    DribblerSpeed::Throttle(_) => 0,
+                });
+                speed = new_speed;
+            }
+            Either::Second(()) => {
+                let measured_rpm = dshot
+                    .erpm_period()
+                    .map(|period| erpm_period_to_rpm(period, config.dribbler_pole_pairs.get()));
+                if measured_rpm.is_none() && telemetry.get().rpm.is_some() {
+                    warn!("lost dribbler telemetry");
+                }
+
+                let throttle = match (speed, measured_rpm) {
+                    (DribblerSpeed::Throttle(speed), _) => unwrap!(
+                        u16::try_from(
+                            u32::from(speed) * u32::from(MAX_THROTTLE) / u32::from(u16::MAX)
+                        ),
+                        "scaled to MAX_THROTTLE"
+                    ),
+                    (DribblerSpeed::Rpm(_), Some(measured_rpm)) => {
+                        controller
+                            .set_gains(config.dribbler_p_gain.get(), config.dribbler_i_gain.get());
+                        controller.regulate(measured_rpm)
+                    }
+                    // the loop can't be closed without telemetry
+                    (DribblerSpeed::Rpm(_), None) => 0,
+                };
+                dshot.throttle(throttle);
+                telemetry.set_if_different(DribblerTelemetry {
+                    rpm: measured_rpm,
+                    throttle,
+                });
+            }
+        }
     }
 }
 
 #[cfg(feature = "test_dribbler")]
 #[task]
 pub async fn dribbler_test_task(
-    speed_signal: &'static Observable<CriticalSectionRawMutex, u16, 8>,
+    speed_signal: &'static Observable<CriticalSectionRawMutex, DribblerSpeed, 8>,
 ) {
     test_dribbler(speed_signal).await;
 }
 
 #[cfg(feature = "test_dribbler")]
-pub async fn test_dribbler<const SUBS: usize>(speed: &Observable<impl RawMutex, u16, SUBS>) {
+pub async fn test_dribbler<const SUBS: usize>(
+    speed: &Observable<impl RawMutex, DribblerSpeed, SUBS>,
+) {
     const MAX: u16 = u16::MAX / 5;
     Timer::after(Duration::from_secs(5)).await;
     loop {
-        speed.set(0);
+        speed.set(DribblerSpeed::Throttle(0));
         Timer::after(Duration::from_secs(5)).await;
         for i in (0..=MAX).step_by(usize::from(MAX) / 16) {
-            speed.set(i);
+            speed.set(DribblerSpeed::Throttle(i));
             Timer::after(Duration::from_secs(1)).await;
         }
         for i in (0..=MAX).step_by(usize::from(MAX) / 16).rev() {
-            speed.set(i);
+            speed.set(DribblerSpeed::Throttle(i));
             Timer::after(Duration::from_secs(1)).await;
         }
         for _ in 0..10 {
-            speed.set(MAX);
+            speed.set(DribblerSpeed::Throttle(MAX));
             Timer::after(Duration::from_secs(1)).await;
-            speed.set(0);
+            speed.set(DribblerSpeed::Throttle(0));
             Timer::after(Duration::from_secs(1)).await;
         }
         Timer::after(Duration::from_secs(10)).await;
@@ -0,0 +1,51 @@
+use control::frame::{position_heading, HeadingEstimator};
+use defmt::{error, info};
+use embassy_executor::task;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
+use embassy_time::{Duration, Instant, Ticker};
+use intra_comms::definitions::{LocalVelocity, Position};
+use sync::observable::Observable;
+
+/// Rate at which the measured angular velocity is integrated
+const UPDATE_RATE: u64 = 200;
+/// Weight of a heading received from the vision
+const VISION_GAIN: f32 = 0.1;
+
+#[task]
+pub async fn heading_task(
+    actual_velocity: &'static Observable<CriticalSectionRawMutex, (LocalVelocity, Instant), 8>,
+    robot_position: &'static Observable<CriticalSectionRawMutex, Option<Position>, 8>,
+    heading: &'static Observable<CriticalSectionRawMutex, Option<f32>, 8>,
+) {
+    heading_estimation(actual_velocity, robot_position, heading).await;
+}
+
+async fn heading_estimation<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
+    actual_velocity: &Observable<impl RawMutex, (LocalVelocity, Instant), SUBS1>,
+    robot_position: &Observable<impl RawMutex, Option<Position>, SUBS2>,
+    heading: &Observable<impl RawMutex, Option<f32>, SUBS3>,
+) {
+    let Ok(mut position_subscriber) = robot_position.subscriber() else {error!("couldn't get position subscriber"); return;};
+    let mut estimator = HeadingEstimator::new(VISION_GAIN);
+    let mut ticker = Ticker::every(Duration::from_hz(UPDATE_RATE));
+    let mut last_update = Instant::now();
+    loop {
+        match select(ticker.next(), position_subscriber.next_value()).await {
+            Either::First(()) => {
+                let now = Instant::now();
+                let dt = (now - last_update).as_micros() as f32 / 1_000_000.0;
+                last_update = now;
+                estimator.predict(actual_velocity.get().0.counterclockwise, dt);
+            }
+            Either::Second(Some(position)) => {
+                if estimator.heading().is_none() {
+                    info!("received first heading from vision");
+                }
+                estimator.correct(position_heading(&position));
+            }
+            Either::Second(None) => (),
+        }
+        heading.set_if_different(estimator.heading());
+    }
+}
@@ -2,11 +2,14 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
+mod ball;
 mod buzzer;
 mod configprovider;
 mod dribbler;
+mod heading;
 mod lightbarrier;
 mod motorcontroller;
+mod position;
 mod power;
 mod rf;
 mod ui;
@@ -24,9 +27,14 @@ use embassy_rp::{
     pio::Pio,
     uart,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex, signal::Signal,
+};
+use embassy_time::Instant;
 use fixed::types::U16F16;
-use intra_comms::definitions::LocalVelocity;
+use intra_comms::definitions::{
+    ErrorFlags, GameState, KickSelection, LocalVelocity, MotionLimits, MotorConfiguration, Position,
+};
 use panic_probe as _;
 use power::BatteryState;
 use static_cell::StaticCell;
@@ -35,11 +43,14 @@ use sync::observable::Observable;
 #[cfg(feature = "test_dribbler")]
 use crate::dribbler::dribbler_test_task;
 use crate::{
+    ball::{ball_task, BallDetection},
     buzzer::buzzer_task,
-    configprovider::{config_task, ConfigV0 as Config},
-    dribbler::dribbler_task,
+    configprovider::{config_task, ConfigV1 as Config},
+    dribbler::{dribbler_task, DribblerTelemetry},
+    heading::heading_task,
     lightbarrier::lightbarrier_task,
     motorcontroller::motorcontroller_task,
+    position::position_task,
     power::{measure_task, power_switch_task},
     rf::rf_task,
     ui::ui_task,
@@ -84,18 +95,36 @@ impl<I: interrupt::Interrupt> Handler<I> for ExecutorInterruptHandler {
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Format)]
 pub enum KickSpeed {
-    Velocity(u16),
+    /// mm/s
+    Velocity(KickSelection, u16),
+    /// us
     Raw(u16),
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Format)]
+pub enum DribblerSpeed {
+    /// u16::MAX is full throttle
+    Throttle(u16),
+    /// rpm
+    Rpm(u16),
+}
+
 #[entry]
 fn main() -> ! {
     static EXECUTOR_LOW: StaticCell<Executor> = StaticCell::new();
     static SHUTDOWN_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
     static SAVE_CONFIG_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
-    static DRIBBLER_SPEED: Observable<CriticalSectionRawMutex, u16, 8> = Observable::new(0);
-    static HAS_BALL: Observable<CriticalSectionRawMutex, lightbarrier::LightBarrierState, 8> =
+    static DRIBBLER_SPEED: Observable<CriticalSectionRawMutex, DribblerSpeed, 8> =
+        Observable::new(DribblerSpeed::Throttle(0));
+    static DRIBBLER_TELEMETRY: Observable<CriticalSectionRawMutex, DribblerTelemetry, 8> =
+        Observable::new(DribblerTelemetry {
+            rpm: None,
+            throttle: 0,
+        });
+    static LIGHT_BARRIER: Observable<CriticalSectionRawMutex, lightbarrier::LightBarrierState, 8> =
         Observable::new(lightbarrier::LightBarrierState::NoBall);
+    static HAS_BALL: Observable<CriticalSectionRawMutex, BallDetection, 8> =
+        Observable::new(BallDetection::new());
     static VOLTAGE_STATE: Observable<CriticalSectionRawMutex, BatteryState, 8> =
         Observable::new(BatteryState::Nominal);
     static COMMAND_VELOCITY: Observable<CriticalSectionRawMutex, LocalVelocity, 8> =
@@ -105,17 +134,36 @@ fn main() -> ! {
             counterclockwise: 0,
         });
     static COMMAND_KICK_SPEED: Observable<CriticalSectionRawMutex, KickSpeed, 8> =
-        Observable::new(KickSpeed::Velocity(0));
+        Observable::new(KickSpeed::Velocity(KickSelection::Kick, 0));
     static VOLTAGE_MUTEX: Mutex<CriticalSectionRawMutex, U16F16> = Mutex::new(U16F16::ZERO);
-    static ACTUAL_VELOCITY: Observable<CriticalSectionRawMutex, LocalVelocity, 8> =
-        Observable::new(LocalVelocity {
-            forward: 0,
-            left: 0,
-            counterclockwise: 0,
-        });
+    /// Measured by the motorcontroller and when it was received
+    static ACTUAL_VELOCITY: Observable<CriticalSectionRawMutex, (LocalVelocity, Instant), 8> =
+        Observable::new((
+            LocalVelocity {
+                forward: 0,
+                left: 0,
+                counterclockwise: 0,
+            },
+            Instant::from_ticks(0),
+        ));
     static KICKER_VOLTAGE: Observable<CriticalSectionRawMutex, u8, 8> = Observable::new(0);
+    static ERRORS: Observable<CriticalSectionRawMutex, ErrorFlags, 8> =
+        Observable::new(ErrorFlags::empty());
+    static ROBOT_POSITION: Observable<CriticalSectionRawMutex, Option<Position>, 8> =
+        Observable::new(None);
+    static HEADING: Observable<CriticalSectionRawMutex, Option<f32>, 8> = Observable::new(None);
+    static TARGET_POSITION: Observable<CriticalSectionRawMutex, Option<Position>, 8> =
+        Observable::new(None);
+    static GAME_STATE: Observable<CriticalSectionRawMutex, GameState, 8> =
+        Observable::new(GameState::Normal);
+    static MOTOR_LIMITS: Observable<CriticalSectionRawMutex, Option<MotionLimits>, 8> =
+        Observable::new(None);
 
     static CONFIG: Config<CriticalSectionRawMutex> = Config::new();
+    static MOTOR_CONFIGURATION: Channel<CriticalSectionRawMutex, MotorConfiguration, 4> =
+        Channel::new();
+    static MOTOR_CONFIGURED: Channel<CriticalSectionRawMutex, (MotorConfiguration, bool), 4> =
+        Channel::new();
 
     unsafe { spinlock_reset() }
     let p = embassy_rp::init(embassy_rp::config::Config::default());
@@ -124,12 +172,30 @@ fn main() -> ! {
     spawner.must_spawn(power_switch_task(p.PIN_13, p.PIN_12, &SHUTDOWN_SIGNAL));
 
     let Pio { common, sm0, .. } = Pio::new(p.PIO0);
+    let Pio {
+        common: dribbler_pio,
+        sm0: dribbler_sm,
+        ..
+    } = Pio::new(p.PIO1);
 
     let executor = EXECUTOR_LOW.init(Executor::new());
     executor.run(|spawner| {
-        spawner.must_spawn(watchdog_task(p.WATCHDOG));
-        spawner.must_spawn(dribbler_task(p.PIN_20, p.PWM_CH2, &DRIBBLER_SPEED));
-        spawner.must_spawn(lightbarrier_task(p.PIN_15, &HAS_BALL, &CONFIG));
+        spawner.must_spawn(watchdog_task(p.WATCHDOG, &ERRORS));
+        spawner.must_spawn(dribbler_task(
+            p.PIN_20,
+            dribbler_pio,
+            dribbler_sm,
+            &CONFIG,
+            &DRIBBLER_SPEED,
+            &DRIBBLER_TELEMETRY,
+        ));
+        spawner.must_spawn(lightbarrier_task(p.PIN_15, &LIGHT_BARRIER, &CONFIG));
+        spawner.must_spawn(ball_task(
+            &LIGHT_BARRIER,
+            &DRIBBLER_SPEED,
+            &DRIBBLER_TELEMETRY,
+            &HAS_BALL,
+        ));
         spawner.must_spawn(rf_task(
             p.PIN_0,
             p.PIN_1,
@@ -145,6 +211,9 @@ fn main() -> ! {
             p.DMA_CH0,
             p.DMA_CH1,
             &CONFIG,
+            &SAVE_CONFIG_SIGNAL,
+            &MOTOR_CONFIGURATION,
+            &MOTOR_CONFIGURED,
             &VOLTAGE_MUTEX,
             &HAS_BALL,
             &DRIBBLER_SPEED,
@@ -152,6 +221,23 @@ fn main() -> ! {
             &COMMAND_KICK_SPEED,
             &ACTUAL_VELOCITY,
             &KICKER_VOLTAGE,
+            &ROBOT_POSITION,
+            &TARGET_POSITION,
+            &HEADING,
+            &ERRORS,
+            &DRIBBLER_TELEMETRY,
+            &GAME_STATE,
+        ));
+        spawner.must_spawn(heading_task(&ACTUAL_VELOCITY, &ROBOT_POSITION, &HEADING));
+        spawner.must_spawn(position_task(
+            &CONFIG,
+            &ROBOT_POSITION,
+            &TARGET_POSITION,
+            &HEADING,
+            &ACTUAL_VELOCITY,
+            &COMMAND_VELOCITY,
+            &GAME_STATE,
+            &MOTOR_LIMITS,
         ));
         spawner.must_spawn(motorcontroller_task(
             p.UART0,
@@ -164,6 +250,10 @@ fn main() -> ! {
             &COMMAND_KICK_SPEED,
             &ACTUAL_VELOCITY,
             &KICKER_VOLTAGE,
+            &ERRORS,
+            &MOTOR_CONFIGURATION,
+            &MOTOR_CONFIGURED,
+            &MOTOR_LIMITS,
             spawner,
         ));
         spawner.must_spawn(ui_task(
@@ -177,6 +267,7 @@ fn main() -> ! {
             &COMMAND_KICK_SPEED,
             &DRIBBLER_SPEED,
             &SHUTDOWN_SIGNAL,
+            &ERRORS,
         ));
         spawner.must_spawn(buzzer_task(p.PIN_21, common, sm0, &VOLTAGE_STATE));
         spawner.must_spawn(config_task(p.FLASH, &CONFIG, &SAVE_CONFIG_SIGNAL));
@@ -187,6 +278,7 @@ fn main() -> ! {
             &SHUTDOWN_SIGNAL,
             &VOLTAGE_STATE,
             &VOLTAGE_MUTEX,
+            &ERRORS,
         ));
         #[cfg(feature = "test_dribbler")]
         spawner.must_spawn(dribbler_test_task(&DRIBBLER_SPEED));
@@ -1,24 +1,28 @@
-use defmt::{debug, error, unwrap};
+use defmt::{debug, error, info, unwrap};
 use embassy_executor::{task, Spawner};
-use embassy_futures::join::join3;
+use embassy_futures::join::join4;
 use embassy_rp::{
     peripherals::{PIN_16, PIN_17, PIN_18, PIN_19, UART0},
     uart::{self, BufferedUart, BufferedUartRx},
 };
 use embassy_sync::{
     blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex, RawMutex},
+    channel::Channel,
     mutex::Mutex,
 };
-use embassy_time::{with_timeout, Duration};
+use embassy_time::{with_timeout, Duration, Instant};
 use embedded_io::asynch::{BufRead, Write};
 use intra_comms::{
-    definitions::{KickerChargeHint, LocalVelocity, Motor2Main},
+    definitions::{
+        ErrorFlags, KickSelection, KickerChargeHint, LocalVelocity, MotionLimits, Motor2Main,
+        MotorConfiguration,
+    },
     uart::{MotorControllerReceiver, MotorControllerSender, ReceiveError, SendError},
 };
 use static_cell::StaticCell;
 use sync::observable::Observable;
 
-use crate::lightbarrier::LightBarrierState;
+use crate::ball::BallDetection;
 
 #[task]
 #[allow(clippy::too_many_arguments)]
@@ -28,11 +32,15 @@ pub async fn motorcontroller_task(
     rx: PIN_17,
     cts: PIN_18,
     rts: PIN_19,
-    has_ball: &'static Observable<CriticalSectionRawMutex, LightBarrierState, 8>,
+    has_ball: &'static Observable<CriticalSectionRawMutex, BallDetection, 8>,
     command_velocity: &'static Observable<CriticalSectionRawMutex, LocalVelocity, 8>,
     command_kick_speed: &'static Observable<CriticalSectionRawMutex, crate::KickSpeed, 8>,
-    actual_velocity: &'static Observable<CriticalSectionRawMutex, LocalVelocity, 8>,
+    actual_velocity: &'static Observable<CriticalSectionRawMutex, (LocalVelocity, Instant), 8>,
     kicker_voltage: &'static Observable<CriticalSectionRawMutex, u8, 8>,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
+    motor_configuration: &'static Channel<CriticalSectionRawMutex, MotorConfiguration, 4>,
+    motor_configured: &'static Channel<CriticalSectionRawMutex, (MotorConfiguration, bool), 4>,
+    motor_limits: &'static Observable<CriticalSectionRawMutex, Option<MotionLimits>, 8>,
     spawner: Spawner,
 ) {
     static UART_RX_BUFFER: StaticCell<[u8; 256]> = StaticCell::new();
@@ -59,12 +67,16 @@ pub async fn motorcontroller_task(
         MotorControllerReceiver::new(rx),
         actual_velocity,
         kicker_voltage,
+        errors,
+        motor_configured,
+        motor_limits,
     ));
     send(
         MotorControllerSender::new(tx),
         has_ball,
         command_velocity,
         command_kick_speed,
+        motor_configuration,
     )
     .await;
 }
@@ -72,19 +84,41 @@ pub async fn motorcontroller_task(
 #[task]
 async fn receive_task(
     receiver: MotorControllerReceiver<BufferedUartRx<'static, UART0>>,
-    actual_velocity: &'static Observable<CriticalSectionRawMutex, LocalVelocity, 8>,
+    actual_velocity: &'static Observable<CriticalSectionRawMutex, (LocalVelocity, Instant), 8>,
     kicker_voltage: &'static Observable<CriticalSectionRawMutex, u8, 8>,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
+    motor_configured: &'static Channel<CriticalSectionRawMutex, (MotorConfiguration, bool), 4>,
+    motor_limits: &'static Observable<CriticalSectionRawMutex, Option<MotionLimits>, 8>,
 ) {
-    receive(receiver, actual_velocity, kicker_voltage).await;
+    receive(
+        receiver,
+        actual_velocity,
+        kicker_voltage,
+        errors,
+        motor_configured,
+        motor_limits,
+    )
+    .await;
 }
 
-async fn receive<const SUBS1: usize, const SUBS2: usize>(
+async fn receive<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize, const SUBS4: usize>(
     mut receiver: MotorControllerReceiver<impl BufRead>,
-    actual_velocity: &Observable<impl RawMutex, LocalVelocity, SUBS1>,
+    actual_velocity: &Observable<impl RawMutex, (LocalVelocity, Instant), SUBS1>,
     kicker_voltage: &Observable<impl RawMutex, u8, SUBS2>,
+    errors: &Observable<impl RawMutex, ErrorFlags, SUBS3>,
+    motor_configured: &Channel<impl RawMutex, (MotorConfiguration, bool), 4>,
+    motor_limits: &Observable<impl RawMutex, Option<MotionLimits>, SUBS4>,
 ) {
+    // the motorcontroller sends its errors at least every 100ms
+    const LINK_TIMEOUT: Duration = Duration::from_millis(500);
+
     loop {
-        match receiver.receive().await {
+        let Ok(result) = with_timeout(LINK_TIMEOUT, receiver.receive()).await else {
+            error!("no message from the motorcontroller");
+            errors.update(|errors| errors.union(ErrorFlags::MOTORCONTROLLER_LINK));
+            continue;
+        };
+        match result {
             Err(e) => match e {
                 ReceiveError::Postcard(_) => {
                     error!("Couldn't deserialize message using postcard")
@@ -92,19 +126,36 @@ async fn receive<const SUBS1: usize, const SUBS2: usize>(
                 ReceiveError::Cobs => error!("Unable to find valid Cobs packet"),
                 ReceiveError::Io(_) => error!("The Uart could not be used"),
             },
-            Ok(cmd) => match cmd {
-                Motor2Main::MotorVelocity(velocity) => actual_velocity.set_if_different(velocity),
-                Motor2Main::CapVoltage(voltage) => kicker_voltage.set_if_different(voltage),
-            },
+            Ok(cmd) => {
+                errors.update(|errors| errors.difference(ErrorFlags::MOTORCONTROLLER_LINK));
+                match cmd {
+                    Motor2Main::MotorVelocity(velocity) => {
+                        actual_velocity.set((velocity, Instant::now()))
+                    }
+                    Motor2Main::CapVoltage(voltage) => kicker_voltage.set_if_different(voltage),
+                    Motor2Main::KickerState(state) => info!("kicker is in state {}", state),
+                    Motor2Main::KickerFault(fault) => error!("kicker reported fault {}", fault),
+                    Motor2Main::Errors(flags) => errors.update(|errors| {
+                        errors.difference(ErrorFlags::MOTORCONTROLLER).union(flags)
+                    }),
+                    Motor2Main::Configured(configuration, applied) => {
+                        if motor_configured.try_send((configuration, applied)).is_err() {
+                            error!("dropping the answer to {}", configuration);
+                        }
+                    }
+                    Motor2Main::Limits(limits) => motor_limits.set_if_different(Some(limits)),
+                }
+            }
         }
     }
 }
 
 async fn send<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
     sender: MotorControllerSender<impl Write>,
-    has_ball: &Observable<impl RawMutex, LightBarrierState, SUBS1>,
+    has_ball: &Observable<impl RawMutex, BallDetection, SUBS1>,
     command_velocity: &Observable<impl RawMutex, LocalVelocity, SUBS2>,
     command_kick_speed: &Observable<impl RawMutex, crate::KickSpeed, SUBS3>,
+    motor_configuration: &Channel<impl RawMutex, MotorConfiguration, 4>,
 ) {
     const MAX_TIME_BETWEEN_SENDS: Duration = Duration::from_hz(1);
 
@@ -114,19 +165,18 @@ async fn send<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
     let sender = Mutex::<NoopRawMutex, _>::new(sender);
 
     let has_ball_fut = async {
+        let mut last = None;
         loop {
             let value = match with_timeout(MAX_TIME_BETWEEN_SENDS, has_ball_sub.next_value()).await
             {
-                Ok(value) => value,
-                Err(_) => has_ball.get(),
+                // the confidence changes far more often than the decision
+                Ok(value) if last == Some(value.has_ball()) => continue,
+                Ok(value) => value.has_ball(),
+                Err(_) => has_ball.get().has_ball(),
             };
-            debug!("sending {} to motorcontroller", value);
-            if let Err(e) = sender
-                .lock()
-                .await
-                .ball_in_dribbler(value == LightBarrierState::HasBall)
-                .await
-            {
+            last = Some(value);
+            debug!("sending ball in dribbler {} to motorcontroller", value);
+            if let Err(e) = sender.lock().await.ball_in_dribbler(value).await {
                 match e {
                     SendError::Postcard(_) => {
                         error!("unable to encode message using postcard")
@@ -169,7 +219,12 @@ async fn send<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
                 .charge_hint(KickerChargeHint::Charge)
                 .await
                 .and(match value {
-                    crate::KickSpeed::Velocity(velocity) => guard.kick(velocity).await,
+                    crate::KickSpeed::Velocity(KickSelection::Kick, velocity) => {
+                        guard.kick(velocity).await
+                    }
+                    crate::KickSpeed::Velocity(KickSelection::Chip, velocity) => {
+                        guard.chip(velocity).await
+                    }
                     crate::KickSpeed::Raw(duration) => guard.kick_raw(duration).await,
                 });
             if let Err(e) = res {
@@ -183,5 +238,26 @@ async fn send<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
         }
     };
 
-    join3(has_ball_fut, velocity_fut, kick_speed_fut).await;
+    let configuration_fut = async {
+        loop {
+            let configuration = motor_configuration.recv().await;
+            info!("sending {} to motorcontroller", configuration);
+            if let Err(e) = sender.lock().await.configure(configuration).await {
+                match e {
+                    SendError::Postcard(_) => {
+                        error!("unable to encode message using postcard")
+                    }
+                    SendError::Io(_) => error!("unable to send message using uart"),
+                }
+            }
+        }
+    };
+
+    join4(
+        has_ball_fut,
+        velocity_fut,
+        kick_speed_fut,
+        configuration_fut,
+    )
+    .await;
 }
@@ -0,0 +1,156 @@
+use control::{
+    frame::{camera_to_local, local_to_camera},
+    position::{Limits, Pose, PositionController},
+};
+use defmt::{debug, error, warn};
+use embassy_executor::task;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
+use embassy_time::{Duration, Instant};
+use intra_comms::definitions::{GameState, LocalVelocity, MotionLimits, Position};
+use sync::observable::Observable;
+
+use crate::Config;
+
+/// If there was no position update for this duration the control is restarted
+const MAX_UPDATE_DURATION: Duration = Duration::from_millis(100);
+
+#[task]
+#[allow(clippy::too_many_arguments)]
+pub async fn position_task(
+    config: &'static Config<CriticalSectionRawMutex>,
+    robot_position: &'static Observable<CriticalSectionRawMutex, Option<Position>, 8>,
+    target_position: &'static Observable<CriticalSectionRawMutex, Option<Position>, 8>,
+    heading: &'static Observable<CriticalSectionRawMutex, Option<f32>, 8>,
+    actual_velocity: &'static Observable<CriticalSectionRawMutex, (LocalVelocity, Instant), 8>,
+    command_velocity: &'static Observable<CriticalSectionRawMutex, LocalVelocity, 8>,
+    game_state: &'static Observable<CriticalSectionRawMutex, GameState, 8>,
+    motor_limits: &'static Observable<CriticalSectionRawMutex, Option<MotionLimits>, 8>,
+) {
+    position_control(
+        config,
+        robot_position,
+        target_position,
+        heading,
+        actual_velocity,
+        command_velocity,
+        game_state,
+        motor_limits,
+    )
+    .await;
+}
+
+/// The accellerations are the ones the motorcontroller applies, so there is one place to
+/// configure them
+fn limits(config: &Config<impl RawMutex>, game_state: GameState, motor: MotionLimits) -> Limits {
+    let mut linear_velocity = config.position_max_velocity.get();
+    if game_state == GameState::Stop {
+        linear_velocity = linear_velocity.min(config.stop_max_velocity.get());
+    }
+    Limits {
+        linear_velocity,
+        linear_accelleration: motor.linear_acceleration,
+        angular_velocity: config.position_max_angular_velocity.get(),
+        angular_accelleration: motor.angular_acceleration,
+        linear_braking: config.position_max_braking.get(),
+        angular_braking: config.position_max_angular_braking.get(),
+        // the motorcontroller needs this long to reach its accelleration with its jerk limit
+        reaction_time: (motor.linear_acceleration / motor.linear_jerk)
+            .max(motor.angular_acceleration / motor.angular_jerk),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn position_control<
+    const SUBS1: usize,
+    const SUBS2: usize,
+    const SUBS3: usize,
+    const SUBS4: usize,
+    const SUBS5: usize,
+    const SUBS6: usize,
+    const SUBS7: usize,
+>(
+    config: &Config<impl RawMutex>,
+    robot_position: &Observable<impl RawMutex, Option<Position>, SUBS1>,
+    target_position: &Observable<impl RawMutex, Option<Position>, SUBS2>,
+    heading: &Observable<impl RawMutex, Option<f32>, SUBS3>,
+    actual_velocity: &Observable<impl RawMutex, (LocalVelocity, Instant), SUBS4>,
+    command_velocity: &Observable<impl RawMutex, LocalVelocity, SUBS5>,
+    game_state: &Observable<impl RawMutex, GameState, SUBS6>,
+    motor_limits: &Observable<impl RawMutex, Option<MotionLimits>, SUBS7>,
+) {
+    let Ok(mut position_subscriber) = robot_position.subscriber() else {error!("couldn't get position subscriber"); return;};
+    let Ok(mut target_subscriber) = target_position.subscriber() else {error!("couldn't get target subscriber"); return;};
+    let Ok(mut limits_subscriber) = motor_limits.subscriber() else {error!("couldn't get limits subscriber"); return;};
+    // the motorcontroller reports its limits periodically
+    let mut motor = loop {
+        if let Some(motor) = motor_limits.get() {
+            break motor;
+        }
+        limits_subscriber.next_value().await;
+    };
+    let mut controller = PositionController::new(
+        config.position_linear_gain.get(),
+        config.position_angular_gain.get(),
+        limits(config, game_state.get(), motor),
+    );
+    if let Some(target) = target_position.get() {
+        controller.set_target(target.into());
+    }
+    let mut last_update = None;
+    loop {
+        match select(
+            target_subscriber.next_value(),
+            position_subscriber.next_value(),
+        )
+        .await
+        {
+            Either::First(Some(target)) => {
+                debug!("new target position {}", target);
+                controller.set_target(target.into());
+            }
+            Either::First(None) => last_update = None,
+            Either::Second(Some(position)) => {
+                if target_position.get().is_none() {
+                    continue;
+                }
+                let Some(heading) = heading.get() else {
+                    warn!("heading unknown. Stopping instead of driving to position");
+                    command_velocity.set_if_different(LocalVelocity {
+                        forward: 0,
+                        left: 0,
+                        counterclockwise: 0,
+                    });
+                    continue;
+                };
+
+                let now = Instant::now();
+                let dt = match last_update {
+                    Some(last_update) if now - last_update <= MAX_UPDATE_DURATION => {
+                        (now - last_update).as_micros() as f32 / 1_000_000.0
+                    }
+                    _ => {
+                        // start from the current velocity of the robot
+                        controller.reset(local_to_camera(actual_velocity.get().0, heading).into());
+                        0.0
+                    }
+                };
+                last_update = Some(now);
+
+                controller.set_gains(
+                    config.position_linear_gain.get(),
+                    config.position_angular_gain.get(),
+                );
+                motor = motor_limits.get().unwrap_or(motor);
+                controller.set_limits(limits(config, game_state.get(), motor));
+                let pose = Pose {
+                    theta: heading,
+                    ..position.into()
+                };
+                let velocity = controller.regulate(pose, dt);
+                command_velocity.set_if_different(camera_to_local(velocity.into(), heading));
+            }
+            Either::Second(None) => (),
+        }
+    }
+}
@@ -21,6 +21,7 @@ use embedded_hal::{
 use embedded_hal_async::digital::Wait;
 use fixed::types::U16F16;
 use fixed_macro::types::U16F16;
+use intra_comms::definitions::ErrorFlags;
 use sync::observable::Observable;
 
 #[task]
@@ -127,6 +128,7 @@ pub async fn measure_task(
     shutdown: &'static Signal<CriticalSectionRawMutex, ()>,
     voltage_state: &'static Observable<CriticalSectionRawMutex, BatteryState, 8>,
     voltage_mutex: &'static Mutex<CriticalSectionRawMutex, U16F16>,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
 ) {
     let adc = Adc::new(adc, crate::Irqs, Default::default());
     measure(
@@ -136,17 +138,19 @@ pub async fn measure_task(
         shutdown,
         voltage_state,
         voltage_mutex,
+        errors,
     )
     .await;
 }
 
-async fn measure<'d, const SUBS: usize>(
+async fn measure<'d, const SUBS1: usize, const SUBS2: usize>(
     mut current_sense: impl Channel<Adc<'d>, ID = u8> + Pin,
     mut voltage_sense: impl Channel<Adc<'d>, ID = u8> + Pin,
     mut adc: Adc<'d>,
     shutdown: &Signal<impl RawMutex, ()>,
-    voltage_state: &Observable<impl RawMutex, BatteryState, SUBS>,
+    voltage_state: &Observable<impl RawMutex, BatteryState, SUBS1>,
     voltage_mutex: &Mutex<impl RawMutex, U16F16>,
+    errors: &Observable<impl RawMutex, ErrorFlags, SUBS2>,
 ) {
     const USB_THRESHOLD: U16F16 = U16F16!(5.0);
 
@@ -172,6 +176,8 @@ async fn measure<'d, const SUBS: usize>(
             }
             voltage_state.set(state);
         }
+        let low = matches!(state, BatteryState::Critical | BatteryState::Low);
+        errors.update(|errors| errors.with(ErrorFlags::LOW_BATTERY, low));
 
         if state == BatteryState::Critical && voltage > USB_THRESHOLD {
             shutdown.signal(());
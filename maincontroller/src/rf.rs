@@ -1,7 +1,11 @@
 use az::Az;
-use defmt::{debug, error, unwrap, warn};
+use control::{
+    frame::camera_to_local, game_state::limit_velocity, kick::relative_kick_speed,
+    timesync::TimeSync,
+};
+use defmt::{debug, error, info, unwrap, warn};
 use embassy_executor::task;
-use embassy_futures::select::{select3, Either3};
+use embassy_futures::select::{select4, Either4};
 use embassy_rp::{
     gpio::{Input, Level, Output, Pull},
     peripherals::{
@@ -12,9 +16,11 @@ use embassy_rp::{
 };
 use embassy_sync::{
     blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex},
+    channel::Channel,
     mutex::Mutex,
+    signal::Signal,
 };
-use embassy_time::{with_timeout, Delay, Duration};
+use embassy_time::{with_timeout, Delay, Duration, Instant};
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 use embedded_hal_async::{digital::Wait, spi::ExclusiveDevice, spi::SpiDevice};
 use fixed::types::U16F16;
@@ -23,26 +29,28 @@ use fugit::RateExtU32;
 use intra_comms::{
     crate_version,
     definitions::{
-        BallState, BasestationToRobot, DribblerSpeedSelection, DribblerState, GameState,
-        KickSpeedSelection, LocalVelocity, MovementSelection, RobotToBasestation, Team,
-        VelocitySelection,
+        BallState, BasestationToRobot, Broadcast, ConfigurationMessage, DribblerSpeedSelection,
+        DribblerState, ErrorFlags, GameState, KickSelection, KickSpeedSelection, LocalVelocity,
+        MotorConfiguration, MovementSelection, Position, RobotConfiguration, RobotToBasestation,
+        TimesyncTimestamp, VelocitySelection,
     },
-    ROBOT_BLUE_SYNC_WORDS,
+    robot_sync_word, BROADCAST_SYNC_WORD, MAX_CHARGE_VOLTAGE, MAX_RF_FREQUENCY, MIN_RF_FREQUENCY,
 };
 use sky66112::{Sky66112, TiedHigh, TiedLow};
 use sx1280::{
     definitions::{
         FlrcBitrateBandwidth, FlrcCodingRate, FlrcModulationShaping, GfskFlrcPacketType,
         GfskFlrcPreambleLength, GfskFlrcSyncWordMatch, IrqBit, IrqWriter, PeriodBase, RampTime,
+        SyncPacketStatusByte,
     },
     Sx1280,
 };
 use sync::observable::Observable;
 
-use crate::{
-    lightbarrier::{self, LightBarrierState},
-    Config,
-};
+use crate::{ball::BallDetection, dribbler::DribblerTelemetry, Config};
+
+/// ms without a packet for this robot until it stops
+const RX_TIMEOUT_MS: u64 = 50;
 
 #[task]
 #[allow(clippy::too_many_arguments)]
@@ -61,13 +69,22 @@ pub async fn rf_task(
     tx_dma: DMA_CH0,
     rx_dma: DMA_CH1,
     config: &'static Config<CriticalSectionRawMutex>,
+    save_config: &'static Signal<CriticalSectionRawMutex, ()>,
+    motor_configuration: &'static Channel<CriticalSectionRawMutex, MotorConfiguration, 4>,
+    motor_configured: &'static Channel<CriticalSectionRawMutex, (MotorConfiguration, bool), 4>,
     voltage: &'static Mutex<CriticalSectionRawMutex, U16F16>,
-    has_ball: &'static Observable<CriticalSectionRawMutex, lightbarrier::LightBarrierState, 8>,
-    dribbler_speed: &'static Observable<CriticalSectionRawMutex, u16, 8>,
+    has_ball: &'static Observable<CriticalSectionRawMutex, BallDetection, 8>,
+    dribbler_speed: &'static Observable<CriticalSectionRawMutex, crate::DribblerSpeed, 8>,
     command_velocity: &'static Observable<CriticalSectionRawMutex, LocalVelocity, 8>,
     command_kick_speed: &'static Observable<CriticalSectionRawMutex, crate::KickSpeed, 8>,
-    actual_velocity: &'static Observable<CriticalSectionRawMutex, LocalVelocity, 8>,
+    actual_velocity: &'static Observable<CriticalSectionRawMutex, (LocalVelocity, Instant), 8>,
     kicker_voltage: &'static Observable<CriticalSectionRawMutex, u8, 8>,
+    robot_position: &'static Observable<CriticalSectionRawMutex, Option<Position>, 8>,
+    target_position: &'static Observable<CriticalSectionRawMutex, Option<Position>, 8>,
+    heading: &'static Observable<CriticalSectionRawMutex, Option<f32>, 8>,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
+    dribbler_telemetry: &'static Observable<CriticalSectionRawMutex, DribblerTelemetry, 8>,
+    game_state: &'static Observable<CriticalSectionRawMutex, GameState, 8>,
 ) {
     let crx = Output::new(crx, Level::Low);
     let cps = Output::new(cps, Level::Low);
@@ -91,6 +108,9 @@ pub async fn rf_task(
         crx,
         ctx,
         config,
+        save_config,
+        motor_configuration,
+        motor_configured,
         voltage,
         has_ball,
         dribbler_speed,
@@ -98,6 +118,12 @@ pub async fn rf_task(
         command_kick_speed,
         actual_velocity,
         kicker_voltage,
+        robot_position,
+        target_position,
+        heading,
+        errors,
+        dribbler_telemetry,
+        game_state,
     )
     .await;
 }
@@ -110,6 +136,12 @@ async fn rf<
     const SUBS4: usize,
     const SUBS5: usize,
     const SUBS6: usize,
+    const SUBS7: usize,
+    const SUBS8: usize,
+    const SUBS9: usize,
+    const SUBS10: usize,
+    const SUBS11: usize,
+    const SUBS12: usize,
 >(
     spi: impl SpiDevice<u8>,
     reset: impl OutputPin,
@@ -119,13 +151,22 @@ async fn rf<
     crx: impl OutputPin,
     ctx: impl OutputPin,
     config: &Config<impl RawMutex>,
+    save_config: &Signal<impl RawMutex, ()>,
+    motor_configuration: &Channel<impl RawMutex, MotorConfiguration, 4>,
+    motor_configured: &Channel<impl RawMutex, (MotorConfiguration, bool), 4>,
     voltage: &Mutex<impl RawMutex, U16F16>,
-    has_ball: &Observable<impl RawMutex, LightBarrierState, SUBS1>,
-    dribbler_speed: &Observable<impl RawMutex, u16, SUBS2>,
+    has_ball: &Observable<impl RawMutex, BallDetection, SUBS1>,
+    dribbler_speed: &Observable<impl RawMutex, crate::DribblerSpeed, SUBS2>,
     command_velocity: &Observable<impl RawMutex, LocalVelocity, SUBS3>,
     command_kick_speed: &Observable<impl RawMutex, crate::KickSpeed, SUBS4>,
-    actual_velocity: &Observable<impl RawMutex, LocalVelocity, SUBS5>,
+    actual_velocity: &Observable<impl RawMutex, (LocalVelocity, Instant), SUBS5>,
     kicker_voltage: &Observable<impl RawMutex, u8, SUBS6>,
+    robot_position: &Observable<impl RawMutex, Option<Position>, SUBS7>,
+    target_position: &Observable<impl RawMutex, Option<Position>, SUBS8>,
+    heading: &Observable<impl RawMutex, Option<f32>, SUBS9>,
+    errors: &Observable<impl RawMutex, ErrorFlags, SUBS10>,
+    dribbler_telemetry: &Observable<impl RawMutex, DribblerTelemetry, SUBS11>,
+    game_state: &Observable<impl RawMutex, GameState, SUBS12>,
 ) {
     let sky = Sky66112::new(TiedHigh, cps, crx, ctx, TiedHigh, TiedLow);
     let mut sky_outer = Some(sky.into_sleep_mode2());
@@ -136,10 +177,20 @@ async fn rf<
         return;
     };
     let Some(mut id_subscriber) = config.id.sub() else {error!("couldn't get id subscriber"); return;};
+    let Some(mut team_subscriber) = config.team.sub() else {error!("couldn't get team subscriber"); return;};
     let Some(mut frequency_subscriber) = config.rf_frequency.sub() else {error!("couldn't get frequency subscriber"); return;};
     let mut frequency = None;
     let mut sync_word = None;
     let mut rx_timed_out = false;
+    // broadcasts must not hide missing packets for this robot
+    let mut rx_deadline = Instant::now() + Duration::from_millis(RX_TIMEOUT_MS);
+    let mut emergency_stop = false;
+    let mut time_sync = TimeSync::new();
+    let mut configuration_ack = None;
+    // applied after the acknowledgement was sent
+    let mut deferred_configuration = None;
+    // acknowledged after the motorcontroller applied it
+    let mut forwarded_configuration: Option<ConfigurationMessage> = None;
     loop {
         if let Some(frequency) = frequency.take() {
             debug!("setting new frequency");
@@ -155,12 +206,16 @@ async fn rf<
         }
         let Some(sky) = sky_outer.take() else {error!("The sky66112 got lost"); return;};
         let sky = sky.into_receive_lna_mode();
+        let timeout = if rx_timed_out {
+            0
+        } else {
+            rx_deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis()
+                .clamp(1, RX_TIMEOUT_MS) as u16
+        };
         if sx
-            .start_receive_packet(
-                32,
-                PeriodBase::MilliSeconds1,
-                if rx_timed_out { 0 } else { 50 },
-            )
+            .start_receive_packet(64, PeriodBase::MilliSeconds1, timeout)
             .await
             .is_err()
         {
@@ -168,22 +223,29 @@ async fn rf<
             return;
         }
         loop {
-            match select3(
+            match select4(
                 dio1.wait_for_high(),
                 id_subscriber.next_value(),
+                team_subscriber.next_value(),
                 frequency_subscriber.next_value(),
             )
             .await
             {
-                Either3::First(Ok(_)) => break,
-                Either3::First(Err(_)) => {
+                Either4::First(Ok(_)) => break,
+                Either4::First(Err(_)) => {
                     error!("Waiting for interrupt");
                     return;
                 }
-                Either3::Second(id) => sync_word = Some(ROBOT_BLUE_SYNC_WORDS[usize::from(id)]),
-                Either3::Third(new_frequency) => frequency = Some(new_frequency.MHz()),
+                Either4::Second(_) | Either4::Third(_) => {
+                    sync_word = robot_sync_word(config.team.get(), config.id.get());
+                    if sync_word.is_none() {
+                        error!("no sync word for robot id {}", config.id.get());
+                    }
+                }
+                Either4::Fourth(new_frequency) => frequency = Some(new_frequency.MHz()),
             }
         }
+        let received = Instant::now();
         if dio1.wait_for_high().await.is_err() {
             return;
         }
@@ -194,18 +256,20 @@ async fn rf<
         };
         if irq.is_set(IrqBit::RxTxTimeout) {
             warn!("timeout while receiving packet");
+            errors.update(|errors| errors.union(ErrorFlags::RF_TIMEOUT));
+            target_position.set_if_different(None);
             command_velocity.set(LocalVelocity {
                 forward: 0,
                 left: 0,
                 counterclockwise: 0,
             });
-            command_kick_speed.set(crate::KickSpeed::Velocity(0));
-            dribbler_speed.set(0);
+            command_kick_speed.set(crate::KickSpeed::Velocity(KickSelection::Kick, 0));
+            dribbler_speed.set(crate::DribblerSpeed::Throttle(0));
             sky_outer = Some(sky.into_sleep_mode2());
             rx_timed_out = true;
             continue;
         }
-        let Ok((rssi, errors, _sync)) = sx.packet_status().await else {
+        let Ok((rssi, errors, sync)) = sx.packet_status().await else {
             error!("getting packet status");
             sky_outer = Some(sky.into_sleep_mode2());
             continue;
@@ -230,27 +294,65 @@ async fn rf<
             sky_outer = Some(sky.into_sleep_mode2());
             continue;
         }
+        if sync == SyncPacketStatusByte::SyncAddress2 {
+            // broadcasts aren't answered
+            sky_outer = Some(sky.into_sleep_mode2());
+            let Ok(packet) = sx.read_packet::<64>().await else {
+                error!("reading buffer from sx1280");
+                return;
+            };
+            let Ok(broadcast) = postcard::from_bytes::<Broadcast>(&packet[..]) else {
+                error!("couldn't decode broadcast from basestation");
+                continue;
+            };
+            if let Some(time) = broadcast.time_sync {
+                time_sync.update(received.as_micros(), time.as_micros());
+            }
+            process_broadcast(
+                &broadcast,
+                &mut emergency_stop,
+                config,
+                dribbler_speed,
+                command_velocity,
+                command_kick_speed,
+                target_position,
+                game_state,
+            )
+            .await;
+            continue;
+        }
+        // packets which only carry a configuration don't count as commands
+        let command_timeout = (rx_timed_out, rx_deadline);
         rx_timed_out = false;
+        rx_deadline = received + Duration::from_millis(RX_TIMEOUT_MS);
+        let ball = has_ball.get();
+        let (velocity, velocity_time) = actual_velocity.get();
         let response = RobotToBasestation {
             id: config.id.get(),
-            team: Team::Blue,
+            team: config.team.get(),
             battery_voltage: (*voltage.lock().await * U16F16!(8)).az(),
             kicker_voltage: kicker_voltage.get(),
-            has_ball: match has_ball.get() {
-                LightBarrierState::HasBall | LightBarrierState::ContactLost => {
-                    BallState::InDribbler
-                }
-                LightBarrierState::NoBall => BallState::NotInDribbler,
+            has_ball: if ball.has_ball() {
+                BallState::InDribbler
+            } else {
+                BallState::NotInDribbler
             },
-            error: 0,
+            error: errors.get(),
             battery_current: None,
             battery_capacity_used: None,
             rssi: unwrap!(u8::try_from(-rssi), "range checked"),
-            velocity: Some(VelocitySelection::RobotVelocity(actual_velocity.get())),
+            velocity: Some(VelocitySelection::RobotVelocity(velocity)),
             position: None,
             firmware_version: crate_version!(),
+            dribbler_rpm: dribbler_telemetry.get().rpm,
+            ball_confidence: ball.confidence,
+            light_barrier_working: !ball.light_barrier_suspected_broken,
+            sample_time: time_sync
+                .to_remote(velocity_time.min(ball.time).as_micros())
+                .map(TimesyncTimestamp::from_micros),
+            configuration_ack,
         };
-        let Ok(feedback_packet) = postcard::to_vec::<_, 24>(&response) else {
+        let Ok(feedback_packet) = postcard::to_vec::<_, 64>(&response) else {
             error!("couldn't encode feedback");
         sky_outer = Some(sky.into_sleep_mode2());
         continue;
@@ -258,32 +360,101 @@ async fn rf<
 
         let sky = sky.into_transmit_high_power_mode();
         if sx
-            .send_packet::<24>(&feedback_packet[..], PeriodBase::MilliSeconds1, 5)
+            .send_packet::<64>(&feedback_packet[..], PeriodBase::MilliSeconds1, 5)
             .await
             .is_err()
         {
             error!("sending rf packet");
             return;
         }
+        // the basestation was told about the timeouts
+        errors.update(|errors| errors.difference(ErrorFlags::RF_TIMEOUT));
+        if let Some(configuration) = deferred_configuration.take() {
+            // it was checked before it was acknowledged
+            apply(configuration, config, save_config);
+        }
         let _ = dio1.wait_for_high().await;
         sx.clear_interrupts().await.ok();
         sky_outer = Some(sky.into_sleep_mode2());
-        let Ok(packet) = sx.read_packet::<32>().await else {
+        let Ok(packet) = sx.read_packet::<64>().await else {
                 error!("reading buffer from sx1280");
                 return;
             };
 
-        let Ok(packet) = postcard::from_bytes::<BasestationToRobot>(&packet[..]) else {
+        let Ok(mut packet) = postcard::from_bytes::<BasestationToRobot>(&packet[..]) else {
             error!("couldn't decode packet from basestation");
             continue;
         };
 
+        if packet.id != config.id.get() || packet.team != config.team.get() {
+            warn!(
+                "ignoring packet for robot {} of team {}",
+                packet.id, packet.team
+            );
+            continue;
+        }
+
+        if let Some(time) = packet.time_sync {
+            time_sync.update(received.as_micros(), time.as_micros());
+        }
+        while let Ok((motor_setting, applied)) = motor_configured.try_recv() {
+            match forwarded_configuration {
+                Some(message) if forwarded(message.configuration) == Some(motor_setting) => {
+                    forwarded_configuration = None;
+                    if applied {
+                        apply(message.configuration, config, save_config);
+                        configuration_ack = Some(message.sequence);
+                    } else {
+                        warn!("the motorcontroller rejected {}", message.configuration);
+                    }
+                }
+                _ => warn!(
+                    "unexpected answer of the motorcontroller to {}",
+                    motor_setting
+                ),
+            }
+        }
+        // the basestation repeats the configuration until it gets the acknowledgement and reports
+        // it as failed if it never comes
+        if let Some(message) = packet.configuration.filter(|message| {
+            configuration_ack != Some(message.sequence)
+                && forwarded_configuration.map(|forwarded| forwarded.sequence)
+                    != Some(message.sequence)
+        }) {
+            match configure(
+                message.configuration,
+                config,
+                save_config,
+                motor_configuration,
+            ) {
+                Configured::Applied => configuration_ack = Some(message.sequence),
+                Configured::Deferred => {
+                    deferred_configuration = Some(message.configuration);
+                    configuration_ack = Some(message.sequence);
+                }
+                Configured::Forwarded => forwarded_configuration = Some(message),
+                Configured::Rejected => (),
+            }
+        }
+        if packet.keep_state {
+            (rx_timed_out, rx_deadline) = command_timeout;
+            continue;
+        }
+        if emergency_stop {
+            packet.game_state = GameState::Halt;
+        }
+
         process(
             &packet,
             config,
             dribbler_speed,
             command_velocity,
             command_kick_speed,
+            robot_position,
+            target_position,
+            heading,
+            actual_velocity,
+            game_state,
         )
         .await;
     }
@@ -313,9 +484,13 @@ where
     sx.set_buffer_base_address(0, 128).await?;
     sx.set_preamble_length(GfskFlrcPreambleLength::PreambleLength08Bits);
     sx.set_packet_type(GfskFlrcPacketType::PacketLengthVariable);
-    sx.set_sync_word_match(GfskFlrcSyncWordMatch::SyncWord1);
-    sx.set_sync_word1(ROBOT_BLUE_SYNC_WORDS[usize::from(config.id.get())])
-        .await?;
+    sx.set_sync_word_match(GfskFlrcSyncWordMatch::SyncWord12);
+    if let Some(sync_word) = robot_sync_word(config.team.get(), config.id.get()) {
+        sx.set_sync_word1(sync_word).await?;
+    } else {
+        error!("no sync word for robot id {}", config.id.get());
+    }
+    sx.set_sync_word2(BROADCAST_SYNC_WORD).await?;
     sx.set_modulation_params(
         FlrcBitrateBandwidth::Bitrate1300Bandwidth12,
         FlrcCodingRate::CodingRate11,
@@ -326,54 +501,298 @@ where
     Ok(sx)
 }
 
-async fn process<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
-    packet: &BasestationToRobot,
+/// What happened to a configuration sent by the basestation
+enum Configured {
+    Applied,
+    /// Has to be applied after the acknowledgement was sent
+    Deferred,
+    /// Waits for the answer of the motorcontroller
+    Forwarded,
+    Rejected,
+}
+
+/// Checks a configuration sent by the basestation and applies it. Settings of the motorcontroller
+/// are forwarded to it first.
+fn configure(
+    configuration: RobotConfiguration,
+    config: &Config<impl RawMutex>,
+    save_config: &Signal<impl RawMutex, ()>,
+    motor_configuration: &Channel<impl RawMutex, MotorConfiguration, 4>,
+) -> Configured {
+    info!("got configuration {}", configuration);
+    if !is_valid(configuration, config) {
+        warn!("ignoring invalid {}", configuration);
+        return Configured::Rejected;
+    }
+    if let Some(motor_setting) = forwarded(configuration) {
+        if motor_configuration.try_send(motor_setting).is_err() {
+            error!("couldn't forward {} to the motorcontroller", motor_setting);
+            return Configured::Rejected;
+        }
+        return Configured::Forwarded;
+    }
+    match configuration {
+        // the acknowledgement has to reach the basestation with the old id and frequency
+        RobotConfiguration::Id(_) | RobotConfiguration::RfFrequency(_) => Configured::Deferred,
+        configuration => {
+            apply(configuration, config, save_config);
+            Configured::Applied
+        }
+    }
+}
+
+/// The part of `configuration` the motorcontroller has to apply
+fn forwarded(configuration: RobotConfiguration) -> Option<MotorConfiguration> {
+    match configuration {
+        RobotConfiguration::MaxAcceleration(acceleration) => {
+            Some(MotorConfiguration::LinearAcceleration(acceleration))
+        }
+        RobotConfiguration::MaxAngularAcceleration(acceleration) => {
+            Some(MotorConfiguration::AngularAcceleration(acceleration))
+        }
+        RobotConfiguration::MaxCapVoltage(voltage) => {
+            Some(MotorConfiguration::ChargeVoltage(voltage))
+        }
+        _ => None,
+    }
+}
+
+/// Sets a checked configuration and saves it
+fn apply(
+    configuration: RobotConfiguration,
+    config: &Config<impl RawMutex>,
+    save_config: &Signal<impl RawMutex, ()>,
+) {
+    match configuration {
+        RobotConfiguration::RfFrequency(frequency) => {
+            // the frequency subscriber retunes the radio
+            config.rf_frequency.set(u32::from(frequency));
+        }
+        RobotConfiguration::Id(id) => {
+            // the id subscriber changes the sync word
+            config.id.set(id);
+        }
+        RobotConfiguration::MaxVelocity(velocity) => {
+            config.position_max_velocity.set(velocity);
+        }
+        RobotConfiguration::MaxAngularVelocity(velocity) => {
+            config.position_max_angular_velocity.set(velocity);
+        }
+        RobotConfiguration::MaxBraking(braking) => {
+            config.position_max_braking.set(braking);
+        }
+        RobotConfiguration::MaxAngularBraking(braking) => {
+            config.position_max_angular_braking.set(braking);
+        }
+        RobotConfiguration::PositionGain(gain) => {
+            config.position_linear_gain.set(gain);
+        }
+        RobotConfiguration::AngularGain(gain) => {
+            config.position_angular_gain.set(gain);
+        }
+        RobotConfiguration::MaxDribblerSpeed(percent) => {
+            config
+                .dribbler_high
+                .set(u16::from(percent) * (u16::MAX / 100));
+        }
+        // the motorcontroller saved it
+        RobotConfiguration::MaxAcceleration(_)
+        | RobotConfiguration::MaxAngularAcceleration(_)
+        | RobotConfiguration::MaxCapVoltage(_) => return,
+    }
+    save_config.signal(());
+}
+
+/// Whether `configuration` can be applied by this robot
+fn is_valid(configuration: RobotConfiguration, config: &Config<impl RawMutex>) -> bool {
+    match configuration {
+        RobotConfiguration::RfFrequency(frequency) => {
+            (MIN_RF_FREQUENCY..=MAX_RF_FREQUENCY).contains(&frequency)
+        }
+        RobotConfiguration::Id(id) => robot_sync_word(config.team.get(), id).is_some(),
+        RobotConfiguration::MaxVelocity(value)
+        | RobotConfiguration::MaxAngularVelocity(value)
+        | RobotConfiguration::MaxAcceleration(value)
+        | RobotConfiguration::MaxAngularAcceleration(value)
+        | RobotConfiguration::MaxBraking(value)
+        | RobotConfiguration::MaxAngularBraking(value)
+        | RobotConfiguration::PositionGain(value)
+        | RobotConfiguration::AngularGain(value) => is_positive(value),
+        RobotConfiguration::MaxDribblerSpeed(percent) => percent <= 100,
+        RobotConfiguration::MaxCapVoltage(voltage) => voltage <= MAX_CHARGE_VOLTAGE,
+    }
+}
+
+/// Limits and gains of the position controller have to be positive
+fn is_positive(value: f32) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_broadcast<
+    const SUBS1: usize,
+    const SUBS2: usize,
+    const SUBS3: usize,
+    const SUBS4: usize,
+    const SUBS5: usize,
+>(
+    broadcast: &Broadcast,
+    emergency_stop: &mut bool,
     config: &Config<impl RawMutex>,
-    command_dribbler_speed: &Observable<impl RawMutex, u16, SUBS1>,
+    command_dribbler_speed: &Observable<impl RawMutex, crate::DribblerSpeed, SUBS1>,
     command_velocity: &Observable<impl RawMutex, LocalVelocity, SUBS2>,
     command_kick_speed: &Observable<impl RawMutex, crate::KickSpeed, SUBS3>,
+    target_position: &Observable<impl RawMutex, Option<Position>, SUBS4>,
+    game_state: &Observable<impl RawMutex, GameState, SUBS5>,
 ) {
-    match packet.movement {
-        MovementSelection::RobotVelocity(velocity) => {
-            command_velocity.set_if_different(velocity);
+    if let Some(team) = broadcast.team {
+        if config.team.get() != team {
+            info!("basestation assigned team {}", team);
+            // the new sync word is used starting with the next packet
+            config.team.set(team);
         }
-        MovementSelection::CameraVelocity(_) => {
-            error!("absolute velocity controll not implemented yet");
+    }
+    if *emergency_stop != broadcast.emergency_stop {
+        if broadcast.emergency_stop {
+            warn!("emergency stop");
+        } else {
+            info!("emergency stop cleared");
         }
-        MovementSelection::Position(_) => {
-            error!("position controll not implemented yet");
+        *emergency_stop = broadcast.emergency_stop;
+    }
+    let new_game_state = if broadcast.emergency_stop {
+        GameState::Halt
+    } else {
+        broadcast.game_state
+    };
+    if game_state.get() != new_game_state {
+        info!("game state changed to {}", new_game_state);
+        game_state.set(new_game_state);
+    }
+
+    // the next packet for this robot might be a whole frame away
+    match new_game_state {
+        GameState::Halt => {
+            target_position.set_if_different(None);
+            command_velocity.set_if_different(LocalVelocity {
+                forward: 0,
+                left: 0,
+                counterclockwise: 0,
+            });
+            command_kick_speed.set_if_different(crate::KickSpeed::Velocity(KickSelection::Kick, 0));
+            command_dribbler_speed.set_if_different(crate::DribblerSpeed::Throttle(0));
         }
+        GameState::Stop => {
+            command_velocity
+                .update(|velocity| limit_velocity(*velocity, config.stop_max_velocity.get()));
+            command_kick_speed.update(|kick| match kick {
+                crate::KickSpeed::Velocity(kick_type, _) => {
+                    crate::KickSpeed::Velocity(*kick_type, 0)
+                }
+                crate::KickSpeed::Raw(_) => crate::KickSpeed::Raw(0),
+            });
+            command_dribbler_speed.set_if_different(crate::DribblerSpeed::Throttle(0));
+        }
+        GameState::Normal => (),
+    }
+}
+
+async fn process<
+    const SUBS1: usize,
+    const SUBS2: usize,
+    const SUBS3: usize,
+    const SUBS4: usize,
+    const SUBS5: usize,
+    const SUBS6: usize,
+    const SUBS7: usize,
+    const SUBS8: usize,
+>(
+    packet: &BasestationToRobot,
+    config: &Config<impl RawMutex>,
+    command_dribbler_speed: &Observable<impl RawMutex, crate::DribblerSpeed, SUBS1>,
+    command_velocity: &Observable<impl RawMutex, LocalVelocity, SUBS2>,
+    command_kick_speed: &Observable<impl RawMutex, crate::KickSpeed, SUBS3>,
+    robot_position: &Observable<impl RawMutex, Option<Position>, SUBS4>,
+    target_position: &Observable<impl RawMutex, Option<Position>, SUBS5>,
+    heading: &Observable<impl RawMutex, Option<f32>, SUBS6>,
+    actual_velocity: &Observable<impl RawMutex, (LocalVelocity, Instant), SUBS7>,
+    game_state: &Observable<impl RawMutex, GameState, SUBS8>,
+) {
+    if let Some(position) = packet.robot_position {
+        robot_position.set(Some(position));
     }
+    if game_state.get() != packet.game_state {
+        info!("game state changed to {}", packet.game_state);
+        game_state.set(packet.game_state);
+    }
+    // the position task limits its velocity itself
+    let stop = packet.game_state == GameState::Stop;
+    let limit = |velocity| {
+        if stop {
+            limit_velocity(velocity, config.stop_max_velocity.get())
+        } else {
+            velocity
+        }
+    };
 
-    match packet.kick_speed {
-        KickSpeedSelection::Relative(speed) => {
-            command_kick_speed.set_if_different(crate::KickSpeed::Velocity(speed));
+    match packet.movement {
+        MovementSelection::RobotVelocity(velocity) => {
+            target_position.set_if_different(None);
+            command_velocity.set_if_different(limit(velocity));
         }
-        KickSpeedSelection::Absolute(_) => {
-            error!("absolute kicking speed not implemented yet");
+        MovementSelection::CameraVelocity(velocity) => {
+            target_position.set_if_different(None);
+            if let Some(heading) = heading.get() {
+                command_velocity.set_if_different(limit(camera_to_local(velocity, heading)));
+            } else {
+                warn!("heading unknown. Stopping instead of using camera velocity");
+                command_velocity.set_if_different(LocalVelocity {
+                    forward: 0,
+                    left: 0,
+                    counterclockwise: 0,
+                });
+            }
+        }
+        MovementSelection::Position(target) => {
+            // the velocity is set by the position task
+            target_position.set_if_different(Some(target));
         }
     }
 
-    match packet.dribbler_speed {
-        DribblerSpeedSelection::Tristate(state) => {
-            command_dribbler_speed.set_if_different(match state {
-                DribblerState::Off => 0,
-                DribblerState::Half => config.dribbler_low.get(),
-                DribblerState::Full => config.dribbler_high.get(),
-            })
+    let kick_speed = match packet.kick_speed {
+        KickSpeedSelection::Relative(speed) => speed,
+        KickSpeedSelection::Absolute(speed) => {
+            relative_kick_speed(speed, actual_velocity.get().0.forward)
         }
+    };
+
+    let dribbler_speed = match packet.dribbler_speed {
+        DribblerSpeedSelection::Tristate(state) => crate::DribblerSpeed::Throttle(match state {
+            DribblerState::Off => 0,
+            DribblerState::Half => config.dribbler_low.get(),
+            DribblerState::Full => config.dribbler_high.get(),
+        }),
         DribblerSpeedSelection::Percent(p) => {
-            command_dribbler_speed.set_if_different(u16::from(p) * (u16::MAX / 100));
-        }
-        DribblerSpeedSelection::Rpm(_) => {
-            error!("Dribbler RPM controll not implemented yet");
+            crate::DribblerSpeed::Throttle(u16::from(p) * (u16::MAX / 100))
         }
+        DribblerSpeedSelection::Rpm(rpm) => crate::DribblerSpeed::Rpm(rpm),
+    };
+
+    // the ball must not be manipulated during STOP
+    if stop {
+        command_kick_speed.set_if_different(crate::KickSpeed::Velocity(packet.kick_type, 0));
+        command_dribbler_speed.set_if_different(crate::DribblerSpeed::Throttle(0));
+    } else {
+        command_kick_speed
+            .set_if_different(crate::KickSpeed::Velocity(packet.kick_type, kick_speed));
+        command_dribbler_speed.set_if_different(dribbler_speed);
     }
 
     match packet.game_state {
         GameState::Halt => {
+            target_position.set_if_different(None);
             let halt = async {
-                command_kick_speed.set(crate::KickSpeed::Velocity(0));
+                command_kick_speed.set(crate::KickSpeed::Velocity(KickSelection::Kick, 0));
                 command_velocity.set(LocalVelocity {
                     forward: 0,
                     left: 0,
@@ -383,9 +802,9 @@ async fn process<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
             if with_timeout(Duration::from_millis(5), halt).await.is_err() {
                 error!("timeout sending HALT to motorcontroller");
             }
-            command_dribbler_speed.set(0);
+            command_dribbler_speed.set(crate::DribblerSpeed::Throttle(0));
         }
-        GameState::Stop => (),
-        GameState::Normal => (),
+        // STOP is applied to the commands above
+        GameState::Stop | GameState::Normal => (),
     }
 }
@@ -13,9 +13,10 @@ use embassy_sync::{
 };
 use fixed::types::U16F16;
 use fixed_macro::types::U16F16;
+use intra_comms::definitions::{ErrorFlags, Team};
 use sync::observable::Observable;
 
-use crate::lightbarrier::LightBarrierState;
+use crate::{ball::BallDetection, lightbarrier::LightBarrierState};
 
 #[task]
 #[allow(clippy::too_many_arguments)]
@@ -26,10 +27,11 @@ pub async fn ui_task(
     config: &'static crate::Config<CriticalSectionRawMutex>,
     save_config: &'static Signal<CriticalSectionRawMutex, ()>,
     voltage_mutex: &'static Mutex<CriticalSectionRawMutex, U16F16>,
-    has_ball: &'static Observable<CriticalSectionRawMutex, LightBarrierState, 8>,
+    has_ball: &'static Observable<CriticalSectionRawMutex, BallDetection, 8>,
     kick_speed: &'static Observable<CriticalSectionRawMutex, crate::KickSpeed, 8>,
-    dribbler_speed: &'static Observable<CriticalSectionRawMutex, u16, 8>,
+    dribbler_speed: &'static Observable<CriticalSectionRawMutex, crate::DribblerSpeed, 8>,
     shutdown: &'static Signal<CriticalSectionRawMutex, ()>,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
 ) {
     const SLAVE_ADDRESS: u8 = 0x42;
     info!("Setting up UI I2C");
@@ -45,20 +47,22 @@ pub async fn ui_task(
         shutdown,
         kick_speed,
         dribbler_speed,
+        errors,
     )
     .await;
 }
 
 #[allow(clippy::too_many_arguments)]
-async fn ui<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
+async fn ui<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize, const SUBS4: usize>(
     mut i2c: I2c<'_, impl i2c::Instance, AsyncSlave>,
     config: &crate::Config<impl RawMutex>,
     save_config: &Signal<impl RawMutex, ()>,
     voltage_mutex: &Mutex<impl RawMutex, U16F16>,
-    has_ball: &Observable<impl RawMutex, LightBarrierState, SUBS1>,
+    has_ball: &Observable<impl RawMutex, BallDetection, SUBS1>,
     shutdown: &Signal<impl RawMutex, ()>,
     kick_speed: &Observable<impl RawMutex, crate::KickSpeed, SUBS2>,
-    dribbler_speed: &Observable<impl RawMutex, u16, SUBS3>,
+    dribbler_speed: &Observable<impl RawMutex, crate::DribblerSpeed, SUBS3>,
+    errors: &Observable<impl RawMutex, ErrorFlags, SUBS4>,
 ) {
     let mut address = None;
     loop {
@@ -83,7 +87,14 @@ async fn ui<const SUBS1: usize, const SUBS2: usize, const SUBS3: usize>(
             }
             Action::Request => {
                 if let Some(addr) = address {
-                    let data = get_addr(addr.into(), config, voltage_mutex, has_ball, shutdown);
+                    let data = get_addr(
+                        addr.into(),
+                        config,
+                        voltage_mutex,
+                        has_ball,
+                        shutdown,
+                        errors,
+                    );
                     address = None;
                     data
                 } else {
@@ -113,6 +124,7 @@ enum Address {
     ResetError,
     HalfDribblerSpeed,
     MaxKickerVoltage,
+    TeamColor,
     Unknown,
 }
 
@@ -134,6 +146,7 @@ impl From<u8> for Address {
             13 => Self::ResetError,
             14 => Self::HalfDribblerSpeed,
             15 => Self::MaxKickerVoltage,
+            16 => Self::TeamColor,
             _ => Self::Unknown,
         }
     }
@@ -145,7 +158,7 @@ fn set_addr<const SUBS1: usize, const SUBS2: usize>(
     config: &crate::Config<impl RawMutex>,
     save_config: &Signal<impl RawMutex, ()>,
     kick_speed: &Observable<impl RawMutex, crate::KickSpeed, SUBS1>,
-    dribbler_speed: &Observable<impl RawMutex, u16, SUBS2>,
+    dribbler_speed: &Observable<impl RawMutex, crate::DribblerSpeed, SUBS2>,
 ) {
     use Address::*;
     match addr {
@@ -170,9 +183,22 @@ fn set_addr<const SUBS1: usize, const SUBS2: usize>(
             config.dribbler_low.set(u16::from(data) * FACTOR);
         }
         DribblerState => match data {
-            1 => dribbler_speed.set_if_different(config.dribbler_low.get()),
-            2 => dribbler_speed.set_if_different(config.dribbler_high.get()),
-            _ => dribbler_speed.set_if_different(0),
+            1 => dribbler_speed
+                .set_if_different(crate::DribblerSpeed::Throttle(config.dribbler_low.get())),
+            2 => dribbler_speed
+                .set_if_different(crate::DribblerSpeed::Throttle(config.dribbler_high.get())),
+            _ => dribbler_speed.set_if_different(crate::DribblerSpeed::Throttle(0)),
+        },
+        TeamColor => match data {
+            0 => {
+                config.team.set(Team::Blue);
+                save_config.signal(());
+            }
+            1 => {
+                config.team.set(Team::Yellow);
+                save_config.signal(());
+            }
+            _ => warn!("invalid team {}", data),
         },
         Kick => {
             let duration = u16::from(data) * 50;
@@ -183,12 +209,13 @@ fn set_addr<const SUBS1: usize, const SUBS2: usize>(
     }
 }
 
-fn get_addr<const SUBS: usize>(
+fn get_addr<const SUBS1: usize, const SUBS2: usize>(
     addr: Address,
     config: &crate::Config<impl RawMutex>,
     voltage_mutex: &Mutex<impl RawMutex, U16F16>,
-    has_ball: &Observable<impl RawMutex, LightBarrierState, SUBS>,
+    has_ball: &Observable<impl RawMutex, BallDetection, SUBS1>,
     shutdown: &Signal<impl RawMutex, ()>,
+    errors: &Observable<impl RawMutex, ErrorFlags, SUBS2>,
 ) -> Option<u8> {
     use Address::*;
     match addr {
@@ -225,15 +252,24 @@ fn get_addr<const SUBS: usize>(
             let voltage = voltage.az();
             Some(voltage)
         }
-        LightbarrierState => match has_ball.get() {
+        LightbarrierState => match has_ball.get().light_barrier {
             LightBarrierState::HasBall => Some(2),
             LightBarrierState::NoBall => Some(0),
             LightBarrierState::ContactLost => Some(1),
         },
+        TeamColor => match config.team.get() {
+            Team::Blue => Some(0),
+            Team::Yellow => Some(1),
+        },
         Reset => {
             shutdown.signal(());
             Some(1)
         }
+        // the error registers contain the bits of the `ErrorFlags` in their group
+        EscError => Some((errors.get() & ErrorFlags::MOTORS).bits()),
+        ComError => Some((errors.get() & ErrorFlags::COMMUNICATION).bits()),
+        KicError => Some((errors.get() & ErrorFlags::KICKER).bits()),
+        ResetError => Some((errors.get() & ErrorFlags::WATCHDOG_RESET).bits()),
         _ => {
             warn!("inimplemented i2c address requested {}", addr);
             None
@@ -1,10 +1,23 @@
-use defmt::info;
+use defmt::{info, warn};
 use embassy_executor::task;
-use embassy_rp::{peripherals::WATCHDOG, watchdog::Watchdog};
+use embassy_rp::{pac, peripherals::WATCHDOG, watchdog::Watchdog};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_time::{Duration, Timer};
+use intra_comms::definitions::ErrorFlags;
+use sync::observable::Observable;
 
 #[task]
-pub async fn watchdog_task(watchdog: WATCHDOG) {
+pub async fn watchdog_task(
+    watchdog: WATCHDOG,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
+) {
+    // # Safety
+    // The reason register is only read. It is cleared by the next reset.
+    if unsafe { pac::WATCHDOG.reason().read().timer() } {
+        warn!("the last reset was caused by the watchdog");
+        errors.update(|errors| errors.union(ErrorFlags::WATCHDOG_RESET));
+    }
+
     let mut watchdog = Watchdog::new(watchdog);
     info!("starting watchdog");
     watchdog.start(Duration::from_millis(750));
@@ -130,15 +130,126 @@ impl<M: RawMutex> Default for ConfigV0<M> {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct ConfigV1<M: RawMutex> {
+    pub motor_pid_kp: Parameter<M, I24F8, 1>,
+    pub motor_pid_ki: Parameter<M, I24F8, 1>,
+    pub motor_pid_kd: Parameter<M, I24F8, 1>,
+    pub motor_pid_ilimit: Parameter<M, Option<I24F8>, 1>,
+    pub motor_pid_limit: Parameter<M, Option<I24F8>, 1>,
+    /// Applied to every commanded velocity. The position controller of the maincontroller plans
+    /// with its own, lower limits.
+    pub linear_accelleration: Parameter<M, MetrePerSquareSecond<I16F16>, 1>,
+    pub angular_accelleration: Parameter<M, RadianPerSquareSecond<I16F16>, 1>,
+    pub linear_jerk: Parameter<M, MetrePerCubeSecond<I16F16>, 1>,
+    pub angular_jerk: Parameter<M, RadianPerCubeSecond<I16F16>, 1>,
+    pub kicker_cap_dac_230v: Parameter<M, u16, 1>,
+    pub kicker_cap_adc_230v: Parameter<M, u16, 1>,
+    pub kicker_charge_voltage: Parameter<M, Volt<u8>, 1>,
+    pub kicker_min_voltage: Parameter<M, Volt<u8>, 1>,
+    pub kicker_poli4: Parameter<M, I16F16, 1>,
+    pub kicker_poli3: Parameter<M, I16F16, 1>,
+    pub kicker_poli2: Parameter<M, I16F16, 1>,
+    pub kicker_poli1: Parameter<M, I16F16, 1>,
+    pub kicker_poli0: Parameter<M, I16F16, 1>,
+    pub kicker_chip_poli4: Parameter<M, I16F16, 1>,
+    pub kicker_chip_poli3: Parameter<M, I16F16, 1>,
+    pub kicker_chip_poli2: Parameter<M, I16F16, 1>,
+    pub kicker_chip_poli1: Parameter<M, I16F16, 1>,
+    pub kicker_chip_poli0: Parameter<M, I16F16, 1>,
+}
+
+impl<M: RawMutex> ConfigV1<M> {
+    pub const fn new() -> Self {
+        Self {
+            motor_pid_kp: Parameter::new(I24F8!(2000).unwrapped_div(I24F8::TAU)),
+            motor_pid_ki: Parameter::new(I24F8!(200).unwrapped_div(I24F8::TAU)),
+            motor_pid_kd: Parameter::new(I24F8!(0).unwrapped_div(I24F8::TAU)),
+            motor_pid_ilimit: Parameter::new(Some(I24F8!(14000))),
+            motor_pid_limit: Parameter::new(Some(I24F8!(2000).unwrapped_mul(I24F8::TAU))),
+            linear_accelleration: Parameter::new(MetrePerSquareSecond::new(I16F16!(7))),
+            angular_accelleration: Parameter::new(RadianPerSquareSecond::new(I16F16!(42))),
+            linear_jerk: Parameter::new(MetrePerCubeSecond::new(I16F16!(50))),
+            angular_jerk: Parameter::new(RadianPerCubeSecond::new(I16F16!(300))),
+            kicker_cap_dac_230v: Parameter::new(DAC_230V_POINT),
+            kicker_cap_adc_230v: Parameter::new(ADC_230V_POINT),
+            #[cfg(not(feature = "lupfer"))]
+            kicker_charge_voltage: Parameter::new(Volt::new(200)),
+            #[cfg(feature = "lupfer")]
+            kicker_charge_voltage: Parameter::new(Volt::new(230)),
+            kicker_min_voltage: Parameter::new(Volt::new(50)),
+            kicker_poli4: Parameter::new(I16F16!(1.74646057)),
+            kicker_poli3: Parameter::new(I16F16!(-14.2552025)),
+            kicker_poli2: Parameter::new(I16F16!(49.25610639)),
+            kicker_poli1: Parameter::new(I16F16!(152.85497417)),
+            kicker_poli0: Parameter::new(I16F16!(149.71060934)),
+            // the chip solenoid fired with the timings of the straight kick before chips were
+            // separated, so these are the only ones measured for it
+            kicker_chip_poli4: Parameter::new(I16F16!(1.74646057)),
+            kicker_chip_poli3: Parameter::new(I16F16!(-14.2552025)),
+            kicker_chip_poli2: Parameter::new(I16F16!(49.25610639)),
+            kicker_chip_poli1: Parameter::new(I16F16!(152.85497417)),
+            kicker_chip_poli0: Parameter::new(I16F16!(149.71060934)),
+        }
+    }
+}
+
+impl<M: RawMutex> Default for ConfigV1<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex> From<ConfigV0<M>> for ConfigV1<M> {
+    fn from(config: ConfigV0<M>) -> Self {
+        Self {
+            motor_pid_kp: config.motor_pid_kp,
+            motor_pid_ki: config.motor_pid_ki,
+            motor_pid_kd: config.motor_pid_kd,
+            motor_pid_ilimit: config.motor_pid_ilimit,
+            motor_pid_limit: config.motor_pid_limit,
+            linear_accelleration: config.linear_accelleration,
+            angular_accelleration: config.angular_accelleration,
+            linear_jerk: config.linear_jerk,
+            angular_jerk: config.angular_jerk,
+            kicker_cap_dac_230v: config.kicker_cap_dac_230v,
+            kicker_cap_adc_230v: config.kicker_cap_adc_230v,
+            kicker_poli4: config.kicker_poli4,
+            kicker_poli3: config.kicker_poli3,
+            kicker_poli2: config.kicker_poli2,
+            kicker_poli1: config.kicker_poli1,
+            kicker_poli0: config.kicker_poli0,
+            // V0 never loaded its charge voltage, so it only holds an old default
+            ..Self::new()
+        }
+    }
+}
+
+/// New versions are only appended, so older records can still be decoded
 #[derive(Serialize, Deserialize)]
 #[serde(bound = "")]
 enum ConfigSelection<M: RawMutex> {
     V0(ConfigV0<M>),
+    V1(ConfigV1<M>),
+}
+
+impl<M: RawMutex> ConfigSelection<M> {
+    fn is_latest(&self) -> bool {
+        matches!(self, Self::V1(_))
+    }
+
+    fn into_latest(self) -> ConfigV1<M> {
+        match self {
+            Self::V0(config) => config.into(),
+            Self::V1(config) => config,
+        }
+    }
 }
 
 impl<M: RawMutex> Default for ConfigSelection<M> {
     fn default() -> Self {
-        Self::V0(ConfigV0::default())
+        Self::V1(ConfigV1::default())
     }
 }
 
@@ -252,12 +363,17 @@ async fn config_inner<
 ) {
     if let Some(disc_config) = DiscConfig::<NoopRawMutex>::load_from_flash(&mut flash) {
         info!("Successfully loaded config");
-        update_config(config, &disc_config.config);
+        let outdated = !disc_config.config.is_latest();
+        update_config(config, &disc_config.config.into_latest());
+        if outdated {
+            info!("Migrating config to the latest version");
+            save.signal(());
+        }
     }
     loop {
         save.wait().await;
         let temp_config = clone_config::<NoopRawMutex>(config);
-        let disc_config = DiscConfig::new(ConfigSelection::V0(temp_config));
+        let disc_config = DiscConfig::new(ConfigSelection::V1(temp_config));
         assert!(disc_config.valid());
         disc_config.save_to_flash(&mut flash);
     }
@@ -285,39 +401,46 @@ fn clone_config<M: RawMutex>(config: &crate::Config<impl RawMutex>) -> crate::Co
         angular_accelleration,
         kicker_cap_dac_230v,
         kicker_cap_adc_230v,
+        kicker_charge_voltage,
+        kicker_min_voltage,
         kicker_poli4,
         kicker_poli3,
         kicker_poli2,
         kicker_poli1,
-        kicker_poli0
+        kicker_poli0,
+        kicker_chip_poli4,
+        kicker_chip_poli3,
+        kicker_chip_poli2,
+        kicker_chip_poli1,
+        kicker_chip_poli0
     );
     res
 }
 
-fn update_config(
-    config: &crate::Config<impl RawMutex>,
-    disc_config: &ConfigSelection<impl RawMutex>,
-) {
-    match disc_config {
-        ConfigSelection::V0(config_v0) => {
-            clone_config!(
-                config_v0,
-                config,
-                motor_pid_kp,
-                motor_pid_ki,
-                motor_pid_kd,
-                motor_pid_ilimit,
-                motor_pid_limit,
-                linear_accelleration,
-                angular_accelleration,
-                kicker_cap_dac_230v,
-                kicker_cap_adc_230v,
-                kicker_poli4,
-                kicker_poli3,
-                kicker_poli2,
-                kicker_poli1,
-                kicker_poli0
-            );
-        }
-    }
+fn update_config(config: &crate::Config<impl RawMutex>, loaded: &ConfigV1<impl RawMutex>) {
+    clone_config!(
+        loaded,
+        config,
+        motor_pid_kp,
+        motor_pid_ki,
+        motor_pid_kd,
+        motor_pid_ilimit,
+        motor_pid_limit,
+        linear_accelleration,
+        angular_accelleration,
+        kicker_cap_dac_230v,
+        kicker_cap_adc_230v,
+        kicker_charge_voltage,
+        kicker_min_voltage,
+        kicker_poli4,
+        kicker_poli3,
+        kicker_poli2,
+        kicker_poli1,
+        kicker_poli0,
+        kicker_chip_poli4,
+        kicker_chip_poli3,
+        kicker_chip_poli2,
+        kicker_chip_poli1,
+        kicker_chip_poli0
+    );
 }
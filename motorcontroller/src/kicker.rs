@@ -1,6 +1,6 @@
-use defmt::{info, unwrap, warn};
+use defmt::{error, info, unwrap, warn, Format};
 use embassy_executor::task;
-use embassy_futures::select::{select3, Either3};
+use embassy_futures::select::{select4, Either4};
 use embassy_rp::{
     adc::Adc,
     gpio::{Input, Level, Output, Pull},
@@ -11,8 +11,9 @@ use embassy_rp::{
     pio::{Common, Instance, StateMachine},
 };
 use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use fixed::types::I16F16;
+use intra_comms::definitions::{ErrorFlags, KickSelection, KickerFault, KickerState};
 use kicker::asynch::PioDac;
 use sync::observable::Observable;
 use units::types::Volt;
@@ -32,15 +33,37 @@ const ADC_BIT_PER_VOLT: f64 = ADC_MAX / 3.3;
 const ADC_BIT_PER_CAP_VOLT: f64 = ADC_BIT_PER_VOLT * CAP_GAIN;
 pub(crate) const ADC_230V_POINT: u16 = (ADC_BIT_PER_CAP_VOLT * 230.0) as u16;
 
+/// Rate at which the cap voltage is measured
+const CAP_SAMPLE_RATE: u64 = 100;
+/// Maximum time the charger may take to charge the caps before a fault is assumed
+const CHARGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the charger is paused after a kick
+const COOLDOWN: Duration = Duration::from_millis(200);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Format)]
+enum State {
+    Discharged,
+    Charging,
+    Ready,
+    Fault(KickerFault),
+    Cooldown,
+}
+
 struct Kicker<'d, PIO: Instance, const SM: usize> {
-    triggers: (Output<'d, PIN_0>, Output<'d, PIN_1>),
-    _not_fault: Input<'d, PIN_12>,
-    _not_done: Input<'d, PIN_13>,
+    kick_trigger: Output<'d, PIN_0>,
+    chip_trigger: Output<'d, PIN_1>,
+    not_fault: Input<'d, PIN_12>,
+    not_done: Input<'d, PIN_13>,
     clear: Output<'d, PIN_14>,
     _charge: Input<'d, PIN_15>,
     dac: PioDac<'d, PIO, SM>,
-    _adc: Adc<'d>,
-    _cap_voltage: PIN_29,
+    adc: Adc<'d>,
+    cap_voltage: PIN_29,
+    state: State,
+    /// Time of the last state change
+    state_since: Instant,
+    /// Dac value the charger charges to
+    charge_value: u16,
 }
 
 impl<'d, PIO: Instance, const SM: usize> Kicker<'d, PIO, SM> {
@@ -70,10 +93,8 @@ impl<'d, PIO: Instance, const SM: usize> Kicker<'d, PIO, SM> {
     ) -> Self {
         let dac = PioDac::new(sm, pio, dac_pins);
         let clear = Output::new(clear, Level::Low);
-        let triggers = (
-            Output::new(triggers.0, Level::Low),
-            Output::new(triggers.1, Level::Low),
-        );
+        let kick_trigger = Output::new(triggers.0, Level::Low);
+        let chip_trigger = Output::new(triggers.1, Level::Low);
         let charge = Input::new(charge, Pull::Down);
         let not_fault = Input::new(not_fault, Pull::None);
         let not_done = Input::new(not_done, Pull::None);
@@ -81,38 +102,145 @@ impl<'d, PIO: Instance, const SM: usize> Kicker<'d, PIO, SM> {
             warn!("Kicker error at creation.");
         }
         Self {
-            triggers,
-            _not_fault: not_fault,
-            _not_done: not_done,
+            kick_trigger,
+            chip_trigger,
+            not_fault,
+            not_done,
             clear,
             _charge: charge,
             dac,
-            _adc: adc,
-            _cap_voltage: cap_voltage,
+            adc,
+            cap_voltage,
+            state: State::Discharged,
+            state_since: Instant::now(),
+            charge_value: 0,
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        if state != self.state {
+            info!("kicker state {} -> {}", self.state, state);
+            self.state = state;
+            self.state_since = Instant::now();
+        }
+    }
+
+    /// The state and the last fault as reported to the maincontroller
+    fn report(&self) -> (KickerState, Option<KickerFault>) {
+        match self.state {
+            State::Discharged => (KickerState::Discharged, None),
+            State::Charging => (KickerState::Charging, None),
+            State::Ready => (KickerState::Ready, None),
+            State::Fault(fault) => (KickerState::Fault, Some(fault)),
+            State::Cooldown => (KickerState::Cooldown, None),
+        }
+    }
+
+    /// Returns `true` if the kicker is allowed to kick with the current `cap_voltage`
+    fn can_kick(&self, cap_voltage: Volt<u8>, min_voltage: Volt<u8>) -> bool {
+        if !matches!(self.state, State::Charging | State::Ready) {
+            warn!("not kicking. Kicker is in state {}", self.state);
+            false
+        } else if cap_voltage < min_voltage {
+            warn!(
+                "not kicking. Cap voltage {} is below {}",
+                cap_voltage, min_voltage
+            );
+            false
+        } else {
+            true
         }
     }
 
+    fn charger_off(&mut self) {
+        self.dac.set(0);
+        self.clear.set_low();
+        self.kick_trigger.set_low();
+        self.chip_trigger.set_low();
+    }
+
     fn discharge(&mut self) {
         info!("discharging");
-        self.dac.set(0);
+        self.charger_off();
+        self.set_state(State::Discharged);
+    }
+
+    /// Turns the charger off until it is commanded to charge again
+    fn fault(&mut self, fault: KickerFault) {
+        error!("kicker fault: {}", fault);
+        self.charger_off();
+        self.set_state(State::Fault(fault));
+    }
+
+    /// Pauses the charger after a kick. Charging is resumed by [`Self::update`]
+    fn cooldown(&mut self) {
         self.clear.set_low();
-        self.triggers.0.set_low();
-        self.triggers.1.set_low();
+        self.set_state(State::Cooldown);
     }
 
     async fn kick(&mut self, time: Duration) {
         warn!("kicking!!!");
-        self.triggers.0.set_high();
-        self.triggers.1.set_high();
+        self.kick_trigger.set_high();
+        Timer::after(time).await;
+        self.kick_trigger.set_low();
+        self.cooldown();
+    }
+
+    async fn chip(&mut self, time: Duration) {
+        warn!("chipping!!!");
+        self.chip_trigger.set_high();
         Timer::after(time).await;
-        self.triggers.0.set_low();
-        self.triggers.1.set_low();
+        self.chip_trigger.set_low();
+        self.cooldown();
+    }
+
+    async fn fire(&mut self, kick: KickSelection, time: Duration) {
+        match kick {
+            KickSelection::Kick => self.kick(time).await,
+            KickSelection::Chip => self.chip(time).await,
+        }
+    }
+
+    /// Charges the caps to the dac `value`. A running cooldown is finished first.
+    fn charge(&mut self, value: u16) {
+        self.charge_value = value;
+        if self.state != State::Cooldown {
+            self.start_charging();
+        }
     }
 
-    fn charge(&mut self, voltage: u16) {
+    fn start_charging(&mut self) {
         info!("charging");
         self.clear.set_high();
-        self.dac.set(voltage);
+        self.dac.set(self.charge_value);
+        self.set_state(State::Charging);
+    }
+
+    /// Measures the voltage of the caps. `adc_230v` is the adc value at 230V.
+    async fn cap_voltage(&mut self, adc_230v: u16) -> Volt<u8> {
+        let value = self.adc.read(&mut self.cap_voltage).await;
+        let voltage = u32::from(value) * 230 / u32::from(adc_230v.max(1));
+        Volt::new(u8::try_from(voltage).unwrap_or(u8::MAX))
+    }
+
+    /// Advances the state machine using the charger status pins
+    fn update(&mut self) {
+        let charging = matches!(self.state, State::Charging | State::Ready);
+        if charging && self.not_fault.is_low() {
+            self.fault(KickerFault::Charger);
+            return;
+        }
+        match self.state {
+            State::Charging if self.not_done.is_low() => self.set_state(State::Ready),
+            State::Charging if self.state_since.elapsed() > CHARGE_TIMEOUT => {
+                self.fault(KickerFault::ChargeTimeout);
+            }
+            State::Ready if self.not_done.is_high() => self.set_state(State::Charging),
+            State::Cooldown if self.state_since.elapsed() > COOLDOWN => {
+                self.start_charging();
+            }
+            _ => (),
+        }
     }
 }
 
@@ -121,8 +249,12 @@ impl<'d, PIO: Instance, const SM: usize> Kicker<'d, PIO, SM> {
 pub async fn kicker_task(
     has_ball: &'static Observable<CriticalSectionRawMutex, bool, 8>,
     set_voltage: &'static Observable<CriticalSectionRawMutex, Volt<u8>, 8>,
-    speed: &'static Observable<CriticalSectionRawMutex, u16, 8>,
+    speed: &'static Observable<CriticalSectionRawMutex, (KickSelection, u16), 8>,
     kicker_raw_duration: &'static Observable<CriticalSectionRawMutex, Duration, 8>,
+    cap_voltage: &'static Observable<CriticalSectionRawMutex, Volt<u8>, 8>,
+    kicker_state: &'static Observable<CriticalSectionRawMutex, KickerState, 8>,
+    kicker_fault: &'static Observable<CriticalSectionRawMutex, Option<KickerFault>, 8>,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
     triggers: (PIN_0, PIN_1),
     not_fault: PIN_12,
     not_done: PIN_13,
@@ -163,6 +295,10 @@ pub async fn kicker_task(
         set_voltage,
         speed,
         kicker_raw_duration,
+        cap_voltage,
+        kicker_state,
+        kicker_fault,
+        errors,
         kicker_obj,
         config,
     )
@@ -175,39 +311,59 @@ async fn kicker<
     const SUBS2: usize,
     const SUBS3: usize,
     const SUBS4: usize,
+    const SUBS5: usize,
+    const SUBS6: usize,
+    const SUBS7: usize,
+    const SUBS8: usize,
     const SM: usize,
 >(
     has_ball: &Observable<impl RawMutex, bool, SUBS1>,
     set_voltage: &Observable<impl RawMutex, Volt<u8>, SUBS2>,
-    speed: &Observable<impl RawMutex, u16, SUBS3>,
+    speed: &Observable<impl RawMutex, (KickSelection, u16), SUBS3>,
     kicker_raw_duration: &Observable<impl RawMutex, Duration, SUBS4>,
+    cap_voltage: &Observable<impl RawMutex, Volt<u8>, SUBS5>,
+    kicker_state: &Observable<impl RawMutex, KickerState, SUBS6>,
+    kicker_fault: &Observable<impl RawMutex, Option<KickerFault>, SUBS7>,
+    errors: &Observable<impl RawMutex, ErrorFlags, SUBS8>,
     mut kicker: Kicker<'_, impl Instance, SM>,
     config: &crate::Config<impl RawMutex>,
 ) {
     let mut has_ball_sub = unwrap!(has_ball.subscriber());
     let mut set_voltage_sub = unwrap!(set_voltage.subscriber());
     let mut speed_sub = unwrap!(speed.subscriber());
+    // raw kicks are always straight kicks. A chip waiting for the ball is stored here
+    let mut chip_duration = None;
+    let mut ticker = Ticker::every(Duration::from_hz(CAP_SAMPLE_RATE));
     loop {
-        match select3(
+        match select4(
             has_ball_sub.next_value(),
             set_voltage_sub.next_value(),
             speed_sub.next_value(),
+            ticker.next(),
         )
         .await
         {
-            Either3::First(has_ball) => {
+            Either4::First(has_ball) => {
                 info!("got ball update {}", has_ball);
                 let timing = kicker_raw_duration.get();
-                if has_ball && timing != Duration::MIN {
-                    kicker.kick(timing).await;
-                    kicker_raw_duration.set(Duration::MIN);
+                let armed = chip_duration.is_some() || timing != Duration::MIN;
+                if has_ball
+                    && armed
+                    && kicker.can_kick(cap_voltage.get(), config.kicker_min_voltage.get())
+                {
+                    if let Some(timing) = chip_duration.take() {
+                        kicker.chip(timing).await;
+                    } else {
+                        kicker.kick(timing).await;
+                        kicker_raw_duration.set(Duration::MIN);
+                    }
                 }
             }
-            Either3::Second(voltage) if voltage == Volt::new(0) => {
+            Either4::Second(voltage) if voltage == Volt::new(0) => {
                 info!("discharging");
                 kicker.discharge();
             }
-            Either3::Second(voltage) => {
+            Either4::Second(voltage) => {
                 info!("setting voltage {}", voltage);
                 let value_230v = config.kicker_cap_dac_230v.get();
                 let value = unwrap!(u16::try_from(
@@ -216,37 +372,68 @@ async fn kicker<
                 ));
                 kicker.charge(value);
             }
-            Either3::Third(speed) => {
+            Either4::Third((kick, speed)) => {
+                kicker_raw_duration.set(Duration::MIN);
+                chip_duration = None;
                 if speed == 0 {
                     info!("commanded to not kick");
-                    kicker_raw_duration.set(Duration::MIN);
                 } else {
-                    let timing = calc_kick_time(speed, config);
+                    let timing = calc_kick_time(kick, speed, config);
                     info!(
-                        "commanded to kick with {}mm/s, {}us",
+                        "commanded to {} with {}mm/s, {}us",
+                        kick,
                         speed,
                         timing.as_micros()
                     );
-                    if has_ball.get() {
+                    if has_ball.get()
+                        && kicker.can_kick(cap_voltage.get(), config.kicker_min_voltage.get())
+                    {
                         info!("we currently have the ball. kicking");
-                        kicker.kick(timing).await;
-                        kicker_raw_duration.set(Duration::MIN);
+                        kicker.fire(kick, timing).await;
                     } else {
-                        kicker_raw_duration.set_if_different(timing);
+                        match kick {
+                            KickSelection::Kick => kicker_raw_duration.set(timing),
+                            KickSelection::Chip => chip_duration = Some(timing),
+                        }
                     }
                 }
             }
+            Either4::Fourth(()) => {
+                let voltage = kicker.cap_voltage(config.kicker_cap_adc_230v.get()).await;
+                cap_voltage.set_if_different(voltage);
+                kicker.update();
+            }
         }
+        let (state, fault) = kicker.report();
+        kicker_fault.set_if_different(fault);
+        kicker_state.set_if_different(state);
+        let fault_flags = fault.map_or(ErrorFlags::empty(), ErrorFlags::from);
+        errors.update(|errors| errors.difference(ErrorFlags::KICKER).union(fault_flags));
     }
 }
 
-fn calc_kick_time(speed: u16, config: &crate::Config<impl RawMutex>) -> Duration {
+fn calc_kick_time(
+    kick: KickSelection,
+    speed: u16,
+    config: &crate::Config<impl RawMutex>,
+) -> Duration {
     let speed = I16F16::from_num(speed) / 1000;
-    let poli4 = config.kicker_poli4.get();
-    let poli3 = config.kicker_poli3.get();
-    let poli2 = config.kicker_poli2.get();
-    let poli1 = config.kicker_poli1.get();
-    let poli0 = config.kicker_poli0.get();
+    let [poli4, poli3, poli2, poli1, poli0] = match kick {
+        KickSelection::Kick => [
+            config.kicker_poli4.get(),
+            config.kicker_poli3.get(),
+            config.kicker_poli2.get(),
+            config.kicker_poli1.get(),
+            config.kicker_poli0.get(),
+        ],
+        KickSelection::Chip => [
+            config.kicker_chip_poli4.get(),
+            config.kicker_chip_poli3.get(),
+            config.kicker_chip_poli2.get(),
+            config.kicker_chip_poli1.get(),
+            config.kicker_chip_poli0.get(),
+        ],
+    };
     let microseconds = (((poli4 * speed + poli3) * speed + poli2) * speed + poli1) * speed + poli0;
     Duration::from_micros_floor(microseconds.to_num())
 }
@@ -9,7 +9,7 @@ mod maincontroller;
 mod odometry;
 mod watchdog;
 
-use configprovider::ConfigV0 as Config;
+use configprovider::ConfigV1 as Config;
 use cortex_m_rt::entry;
 #[allow(unused_imports)]
 use defmt::{
@@ -32,6 +32,7 @@ use embassy_rp::{
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::Duration;
 use fixed::types::I24F8;
+use intra_comms::definitions::{ErrorFlags, KickSelection, KickerFault, KickerState};
 use panic_probe as _;
 use static_cell::StaticCell;
 use sync::observable::Observable;
@@ -101,7 +102,14 @@ fn main() -> ! {
         Observable::new(Volt::new(0));
     static KICKER_CAP_VOLTAGE: Observable<CriticalSectionRawMutex, Volt<u8>, 8> =
         Observable::new(Volt::new(0));
-    static KICKER_SPEED: Observable<CriticalSectionRawMutex, u16, 8> = Observable::new(0);
+    static KICKER_STATE: Observable<CriticalSectionRawMutex, KickerState, 8> =
+        Observable::new(KickerState::Discharged);
+    static KICKER_FAULT: Observable<CriticalSectionRawMutex, Option<KickerFault>, 8> =
+        Observable::new(None);
+    static ERRORS: Observable<CriticalSectionRawMutex, ErrorFlags, 8> =
+        Observable::new(ErrorFlags::empty());
+    static KICKER_SPEED: Observable<CriticalSectionRawMutex, (KickSelection, u16), 8> =
+        Observable::new((KickSelection::Kick, 0));
     static WHEEL_SPEEDS: Observable<CriticalSectionRawMutex, [RadianPerSecond<I24F8>; 4], 8> =
         Observable::new([RadianPerSecond::new(I24F8::ZERO); 4]);
     static KICKER_RAW_DURATION: Observable<CriticalSectionRawMutex, Duration, 8> =
@@ -130,6 +138,7 @@ fn main() -> ! {
                 &MOVEMENT_SETPOINT,
                 &WHEEL_SPEEDS,
                 &CONFIG,
+                &ERRORS,
                 spawner,
             ));
         })
@@ -145,6 +154,10 @@ fn main() -> ! {
         &KICKER_VOLTAGE_SIGNAL,
         &KICKER_SPEED,
         &KICKER_RAW_DURATION,
+        &KICKER_CAP_VOLTAGE,
+        &KICKER_STATE,
+        &KICKER_FAULT,
+        &ERRORS,
         (p.PIN_0, p.PIN_1),
         p.PIN_12,
         p.PIN_13,
@@ -163,7 +176,7 @@ fn main() -> ! {
 
     let executor = EXECUTOR_LOW.init(Executor::new());
     executor.run(|spawner| {
-        spawner.must_spawn(watchdog_task(p.WATCHDOG));
+        spawner.must_spawn(watchdog_task(p.WATCHDOG, &ERRORS));
         spawner.must_spawn(maincontroller_task(
             p.UART0,
             p.PIN_16,
@@ -177,6 +190,9 @@ fn main() -> ! {
             &KICKER_SPEED,
             &KICKER_RAW_DURATION,
             &ACTUAL_MOVEMENT,
+            &KICKER_STATE,
+            &KICKER_FAULT,
+            &ERRORS,
             &SAVE_CONFIG,
             &CONFIG,
             spawner,
@@ -3,25 +3,32 @@ use az::Az;
 use defmt::debug;
 use defmt::{error, info, unwrap};
 use embassy_executor::{task, Spawner};
-use embassy_futures::select::{select, Either};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_rp::{
     peripherals::{PIN_16, PIN_17, PIN_18, PIN_19, UART0},
     uart::{self, BufferedUart, BufferedUartRx},
 };
 use embassy_sync::{
     blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex},
+    channel::Channel,
     signal::Signal,
 };
-use embassy_time::Duration;
+use embassy_time::{with_timeout, Duration, Instant};
 use embedded_io::asynch::{BufRead, Write};
 use fixed::types::I16F16;
 use intra_comms::{
-    definitions::{KickerChargeHint, LocalVelocity, Main2Motor},
+    definitions::{
+        ErrorFlags, KickSelection, KickerChargeHint, KickerFault, KickerState, LocalVelocity,
+        Main2Motor, MotionLimits, MotorConfiguration,
+    },
     uart::{MainControllerReceiver, MainControllerSender, ReceiveError, SendError},
+    MAX_CHARGE_VOLTAGE,
 };
 use static_cell::StaticCell;
 use sync::observable::Observable;
-use units::types::{MetrePerSecond, RadianPerSecond, Volt};
+use units::types::{
+    MetrePerSecond, MetrePerSquareSecond, RadianPerSecond, RadianPerSquareSecond, Volt,
+};
 
 use crate::odometry::Movement;
 
@@ -38,15 +45,21 @@ pub async fn maincontroller_task(
     kicker_set_voltage: &'static Observable<CriticalSectionRawMutex, Volt<u8>, 8>,
     has_ball: &'static Observable<CriticalSectionRawMutex, bool, 8>,
     kicker_cap_voltage: &'static Observable<CriticalSectionRawMutex, Volt<u8>, 8>,
-    kicker_speed: &'static Observable<CriticalSectionRawMutex, u16, 8>,
+    kicker_speed: &'static Observable<CriticalSectionRawMutex, (KickSelection, u16), 8>,
     kicker_raw_duration: &'static Observable<CriticalSectionRawMutex, Duration, 8>,
     robot_velocity: &'static Observable<CriticalSectionRawMutex, Movement, 8>,
+    kicker_state: &'static Observable<CriticalSectionRawMutex, KickerState, 8>,
+    kicker_fault: &'static Observable<CriticalSectionRawMutex, Option<KickerFault>, 8>,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
     save_config: &'static Signal<CriticalSectionRawMutex, ()>,
     config: &'static crate::Config<CriticalSectionRawMutex>,
     spawner: Spawner,
 ) {
     static UART_RX_BUFFER: StaticCell<[u8; 256]> = StaticCell::new();
     static UART_TX_BUFFER: StaticCell<[u8; 256]> = StaticCell::new();
+    /// Answers to the configurations of the maincontroller
+    static CONFIGURED: Channel<CriticalSectionRawMutex, (MotorConfiguration, bool), 4> =
+        Channel::new();
 
     let tx_buffer = &mut UART_TX_BUFFER.init([0; 256])[..];
     let rx_buffer = &mut UART_RX_BUFFER.init([0; 256])[..];
@@ -75,11 +88,17 @@ pub async fn maincontroller_task(
         kicker_raw_duration,
         save_config,
         config,
+        &CONFIGURED,
     ));
     send(
         MainControllerSender::new(tx),
         kicker_cap_voltage,
         robot_velocity,
+        kicker_state,
+        kicker_fault,
+        errors,
+        &CONFIGURED,
+        config,
     )
     .await;
 }
@@ -92,10 +111,11 @@ async fn receive_task(
     kicker_set_voltage: &'static Observable<CriticalSectionRawMutex, Volt<u8>, 8>,
     has_ball: &'static Observable<CriticalSectionRawMutex, bool, 8>,
     kicker_cap_voltage: &'static Observable<CriticalSectionRawMutex, Volt<u8>, 8>,
-    kick_speed: &'static Observable<CriticalSectionRawMutex, u16, 8>,
+    kick_speed: &'static Observable<CriticalSectionRawMutex, (KickSelection, u16), 8>,
     kicker_raw_duration: &'static Observable<CriticalSectionRawMutex, Duration, 8>,
     save_config: &'static Signal<CriticalSectionRawMutex, ()>,
     config: &'static crate::Config<CriticalSectionRawMutex>,
+    configured: &'static Channel<CriticalSectionRawMutex, (MotorConfiguration, bool), 4>,
 ) {
     receive(
         receiver,
@@ -107,6 +127,7 @@ async fn receive_task(
         kicker_raw_duration,
         save_config,
         config,
+        configured,
     )
     .await;
 }
@@ -125,10 +146,11 @@ async fn receive<
     kicker_set_voltage: &Observable<impl RawMutex, Volt<u8>, SUBS2>,
     has_ball: &Observable<impl RawMutex, bool, SUBS3>,
     kicker_cap_voltage: &Observable<impl RawMutex, Volt<u8>, SUBS4>,
-    kick_speed: &Observable<impl RawMutex, u16, SUBS5>,
+    kick_speed: &Observable<impl RawMutex, (KickSelection, u16), SUBS5>,
     kicker_raw_duration: &Observable<impl RawMutex, Duration, SUBS6>,
     save_config: &Signal<impl RawMutex, ()>,
     config: &crate::Config<impl RawMutex>,
+    configured: &Channel<impl RawMutex, (MotorConfiguration, bool), 4>,
 ) {
     loop {
         info!("trying to receive packet from maincontroller");
@@ -162,15 +184,26 @@ async fn receive<
                         movement,
                     );
                 }
-                Main2Motor::Kick(speed) | Main2Motor::Chip(speed) => {
-                    info!("got kick or chip command with speed {}mm/s²", speed);
+                Main2Motor::Kick(speed) => {
+                    info!("got kick command with speed {}mm/s", speed);
+                    #[cfg(not(feature = "test_kicker"))]
+                    kick_speed.set_if_different((KickSelection::Kick, speed));
+                    #[cfg(feature = "test_kicker")]
+                    debug!(
+                        "Test build. test value {} is not changed to {}",
+                        kick_speed.get(),
+                        (KickSelection::Kick, speed),
+                    );
+                }
+                Main2Motor::Chip(speed) => {
+                    info!("got chip command with speed {}mm/s", speed);
                     #[cfg(not(feature = "test_kicker"))]
-                    kick_speed.set_if_different(speed);
+                    kick_speed.set_if_different((KickSelection::Chip, speed));
                     #[cfg(feature = "test_kicker")]
                     debug!(
                         "Test build. test value {} is not changed to {}",
                         kick_speed.get(),
-                        speed,
+                        (KickSelection::Chip, speed),
                     );
                 }
                 Main2Motor::KickRaw(duration) => {
@@ -240,27 +273,94 @@ async fn receive<
                         voltage,
                     );
                 }
+                Main2Motor::Configure(configuration) => {
+                    info!("got configuration {}", configuration);
+                    let applied = configure(configuration, config, save_config);
+                    if configured.try_send((configuration, applied)).is_err() {
+                        error!("couldn't answer the configuration {}", configuration);
+                    }
+                }
             },
         }
     }
 }
 
-async fn send<const SUBS1: usize, const SUBS2: usize>(
+/// Saves a setting changed over the air. Returns false if it was rejected.
+fn configure(
+    configuration: MotorConfiguration,
+    config: &crate::Config<impl RawMutex>,
+    save_config: &Signal<impl RawMutex, ()>,
+) -> bool {
+    match configuration {
+        MotorConfiguration::ChargeVoltage(voltage) if voltage <= MAX_CHARGE_VOLTAGE => {
+            config.kicker_charge_voltage.set(Volt::new(voltage));
+        }
+        MotorConfiguration::ChargeVoltage(voltage) => {
+            error!(
+                "ignoring charge voltage {}V above the rating of the caps",
+                voltage
+            );
+            return false;
+        }
+        MotorConfiguration::LinearAcceleration(acceleration) => {
+            config.linear_accelleration.set(MetrePerSquareSecond::new(
+                I16F16::saturating_from_num(acceleration),
+            ));
+        }
+        MotorConfiguration::AngularAcceleration(acceleration) => {
+            config.angular_accelleration.set(RadianPerSquareSecond::new(
+                I16F16::saturating_from_num(acceleration),
+            ));
+        }
+    }
+    save_config.signal(());
+    true
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send<
+    const SUBS1: usize,
+    const SUBS2: usize,
+    const SUBS3: usize,
+    const SUBS4: usize,
+    const SUBS5: usize,
+>(
     mut sender: MainControllerSender<impl Write>,
     kicker_cap_voltage: &Observable<impl RawMutex, Volt<u8>, SUBS1>,
     robot_velocity: &Observable<impl RawMutex, Movement, SUBS2>,
+    kicker_state: &Observable<impl RawMutex, KickerState, SUBS3>,
+    kicker_fault: &Observable<impl RawMutex, Option<KickerFault>, SUBS4>,
+    errors: &Observable<impl RawMutex, ErrorFlags, SUBS5>,
+    configured: &Channel<impl RawMutex, (MotorConfiguration, bool), 4>,
+    config: &crate::Config<impl RawMutex>,
 ) {
+    /// The errors are sent at least this often, so the maincontroller can tell that the link is
+    /// working even if nothing else changes
+    const MAX_TIME_BETWEEN_ERRORS: Duration = Duration::from_millis(100);
+    /// The maincontroller plans with the limits of the last message
+    const TIME_BETWEEN_LIMITS: Duration = Duration::from_secs(1);
+
     let mut kicker_cap_voltage_sub = unwrap!(kicker_cap_voltage.subscriber());
     let mut robot_velocity_sub = unwrap!(robot_velocity.subscriber());
+    let mut kicker_state_sub = unwrap!(kicker_state.subscriber());
+    let mut kicker_fault_sub = unwrap!(kicker_fault.subscriber());
+    let mut errors_sub = unwrap!(errors.subscriber());
+    let mut next_limits = Instant::now();
     loop {
-        if let Err(e) = match select(
-            kicker_cap_voltage_sub.next_value(),
-            robot_velocity_sub.next_value(),
+        let next = with_timeout(
+            MAX_TIME_BETWEEN_ERRORS,
+            select4(
+                kicker_cap_voltage_sub.next_value(),
+                robot_velocity_sub.next_value(),
+                select(kicker_state_sub.next_value(), kicker_fault_sub.next_value()),
+                select(errors_sub.next_value(), configured.recv()),
+            ),
         )
-        .await
-        {
-            Either::First(voltage) => sender.cap_voltage(voltage.raw()).await,
-            Either::Second(movement) => {
+        .await;
+        if let Err(e) = match next {
+            Err(_) => sender.errors(errors.get()).await,
+            Ok(Either4::First(voltage)) => sender.cap_voltage(voltage.raw()).await,
+            Ok(Either4::Second(movement)) => {
                 sender
                     .motor_velocity(LocalVelocity {
                         forward: (movement.forward.raw() * 1000).az(),
@@ -269,11 +369,39 @@ async fn send<const SUBS1: usize, const SUBS2: usize>(
                     })
                     .await
             }
+            Ok(Either4::Third(Either::First(state))) => sender.kicker_state(state).await,
+            Ok(Either4::Third(Either::Second(Some(fault)))) => sender.kicker_fault(fault).await,
+            Ok(Either4::Third(Either::Second(None))) => Ok(()),
+            Ok(Either4::Fourth(Either::First(errors))) => sender.errors(errors).await,
+            Ok(Either4::Fourth(Either::Second((configuration, applied)))) => {
+                // the limits may have changed
+                next_limits = Instant::now();
+                sender.configured(configuration, applied).await
+            }
         } {
-            match e {
-                SendError::Postcard(_) => error!("Unable to serialize using postcard"),
-                SendError::Io(_) => error!("Io error"),
+            log_send_error(&e);
+        }
+        if Instant::now() >= next_limits {
+            next_limits = Instant::now() + TIME_BETWEEN_LIMITS;
+            if let Err(e) = sender.limits(motion_limits(config)).await {
+                log_send_error(&e);
             }
         }
     }
 }
+
+fn motion_limits(config: &crate::Config<impl RawMutex>) -> MotionLimits {
+    MotionLimits {
+        linear_acceleration: config.linear_accelleration.get().raw().to_num(),
+        angular_acceleration: config.angular_accelleration.get().raw().to_num(),
+        linear_jerk: config.linear_jerk.get().raw().to_num(),
+        angular_jerk: config.angular_jerk.get().raw().to_num(),
+    }
+}
+
+fn log_send_error<Tx: Write>(e: &SendError<Tx>) {
+    match e {
+        SendError::Postcard(_) => error!("Unable to serialize using postcard"),
+        SendError::Io(_) => error!("Io error"),
+    }
+}
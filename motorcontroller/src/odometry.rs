@@ -23,6 +23,7 @@ use embedded_hal_async::spi::SpiDevice;
 use fixed::types::{I16F16, I24F8};
 use fixed_macro::types::{I16F16, I24F8};
 use fugit::ExtU32;
+use intra_comms::definitions::ErrorFlags;
 use nalgebra::{matrix, Matrix3x4, Matrix4x3};
 use pidcontroller::{Controller as _, PIDController};
 use static_cell::StaticCell;
@@ -99,6 +100,7 @@ pub async fn motors_task(
     setpoint: &'static Observable<CriticalSectionRawMutex, Movement, 8>,
     actual_speeds: &'static Observable<CriticalSectionRawMutex, [RadianPerSecond<I24F8>; 4], 8>,
     config: &'static Config<CriticalSectionRawMutex>,
+    errors: &'static Observable<CriticalSectionRawMutex, ErrorFlags, 8>,
     spawner: Spawner,
 ) {
     info!("Motors starting");
@@ -122,11 +124,14 @@ pub async fn motors_task(
     spawner.must_spawn(config_proxy(config, proxy_config_ref));
 
     let mut drivetrain = Drivetrain::new(dev0, dev1, dev2, dev3);
-    if drivetrain.init().await.is_ok() {
-        debug!("initialized motors");
-        drivetrain
-            .run(setpoint, actual_speeds, proxy_config_ref)
-            .await;
+    match drivetrain.init().await {
+        Ok(()) => {
+            debug!("initialized motors");
+            drivetrain
+                .run(setpoint, actual_speeds, proxy_config_ref)
+                .await;
+        }
+        Err(flags) => errors.update(|errors| errors.union(flags)),
     }
     error!("couldn't initialize motors. Disabling");
 }
@@ -289,8 +294,8 @@ where
         self.motor.set_decoder_direction(self.direction).await?;
         if let Err(e) = self.motor.calibrate_encoder(4000, &mut Delay, 20).await {
             match e {
-                tmc4671::nonblocking::Error::Spi(_) => todo!(),
-                tmc4671::nonblocking::Error::Deserialization(_) => todo!(),
+                tmc4671::nonblocking::Error::Spi(_)
+                | tmc4671::nonblocking::Error::Deserialization(_) => return Err(e),
                 tmc4671::nonblocking::Error::CalibrationValidation => {
                     debug!("testing negative encoder direction");
                     self.direction = Direction::Negative;
@@ -380,32 +385,40 @@ where
         }
     }
 
-    async fn init(&mut self) -> Result<(), ()> {
+    async fn init(&mut self) -> Result<(), ErrorFlags> {
         info!("initializing drivetrain");
         let results = join4(
             async {
-                self.motors.0.init().await.map_err(|_| {
+                self.motors.0.init().await.map_err(|e| {
                     error!("unable to initialize motor 0");
+                    init_error_flags(&e)
                 })
             },
             async {
-                self.motors.1.init().await.map_err(|_| {
+                self.motors.1.init().await.map_err(|e| {
                     error!("unable to initialize motor 1");
+                    init_error_flags(&e)
                 })
             },
             async {
-                self.motors.2.init().await.map_err(|_| {
+                self.motors.2.init().await.map_err(|e| {
                     error!("unable to initialize motor 2");
+                    init_error_flags(&e)
                 })
             },
             async {
-                self.motors.3.init().await.map_err(|_| {
+                self.motors.3.init().await.map_err(|e| {
                     error!("unable to initialize motor 3");
+                    init_error_flags(&e)
                 })
             },
         )
         .await;
-        let result = results.0.and(results.1).and(results.2).and(results.3);
+        let flags = [results.0, results.1, results.2, results.3]
+            .into_iter()
+            .filter_map(Result::err)
+            .fold(ErrorFlags::empty(), ErrorFlags::union);
+        let result = if flags.is_empty() { Ok(()) } else { Err(flags) };
         if result.is_err() {
             warn!("stopping all motors");
             self.motors.0.full_stop().await;
@@ -510,6 +523,14 @@ where
     }
 }
 
+/// The error reported to the basestation for a failed motor initialization
+fn init_error_flags<E>(error: &tmc4671::nonblocking::Error<E>) -> ErrorFlags {
+    match error {
+        tmc4671::nonblocking::Error::CalibrationValidation => ErrorFlags::ENCODER_CALIBRATION,
+        _ => ErrorFlags::MOTOR_INIT,
+    }
+}
+
 /// Calculates jerk limited accelleration.
 ///
 /// # Arguments